@@ -1,22 +1,21 @@
+use crate::cmd::OvpnExit;
 use crate::config::Config;
 use crate::dns::DnsResolver;
 use crate::log::Log;
 use crate::manager::ConnectionManager;
 use crate::state_manager::StateManager;
 use crate::task::{OavcProcessTask, OavcTask};
-use std::cell::RefCell;
-use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 use tokio::runtime::Runtime;
 
 pub struct VpnApp {
     pub log: Arc<Log>,
-    pub config: Rc<Config>,
-    pub server: RefCell<Option<OavcTask<()>>>,
-    pub openvpn: RefCell<Option<OavcTask<()>>>,
-    pub openvpn_connection: Arc<Mutex<Option<OavcProcessTask<i32>>>>,
+    pub config: Arc<Config>,
+    pub server: Mutex<Option<OavcTask<()>>>,
+    pub openvpn: Mutex<Option<OavcTask<()>>>,
+    pub openvpn_connection: Arc<Mutex<Option<OavcProcessTask<OvpnExit>>>>,
     pub runtime: Arc<Runtime>,
-    pub dns: Rc<DnsResolver>,
+    pub dns: Arc<DnsResolver>,
     pub state: Arc<Mutex<Option<StateManager>>>,
     pub connection_manager: Arc<Mutex<Option<ConnectionManager>>>,
 }
@@ -24,7 +23,7 @@ pub struct VpnApp {
 impl VpnApp {
     pub fn new() -> VpnApp {
         let log = Arc::new(Log::new());
-        let config = Rc::new(Config::new());
+        let config = Arc::new(Config::new());
         let runtime = Arc::new(
             tokio::runtime::Builder::new_multi_thread()
                 .enable_all()
@@ -32,14 +31,14 @@ impl VpnApp {
                 .unwrap(),
         );
 
-        let mut app = VpnApp {
+        let app = VpnApp {
             log: log.clone(),
             config: config.clone(),
-            server: RefCell::new(None),
-            openvpn: RefCell::new(None),
+            server: Mutex::new(None),
+            openvpn: Mutex::new(None),
             openvpn_connection: Arc::new(Mutex::new(None)),
             runtime: runtime.clone(),
-            dns: Rc::new(DnsResolver::new(config, log.clone(), runtime)),
+            dns: Arc::new(DnsResolver::new(config, log.clone(), runtime)),
             state: Arc::new(Mutex::new(None)),
             connection_manager: Arc::new(Mutex::new(None)),
         };
@@ -58,10 +57,3 @@ impl VpnApp {
         *current = Some(manager)
     }
 }
-
-#[derive(Clone, Copy, Debug)]
-pub enum State {
-    Connecting,
-    Connected,
-    Disconnected,
-}
@@ -1,22 +1,46 @@
+use crate::config::{Candidate, Cidr, PrivilegeHelper, RoutePolicy};
+use crate::diagnose::{Diagnosis, Tail};
+use crate::error::ConnectError;
 use crate::local_config::LocalConfig;
 use crate::log::Log;
+use crate::management::{
+    EventHandler, ManagementClient, ManagementEvent, ManagementListener, PasswordEvent,
+};
 use crate::saml_server::Saml;
 use lazy_static::lazy_static;
 use std::env;
 use std::ffi::OsString;
-use std::fs::{create_dir_all, remove_file, File};
-use std::io::Write;
+use std::fs::create_dir_all;
+use std::io;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 use std::sync::{Arc, Mutex};
-use temp_dir::TempDir;
-use tokio::io::AsyncBufReadExt;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::{Child, ChildStdout};
+use tokio::task::JoinHandle;
+
+/// How long OpenVPN may take to connect to the management socket. Covers the
+/// password prompt of sudo/pkexec.
+const MANAGEMENT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Pushed options dropped when the profile only routes its own networks. The
+/// text is matched against the start of each option.
+const PUSHED_ROUTE_OPTIONS: &[&str] = &[
+    "route ",
+    "route-ipv6 ",
+    "redirect-gateway",
+    "redirect-private",
+];
+
+/// How long the last output of an exited process may take to be read.
+const OUTPUT_TIMEOUT: Duration = Duration::from_secs(2);
 
 // Change from a relative path to a temp file path
 lazy_static! {
     static ref SHARED_DIR: String = {
         let path = dirs::data_local_dir()
-            .unwrap_or_else(|| PathBuf::from(env::temp_dir()))
+            .unwrap_or_else(env::temp_dir)
             .join("openaws-vpn-client");
 
         // Create the directory if it doesn't exist
@@ -28,56 +52,19 @@ lazy_static! {
 
         path.to_string_lossy().to_string()
     };
-
-static ref DEFAULT_PWD_FILE: String = {
-    let path = Path::new(&*SHARED_DIR).join("pwd.txt");
-
-    // Create properly formatted file if it doesn't exist
-    if let Ok(mut file) = File::create(&path) {
-        // Initial auth needs "N/A" and "ACS::35001"
-        writeln!(file, "N/A").unwrap_or_else(|e| eprintln!("Error writing username: {}", e));
-        writeln!(file, "ACS::35001").unwrap_or_else(|e| eprintln!("Error writing password: {}", e));
-    }
-
-    path.to_string_lossy().to_string()
-};
-
-    static ref OPENVPN_FILE: String = {
-         let custom_path = "/Users/niteshchowdharybalusu/Downloads/openaws-vpn-client/share/openvpn/bin/openvpn";
-
-    if Path::new(custom_path).exists() {
-        return custom_path.to_string();
-    }
-
-
-        // Try to find openvpn in common locations
-        let possible_paths = vec![
-            "/usr/bin/openvpn",
-            "/usr/local/bin/openvpn",
-            "/opt/homebrew/bin/openvpn", // Common on macOS with Homebrew
-            "/opt/local/bin/openvpn",    // Common on macOS with MacPorts
-            "C:\\Program Files\\OpenVPN\\bin\\openvpn.exe", // Windows path
-        ];
-
-        for path in &possible_paths {
-            if Path::new(path).exists() {
-                return path.to_string();
-            }
-        }
-
-        // Fall back to hoping it's in PATH
-        "openvpn".to_string()
-    };
 }
 
 pub struct ProcessInfo {
     pub pid: Mutex<Option<u32>>,
+    /// Needed again to stop the process.
+    pub privilege: PrivilegeHelper,
 }
 
 impl ProcessInfo {
-    pub fn new() -> Self {
+    pub fn new(privilege: PrivilegeHelper) -> Self {
         Self {
             pid: Mutex::new(None),
+            privilege,
         }
     }
 }
@@ -88,132 +75,174 @@ pub struct AwsSaml {
     pub pwd: String,
 }
 
-pub async fn run_ovpn(log: Arc<Log>, config: PathBuf, addr: String, port: u16) -> AwsSaml {
-    // Log the paths we're using for debugging
-    log.append(format!("Using shared directory: {}", SHARED_DIR.as_str()));
-    log.append(format!("Using pwd file: {}", DEFAULT_PWD_FILE.as_str()));
-    log.append(format!("Using OpenVPN path: {}", OPENVPN_FILE.as_str()));
+/// How an OpenVPN connection process ended.
+#[derive(Debug)]
+pub struct OvpnExit {
+    pub code: i32,
+    /// Whether the tunnel came up before the process exited.
+    pub connected: bool,
+    /// Why the process ended, if it was not asked to.
+    pub error: Option<ConnectError>,
+    /// Explanation of the failure found in the output of the process.
+    pub diagnosis: Option<Diagnosis>,
+}
 
-    if !Path::new(OPENVPN_FILE.as_str()).exists() {
-        log.append(format!(
-            "WARNING: OpenVPN executable not found at '{}'",
-            OPENVPN_FILE.as_str()
-        ));
+impl OvpnExit {
+    fn new() -> OvpnExit {
+        OvpnExit {
+            code: -1,
+            connected: false,
+            error: None,
+            diagnosis: None,
+        }
     }
 
-    // Create the command
-    let mut cmd = tokio::process::Command::new(OPENVPN_FILE.as_str());
-    cmd.arg("--config")
-        .arg(&config)
-        .arg("--verb")
-        .arg("3")
-        .arg("--proto")
-        .arg("udp")
-        .arg("--remote")
-        .arg(addr)
-        .arg(format!("{}", port))
-        .arg("--auth-user-pass")
-        .arg(DEFAULT_PWD_FILE.as_str())
-        .stdout(Stdio::piped())
-        .current_dir(SHARED_DIR.as_str());
+    fn failed(error: ConnectError) -> OvpnExit {
+        OvpnExit {
+            error: Some(error),
+            ..OvpnExit::new()
+        }
+    }
 
-    // Log the full command for debugging
-    log.append(format!("Executing command: {:?}", cmd));
+    /// Whether the server rejected the SAML response.
+    pub fn auth_failed(&self) -> bool {
+        self.error == Some(ConnectError::AuthRejected)
+    }
+}
 
-    // Try to spawn the process, but handle errors gracefully
-    let out = match cmd.spawn() {
-        Ok(o) => o,
-        Err(e) => {
-            log.append(format!("Error starting OpenVPN: {}", e));
-            panic!("Failed to start OpenVPN: {}", e);
-        }
-    };
+/// How OpenVPN is started.
+#[derive(Clone, Debug)]
+pub struct Launch {
+    pub openvpn: PathBuf,
+    pub privilege: PrivilegeHelper,
+    /// OpenVPN `--verb` level.
+    pub verbosity: u8,
+    /// OpenVPN `--inactive`, from the session policy.
+    pub inactive_timeout: Option<Duration>,
+    pub routes: RoutePolicy,
+}
 
-    let pid = out.id().unwrap_or(0);
-    let stdout = match out.stdout {
-        Some(s) => s,
-        None => {
-            log.append("Failed to capture OpenVPN stdout");
-            panic!("Failed to capture OpenVPN stdout");
-        }
-    };
+pub async fn run_ovpn(
+    log: Arc<Log>,
+    launch: Launch,
+    config: PathBuf,
+    target: Candidate,
+    acs_port: u16,
+    on_event: EventHandler,
+) -> Result<AwsSaml, OvpnExit> {
+    // Log the paths we're using for debugging
+    log.append(format!("Using shared directory: {}", SHARED_DIR.as_str()));
 
-    let buf = tokio::io::BufReader::new(stdout);
-    let log = log.clone();
-    let mut lines = buf.lines();
+    let listener = ManagementListener::bind().map_err(|e| {
+        log.append(format!("Failed to create management socket: {}", e));
+        OvpnExit::failed(ConnectError::Management(e.to_string()))
+    })?;
 
-    let mut next = lines.next_line().await;
-    let mut addr = None::<String>;
-    let mut pwd = None::<String>;
+    // Create the command
+    let mut cmd = tokio::process::Command::new(&launch.openvpn);
+    cmd.args(saml_args(
+        &config,
+        &target,
+        launch.verbosity,
+        &listener.args(),
+    ))
+    .stdout(Stdio::piped())
+    .current_dir(SHARED_DIR.as_str())
+    .kill_on_drop(true);
 
-    loop {
-        if let Ok(ref line) = next {
-            if let Some(line) = line {
-                log.append_process(pid, line.as_str());
+    // Log the full command for debugging
+    log.append(format!("Executing command: {:?}", cmd));
 
-                // Check for different types of auth failures that might contain SAML info
-                let auth_prefix_crv1 = "AUTH_FAILED,CRV1";
-                let auth_prefix_simple = "AUTH_FAILED";
-                let prefix = "https://";
+    let mut out = cmd.spawn().map_err(|e| {
+        log.append(format!("Error starting OpenVPN: {}", e));
+        OvpnExit::failed(ConnectError::Spawn(e.to_string()))
+    })?;
 
-                if line.contains(auth_prefix_crv1) {
-                    log.append_process(pid, format!("Found SAML auth redirect: {}", line).as_str());
-                    if let Some(find) = line.find(prefix) {
-                        addr = Some((&line[find..]).to_string());
+    let pid = out.id().unwrap_or(0);
+    let tail = Arc::new(Tail::default());
+    let output = forward_output(log.clone(), pid, out.stdout.take(), tail.clone());
+
+    let mut saml = None;
+    let mut error = None;
+    match attach(&log, listener, &mut out).await {
+        Ok(mut client) => {
+            while let Some(event) = client.next_event().await {
+                on_event(&event);
+                if let Some(tls) = tls_error(&event) {
+                    error.get_or_insert(ConnectError::Tls(tls));
+                }
 
-                        if let Some(auth_find) = line
-                            .find(auth_prefix_crv1)
-                            .map(|v| v + auth_prefix_crv1.len() + 1)
+                match event {
+                    ManagementEvent::Password(PasswordEvent::Need { realm }) => {
+                        if let Err(e) = client
+                            .send_credentials(&realm, "N/A", &acs_password(acs_port))
+                            .await
                         {
-                            if auth_find < find {
-                                let sub = &line[auth_find..find - 1];
-                                if let Some(e) = sub.split(":").skip(1).next() {
-                                    pwd = Some(e.to_string());
-                                }
-                            }
+                            log.append(format!("Failed to send credentials to OpenVPN: {}", e));
+                            error = Some(ConnectError::Management(e.to_string()));
+                            break;
                         }
                     }
+                    ManagementEvent::Password(PasswordEvent::VerificationFailed {
+                        challenge: Some(challenge),
+                        ..
+                    }) => {
+                        log.append_process(pid, "Found SAML auth redirect");
+                        saml = Some(AwsSaml {
+                            url: challenge.text,
+                            pwd: challenge.state_id,
+                        });
+                        break;
+                    }
+                    ManagementEvent::Password(PasswordEvent::VerificationFailed {
+                        challenge: None,
+                        ..
+                    }) => {
+                        // Try to open the AWS Client VPN portal directly
+                        log.append_process(
+                            pid,
+                            "Regular auth failure detected, trying direct portal access",
+                        );
+                        let portal_url = portal_url(&config);
+                        log.append_process(
+                            pid,
+                            format!("Opening browser to: {}", portal_url).as_str(),
+                        );
+                        saml = Some(AwsSaml {
+                            url: portal_url,
+                            pwd: "direct-portal".to_string(),
+                        });
+                        break;
+                    }
+                    ManagementEvent::Fatal(_) => break,
+                    _ => {}
                 }
-                // Fix the variable name issue:
-                else if line.contains(auth_prefix_simple) {
-                    // Try to open the AWS Client VPN portal directly
-                    log.append_process(
-                        pid,
-                        "Regular auth failure detected, trying direct portal access",
-                    );
-
-                    // Extract domain from config file or use a default
-                    let config_path = config.to_string_lossy();
-                    let portal_url = if config_path.contains("cvpn-endpoint") {
-                        // Try to extract the endpoint ID from the config path
-                        let parts: Vec<&str> = config_path.split("cvpn-endpoint").collect();
-                        if parts.len() > 1 {
-                            format!("https://self-service.clientvpn.amazonaws.com/endpoints/cvpn-endpoint{}", parts[1].split('.').next().unwrap_or(""))
-                        } else {
-                            "https://self-service.clientvpn.amazonaws.com/".to_string()
-                        }
-                    } else {
-                        // If we can't determine the domain, use a generic URL
-                        "https://self-service.clientvpn.amazonaws.com/".to_string()
-                    };
-
-                    log.append_process(pid, format!("Opening browser to: {}", portal_url).as_str());
-                    addr = Some(portal_url);
-                    pwd = Some("direct-portal".to_string());
-                }
-            } else {
-                break;
             }
-        } else {
-            break;
+
+            let _ = client.terminate().await;
         }
+        Err(e) => error = Some(e),
+    }
 
-        next = lines.next_line().await;
+    let exit = finish(
+        &log,
+        &mut out,
+        output,
+        &tail,
+        OvpnExit {
+            error,
+            ..OvpnExit::new()
+        },
+    )
+    .await;
+
+    if let Some(saml) = saml {
+        return Ok(saml);
     }
 
     // If we didn't get authentication info, print a helpful message
-    if addr.is_none() || pwd.is_none() {
-        log.append("Failed to extract SAML URL or password from OpenVPN output");
+    if exit.error.is_none() {
+        log.append("Failed to receive a SAML challenge from the VPN server");
         log.append("This may be because:");
         log.append(
             "1. Your VPN config file doesn't have the correct SAML authentication directives",
@@ -223,118 +252,55 @@ pub async fn run_ovpn(log: Arc<Log>, config: PathBuf, addr: String, port: u16) -
         log.append("auth-federate");
         log.append("auth-user-pass");
         log.append("auth-retry interact");
-
-        // Provide a direct URL to try manually
-        let hostname = addr.unwrap_or_else(|| "your-domain.com".to_string());
-        log.append(format!("You can try manually opening: https://self-service.clientvpn.amazonaws.com/endpoints/{}", hostname));
-
-        panic!("Failed to extract SAML URL or password");
     }
 
-    AwsSaml {
-        url: addr.unwrap(),
-        pwd: pwd.unwrap(),
-    }
+    Err(OvpnExit {
+        error: exit.error.or(Some(ConnectError::MissingSamlChallenge)),
+        ..exit
+    })
 }
 
 pub async fn connect_ovpn(
     log: Arc<Log>,
+    launch: Launch,
     config: PathBuf,
-    addr: String,
-    port: u16,
+    target: Candidate,
     saml: Saml,
     process_info: Arc<ProcessInfo>,
-) -> i32 {
-    let temp = TempDir::new().unwrap();
-    let temp_pwd = temp.child("pwd.txt");
-
-    if temp_pwd.exists() {
-        remove_file(&temp_pwd).unwrap_or_else(|e| {
-            log.append(format!("Failed to remove temp file: {}", e));
-        });
-    }
-
-    println!(
-        "Temp pwd file before saving: {}",
-        temp_pwd.to_string_lossy()
-    );
-
-    let mut save = match File::create(&temp_pwd) {
-        Ok(f) => f,
+    on_event: EventHandler,
+) -> OvpnExit {
+    let listener = match ManagementListener::bind() {
+        Ok(l) => l,
         Err(e) => {
-            log.append(format!("Failed to create temp pwd file: {}", e));
-            panic!("Failed to create temp pwd file: {}", e);
+            log.append(format!("Failed to create management socket: {}", e));
+            return OvpnExit::failed(ConnectError::Management(e.to_string()));
         }
     };
 
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::PermissionsExt;
-        if let Ok(mut perms) = std::fs::metadata(&temp_pwd).map(|m| m.permissions()) {
-            perms.set_mode(0o600); // User read/write only
-            let _ = std::fs::set_permissions(&temp_pwd, perms);
-            log.append("Set secure permissions on pwd file");
-        }
-    }
-
-    println!("Temp pwd file: {}", temp_pwd.to_string_lossy());
-
-    write!(save, "N/A\nCRV1::{}::{}\n", saml.pwd, saml.data).unwrap_or_else(|e| {
-        log.append(format!("Failed to write to temp pwd file: {}", e));
-    });
-
     log.append(format!(
         "SAML auth string: CRV1::{}::{}",
         saml.pwd.len(),
         saml.data.len()
     ));
 
-    let b = match std::fs::canonicalize(&temp_pwd) {
-        Ok(p) => p,
-        Err(e) => {
-            log.append(format!("Failed to canonicalize temp pwd path: {}", e));
-            temp_pwd.to_path_buf()
-        }
-    };
-
-    // Check for sudo or pkexec
-    let sudo_cmd = if cfg!(target_os = "macos") {
-        // macOS typically uses sudo
-        "sudo"
-    } else {
-        // Linux typically has pkexec, but fall back to sudo
-        if Path::new("/usr/bin/pkexec").exists() {
-            "pkexec"
-        } else {
-            "sudo"
+    let args = connect_args(
+        &config,
+        &target,
+        launch.verbosity,
+        launch.inactive_timeout,
+        &launch.routes,
+        &listener.args(),
+    );
+    let mut cmd = match launch.privilege.command() {
+        Some(helper) => {
+            log.append(format!("Using privilege escalation command: {}", helper));
+            let mut cmd = tokio::process::Command::new(helper);
+            cmd.arg(&launch.openvpn);
+            cmd
         }
+        None => tokio::process::Command::new(&launch.openvpn),
     };
-
-    log.append(format!("Using privilege escalation command: {}", sudo_cmd));
-
-    let mut cmd = tokio::process::Command::new(sudo_cmd);
-    cmd.arg(OPENVPN_FILE.as_str())
-        .arg("--config")
-        .arg(&config)
-        .arg("--verb")
-        .arg("3")
-        .arg("--auth-nocache")
-        .arg("--inactive")
-        .arg("3600")
-        .arg("--auth-retry")
-        .arg("none")
-        .arg("--pull")
-        .arg("--proto")
-        .arg("udp")
-        .arg("--remote")
-        .arg(addr)
-        .arg(format!("{}", port))
-        .arg("--script-security")
-        .arg("2")
-        .arg("--route-up")
-        .arg(rm_file_command(&b))
-        .arg("--auth-user-pass")
-        .arg(&b)
+    cmd.args(args)
         .stdout(Stdio::piped())
         .current_dir(SHARED_DIR.as_str())
         .kill_on_drop(true);
@@ -346,7 +312,7 @@ pub async fn connect_ovpn(
         Ok(o) => o,
         Err(e) => {
             log.append(format!("Failed to start OpenVPN connection: {}", e));
-            panic!("Failed to start OpenVPN connection: {}", e);
+            return OvpnExit::failed(ConnectError::Spawn(e.to_string()));
         }
     };
 
@@ -358,44 +324,283 @@ pub async fn connect_ovpn(
         LocalConfig::save_last_pid(Some(pid));
     }
 
-    let stdout = match out.stdout.take() {
+    let tail = Arc::new(Tail::default());
+    let output = forward_output(log.clone(), pid, out.stdout.take(), tail.clone());
+
+    let exit = match attach(&log, listener, &mut out).await {
+        Ok(mut client) => drive_tunnel(&log, pid, &mut client, &saml, &on_event).await,
+        Err(e) => OvpnExit::failed(e),
+    };
+
+    let mut exit = finish(&log, &mut out, output, &tail, exit).await;
+    if !exit.connected && exit.error.is_none() {
+        exit.error = Some(ConnectError::Exited(exit.code));
+    }
+
+    exit
+}
+
+/// Waits for the process and the rest of its output, then looks for the
+/// reason it exited.
+async fn finish(
+    log: &Log,
+    process: &mut Child,
+    output: Option<JoinHandle<()>>,
+    tail: &Tail,
+    mut exit: OvpnExit,
+) -> OvpnExit {
+    exit.code = match process.wait().await {
+        Ok(status) => status.code().unwrap_or(-1),
+        Err(e) => {
+            log.append(format!("Error waiting for OpenVPN process: {}", e));
+            -1
+        }
+    };
+    if let Some(output) = output {
+        let _ = tokio::time::timeout(OUTPUT_TIMEOUT, output).await;
+    }
+
+    exit.diagnosis = tail.diagnose(exit.code);
+    if let Some(diagnosis) = &exit.diagnosis {
+        if matches!(exit.error, None | Some(ConnectError::Exited(_))) {
+            exit.error = Some(diagnosis.problem.error(exit.code));
+        }
+    }
+
+    exit
+}
+
+/// Answers the credential request of the tunnel run with the SAML response and
+/// follows its events until OpenVPN closes the management connection. The
+/// response only ever travels over the socket. The exit code is left to the caller.
+async fn drive_tunnel(
+    log: &Log,
+    pid: u32,
+    client: &mut ManagementClient,
+    saml: &Saml,
+    on_event: &EventHandler,
+) -> OvpnExit {
+    let password = format!("CRV1::{}::{}", saml.pwd, saml.data);
+    let mut exit = OvpnExit::new();
+
+    while let Some(event) = client.next_event().await {
+        on_event(&event);
+        if let Some(tls) = tls_error(&event) {
+            exit.error.get_or_insert(ConnectError::Tls(tls));
+        }
+
+        match event {
+            ManagementEvent::Password(PasswordEvent::Need { realm }) => {
+                if let Err(e) = client.send_credentials(&realm, "N/A", &password).await {
+                    log.append(format!("Failed to send credentials to OpenVPN: {}", e));
+                    exit.error = Some(ConnectError::Management(e.to_string()));
+                    break;
+                }
+            }
+            ManagementEvent::Password(PasswordEvent::VerificationFailed { .. }) => {
+                log.append_process(pid, "The VPN server rejected the SAML response");
+                exit.error = Some(ConnectError::AuthRejected);
+            }
+            ManagementEvent::State(state) if state.name == "CONNECTED" => {
+                exit.connected = true;
+                exit.error = None;
+            }
+            _ => {}
+        }
+    }
+
+    exit
+}
+
+/// Waits for OpenVPN to connect to the management socket and starts the event
+/// stream. Gives up when the process exits first.
+async fn attach(
+    log: &Log,
+    listener: ManagementListener,
+    process: &mut Child,
+) -> Result<ManagementClient, ConnectError> {
+    let client = tokio::select! {
+        client = listener.accept(MANAGEMENT_TIMEOUT) => client,
+        status = process.wait() => {
+            log.append(format!(
+                "OpenVPN exited before connecting to the management interface: {:?}",
+                status
+            ));
+            let code = status.ok().and_then(|s| s.code()).unwrap_or(-1);
+            return Err(ConnectError::Exited(code));
+        }
+    };
+
+    let mut client = match client {
+        Ok(c) => c,
+        Err(e) => {
+            log.append(format!("Failed to attach to OpenVPN: {}", e));
+            let _ = process.kill().await;
+            return Err(match e.kind() {
+                io::ErrorKind::TimedOut => ConnectError::Timeout,
+                _ => ConnectError::Management(e.to_string()),
+            });
+        }
+    };
+
+    if let Err(e) = client.start().await {
+        log.append(format!("Failed to start OpenVPN: {}", e));
+        let _ = process.kill().await;
+        return Err(ConnectError::Management(e.to_string()));
+    }
+
+    Ok(client)
+}
+
+/// The reason of a failed TLS handshake, from the log or state of OpenVPN.
+fn tls_error(event: &ManagementEvent) -> Option<String> {
+    match event {
+        ManagementEvent::Log { message, .. }
+            if message.contains("TLS Error") || message.contains("TLS handshake failed") =>
+        {
+            Some(message.clone())
+        }
+        ManagementEvent::State(state) if state.description == "tls-error" => {
+            Some("TLS handshake failed".to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Copies the process output into the log and `tail` until the process closes it.
+fn forward_output(
+    log: Arc<Log>,
+    pid: u32,
+    stdout: Option<ChildStdout>,
+    tail: Arc<Tail>,
+) -> Option<JoinHandle<()>> {
+    let stdout = match stdout {
         Some(s) => s,
         None => {
-            log.append("Failed to capture OpenVPN connection stdout");
-            panic!("Failed to capture OpenVPN connection stdout");
+            log.append("Failed to capture OpenVPN stdout");
+            return None;
         }
     };
 
-    let buf = tokio::io::BufReader::new(stdout);
-    let log = log.clone();
-    let mut lines = buf.lines();
+    Some(tokio::spawn(async move {
+        let mut lines = BufReader::new(stdout).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            tail.push(&line);
+            log.append_process(pid, line.as_str());
+        }
+    }))
+}
 
-    let mut next = lines.next_line().await;
+/// Password of the first run. The server answers it with the SAML challenge,
+/// the login page then posts back to the local SAML server on this port.
+fn acs_password(port: u16) -> String {
+    format!("ACS::{}", port)
+}
 
-    loop {
-        if let Ok(ref line) = next {
-            if let Some(line) = line {
-                log.append_process(pid, line.as_str());
-            } else {
-                break;
-            }
-        } else {
-            break;
+/// Self-service portal of the endpoint, used when the server does not send a SAML challenge.
+fn portal_url(config: &Path) -> String {
+    // Extract domain from config file or use a default
+    let config_path = config.to_string_lossy();
+    if config_path.contains("cvpn-endpoint") {
+        // Try to extract the endpoint ID from the config path
+        let parts: Vec<&str> = config_path.split("cvpn-endpoint").collect();
+        if parts.len() > 1 {
+            return format!(
+                "https://self-service.clientvpn.amazonaws.com/endpoints/cvpn-endpoint{}",
+                parts[1].split('.').next().unwrap_or("")
+            );
         }
+    }
 
-        next = lines.next_line().await;
+    // If we can't determine the domain, use a generic URL
+    "https://self-service.clientvpn.amazonaws.com/".to_string()
+}
+
+/// Arguments for the first OpenVPN run, which only has to obtain the SAML challenge.
+fn saml_args(
+    config: &Path,
+    target: &Candidate,
+    verbosity: u8,
+    management: &[OsString],
+) -> Vec<OsString> {
+    let mut args: Vec<OsString> = vec!["--config".into(), config.into()];
+    args.push("--verb".into());
+    args.push(verbosity.to_string().into());
+    args.extend(remote_args(target));
+    args.push("--auth-user-pass".into());
+    args.extend_from_slice(management);
+    args
+}
+
+/// Arguments for the OpenVPN run that establishes the tunnel. The management
+/// options stay last, `kill_openvpn` relies on them to recognise our processes.
+fn connect_args(
+    config: &Path,
+    target: &Candidate,
+    verbosity: u8,
+    inactive_timeout: Option<Duration>,
+    routes: &RoutePolicy,
+    management: &[OsString],
+) -> Vec<OsString> {
+    let mut args: Vec<OsString> = vec!["--config".into(), config.into()];
+    args.push("--verb".into());
+    args.push(verbosity.to_string().into());
+    args.push("--auth-nocache".into());
+    if let Some(timeout) = inactive_timeout {
+        args.push("--inactive".into());
+        args.push(timeout.as_secs().to_string().into());
     }
+    args.extend(["--auth-retry", "none", "--pull"].map(OsString::from));
+    args.extend(route_args(routes));
+    args.extend(remote_args(target));
+    args.push("--auth-user-pass".into());
+    args.extend_from_slice(management);
+    args
+}
 
-    match out.wait().await {
-        Ok(status) => status.code().unwrap_or(-1),
-        Err(e) => {
-            log.append(format!("Error waiting for OpenVPN process: {}", e));
-            -1
+/// Options that make OpenVPN follow the route policy of the profile.
+pub fn route_args(policy: &RoutePolicy) -> Vec<OsString> {
+    let mut args: Vec<OsString> = Vec::new();
+    if policy.no_pull {
+        args.push("--route-nopull".into());
+    } else if !policy.accepts_pushed() {
+        for option in PUSHED_ROUTE_OPTIONS {
+            args.extend(["--pull-filter", "ignore", option].map(OsString::from));
         }
     }
+    for net in policy.include.iter().chain(&policy.routes) {
+        args.extend(route(net, None));
+    }
+    for net in &policy.exclude {
+        args.extend(route(net, Some("net_gateway")));
+    }
+    args
 }
 
-pub fn kill_openvpn(pid: u32) {
+fn route(net: &Cidr, gateway: Option<&str>) -> Vec<OsString> {
+    let mut args: Vec<OsString> = match net.netmask() {
+        Some(mask) => vec![
+            "--route".into(),
+            net.addr.to_string().into(),
+            mask.to_string().into(),
+        ],
+        None => vec!["--route-ipv6".into(), net.to_string().into()],
+    };
+    args.extend(gateway.map(OsString::from));
+    args
+}
+
+fn remote_args(target: &Candidate) -> Vec<OsString> {
+    vec![
+        "--proto".into(),
+        target.endpoint.proto.as_arg(target.addr.is_ipv6()).into(),
+        "--remote".into(),
+        target.addr.to_string().into(),
+        target.endpoint.port.to_string().into(),
+    ]
+}
+
+pub fn kill_openvpn(pid: u32, privilege: PrivilegeHelper) {
     if pid == 0 || pid == 1 {
         LocalConfig::save_last_pid(None);
         return;
@@ -417,33 +622,27 @@ pub fn kill_openvpn(pid: u32) {
     };
 
     if let Ok(msg) = String::from_utf8(info.stdout) {
-        let last = msg.lines().rev().next();
+        let last = msg.lines().next_back();
         if let Some(last) = last {
-            if last.len() > 0 && last.chars().next().map(|v| v == '/').unwrap_or(false) {
-                if last.contains("openvpn --config /")
-                    && last.contains("--auth-user-pass /")
-                    && last.ends_with("pwd.txt")
-                {
-                    // Check for sudo or pkexec
-                    let sudo_cmd = if cfg!(target_os = "macos") {
-                        "sudo"
-                    } else if Path::new("/usr/bin/pkexec").exists() {
-                        "pkexec"
-                    } else {
-                        "sudo"
+            if !last.is_empty() && last.chars().next().map(|v| v == '/').unwrap_or(false) {
+                if last.contains("openvpn --config /") && last.contains("--management-client") {
+                    let mut kill = match privilege.command() {
+                        Some(helper) => {
+                            let mut kill = Command::new(helper);
+                            kill.arg("kill");
+                            kill
+                        }
+                        None => Command::new("kill"),
                     };
 
-                    let mut p = Command::new(sudo_cmd)
-                        .arg("kill")
-                        .arg(format!("{}", pid))
-                        .spawn()
-                        .unwrap_or_else(|e| {
-                            println!("Failed to kill OpenVPN process {}: {}", pid, e);
-                            panic!("Failed to kill OpenVPN process {}: {}", pid, e);
-                        });
-
-                    let _ = p.wait();
-                    LocalConfig::save_last_pid(None);
+                    match kill.arg(format!("{}", pid)).spawn() {
+                        Ok(mut p) => {
+                            let _ = p.wait();
+                            LocalConfig::save_last_pid(None);
+                        }
+                        // Keep the pid, the next start tries again
+                        Err(e) => println!("Failed to kill OpenVPN process {}: {}", pid, e),
+                    }
                 } else {
                     LocalConfig::save_last_pid(None);
                 }
@@ -454,13 +653,251 @@ pub fn kill_openvpn(pid: u32) {
     }
 }
 
-fn rm_file_command(dir: &PathBuf) -> OsString {
-    let mut str = OsString::new();
-    if cfg!(target_os = "windows") {
-        str.push("cmd.exe /c del ");
-    } else {
-        str.push("/usr/bin/env rm ");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Endpoint;
+    use crate::ovpn::Proto;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn candidate(proto: Proto, port: u16) -> Candidate {
+        Candidate {
+            endpoint: Endpoint {
+                host: "cvpn-endpoint-0123.prod.clientvpn.us-east-1.amazonaws.com".to_string(),
+                port,
+                proto,
+            },
+            addr: "192.0.2.10".parse().unwrap(),
+        }
+    }
+
+    fn management() -> Vec<OsString> {
+        [
+            "--management",
+            "/tmp/m/management.sock",
+            "unix",
+            "--management-client",
+        ]
+        .map(OsString::from)
+        .to_vec()
+    }
+
+    fn strings(args: Vec<OsString>) -> Vec<String> {
+        args.into_iter()
+            .map(|a| a.to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn saml_args_use_endpoint_transport() {
+        let args = strings(saml_args(
+            Path::new("/tmp/profile-oavc.ovpn"),
+            &candidate(Proto::Tcp, 443),
+            3,
+            &management(),
+        ));
+
+        assert_eq!(
+            args,
+            vec![
+                "--config",
+                "/tmp/profile-oavc.ovpn",
+                "--verb",
+                "3",
+                "--proto",
+                "tcp-client",
+                "--remote",
+                "192.0.2.10",
+                "443",
+                "--auth-user-pass",
+                "--management",
+                "/tmp/m/management.sock",
+                "unix",
+                "--management-client",
+            ]
+        );
+    }
+
+    #[test]
+    fn connect_args_use_endpoint_transport() {
+        let args = strings(connect_args(
+            Path::new("/tmp/profile-oavc.ovpn"),
+            &candidate(Proto::Udp, 1194),
+            5,
+            Some(Duration::from_secs(1800)),
+            &RoutePolicy::default(),
+            &management(),
+        ));
+
+        let verb = args.iter().position(|a| a == "--verb").unwrap();
+        assert_eq!(args[verb + 1], "5");
+        let inactive = args.iter().position(|a| a == "--inactive").unwrap();
+        assert_eq!(args[inactive + 1], "1800");
+        let proto = args.iter().position(|a| a == "--proto").unwrap();
+        assert_eq!(args[proto + 1], "udp");
+        let remote = args.iter().position(|a| a == "--remote").unwrap();
+        assert_eq!(args[remote + 1..remote + 3], ["192.0.2.10", "1194"]);
+        assert_eq!(args.iter().filter(|a| *a == "--proto").count(), 1);
+        assert_eq!(args[args.len() - 1], "--management-client");
+    }
+
+    #[test]
+    fn route_args_follow_the_policy() {
+        let nets = |nets: &[&str]| nets.iter().filter_map(|n| Cidr::parse(n)).collect();
+        assert!(route_args(&RoutePolicy::default()).is_empty());
+
+        let include = RoutePolicy {
+            include: nets(&["10.0.0.0/16", "2001:db8::/32"]),
+            exclude: nets(&["10.0.5.0/24"]),
+            ..RoutePolicy::default()
+        };
+        let args = strings(route_args(&include));
+        assert_eq!(args.iter().filter(|a| *a == "--pull-filter").count(), 4);
+        assert_eq!(args[..3], ["--pull-filter", "ignore", "route "]);
+        assert_eq!(
+            args[12..],
+            [
+                "--route",
+                "10.0.0.0",
+                "255.255.0.0",
+                "--route-ipv6",
+                "2001:db8::/32",
+                "--route",
+                "10.0.5.0",
+                "255.255.255.0",
+                "net_gateway"
+            ]
+        );
+
+        let no_pull = RoutePolicy {
+            no_pull: true,
+            routes: nets(&["172.16.0.0/12"]),
+            ..RoutePolicy::default()
+        };
+        assert_eq!(
+            strings(route_args(&no_pull)),
+            ["--route-nopull", "--route", "172.16.0.0", "255.240.0.0"]
+        );
+    }
+
+    #[test]
+    fn remote_args_for_ipv6() {
+        let mut target = candidate(Proto::Tcp, 443);
+        target.addr = "2001:db8::10".parse().unwrap();
+
+        assert_eq!(
+            strings(remote_args(&target)),
+            ["--proto", "tcp6-client", "--remote", "2001:db8::10", "443"]
+        );
+    }
+
+    #[test]
+    fn recognises_tls_errors() {
+        let log = ManagementEvent::parse(">LOG:1700000000,N,TLS Error: TLS handshake failed");
+        assert_eq!(
+            tls_error(&log),
+            Some("TLS Error: TLS handshake failed".to_string())
+        );
+
+        let state = ManagementEvent::parse(">STATE:1700000000,RECONNECTING,tls-error,,");
+        assert!(tls_error(&state).is_some());
+
+        let other = ManagementEvent::parse(">LOG:1700000000,I,Initialization Sequence Completed");
+        assert_eq!(tls_error(&other), None);
+    }
+
+    /// Whether any regular file below `dirs` contains `needle`.
+    fn on_disk(dirs: &[PathBuf], needle: &str) -> bool {
+        fn walk(dir: &Path, needle: &[u8], depth: usize) -> bool {
+            let entries = match std::fs::read_dir(dir) {
+                Ok(e) => e,
+                Err(_) => return false,
+            };
+            entries.filter_map(|e| e.ok()).any(|entry| {
+                let kind = match entry.file_type() {
+                    Ok(t) => t,
+                    Err(_) => return false,
+                };
+                if kind.is_dir() {
+                    depth > 0 && walk(&entry.path(), needle, depth - 1)
+                } else if kind.is_file() {
+                    std::fs::read(entry.path())
+                        .map(|content| content.windows(needle.len()).any(|w| w == needle))
+                        .unwrap_or(false)
+                } else {
+                    false
+                }
+            })
+        }
+
+        dirs.iter().any(|d| walk(d, needle.as_bytes(), 4))
+    }
+
+    #[tokio::test]
+    async fn saml_response_is_never_written_to_disk() {
+        let assertion = format!(
+            "PHNhbWxwOlJlc3BvbnNl{}",
+            hex::encode(rand::random::<[u8; 16]>())
+        );
+        let saml = Saml {
+            data: assertion.clone(),
+            pwd: "instance-1/123/abc".to_string(),
+        };
+        let listener = ManagementListener::bind().unwrap();
+        let socket = listener.args()[1].clone();
+        // Where the client writes: the listener's own directory and the one OpenVPN runs in
+        let dirs = vec![
+            Path::new(&socket).parent().unwrap().to_path_buf(),
+            PathBuf::from(SHARED_DIR.as_str()),
+        ];
+
+        let openvpn = {
+            let dirs = dirs.clone();
+            let assertion = assertion.clone();
+            tokio::spawn(async move {
+                let mut stream = tokio::net::UnixStream::connect(socket).await.unwrap();
+                stream
+                    .write_all(b">PASSWORD:Need 'Auth' username/password\n")
+                    .await
+                    .unwrap();
+
+                let mut received = String::new();
+                let mut buf = [0u8; 1024];
+                while !received.contains(&assertion) {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    assert!(n > 0, "credentials were not sent over the socket");
+                    received.push_str(&String::from_utf8_lossy(&buf[..n]));
+                }
+                assert!(!on_disk(&dirs, &assertion));
+
+                stream
+                    .write_all(b">STATE:1700000000,CONNECTED,SUCCESS,10.0.0.2,192.0.2.10\n")
+                    .await
+                    .unwrap();
+            })
+        };
+
+        let mut client = listener.accept(Duration::from_secs(5)).await.unwrap();
+        assert!(!on_disk(&dirs, &assertion));
+
+        let checks = Arc::new(AtomicUsize::new(0));
+        let on_event: EventHandler = {
+            let dirs = dirs.clone();
+            let assertion = assertion.clone();
+            let checks = checks.clone();
+            Arc::new(move |_| {
+                assert!(!on_disk(&dirs, &assertion));
+                checks.fetch_add(1, Ordering::SeqCst);
+            })
+        };
+
+        let log = Log::new();
+        let exit = drive_tunnel(&log, 0, &mut client, &saml, &on_event).await;
+        openvpn.await.unwrap();
+
+        assert!(exit.connected && exit.error.is_none());
+        assert_eq!(checks.load(Ordering::SeqCst), 2);
+        assert!(!on_disk(&dirs, &assertion));
     }
-    str.push(dir);
-    str
 }
@@ -1,31 +1,566 @@
-use lazy_static::lazy_static;
-use std::collections::HashSet;
+use crate::dns::{DnsCache, ResolveError};
+use crate::ovpn::{OvpnProfile, ParseError, Proto};
+use hyper::Uri;
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+use serde::{Deserialize, Serialize};
 use std::ffi::{OsStr, OsString};
+use std::fmt;
 use std::fs::{create_dir_all, remove_file, File};
-use std::io::Read;
 use std::io::Write;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
 type StdMutex<T> = std::sync::Mutex<T>;
 type TokioMutex<T> = tokio::sync::Mutex<T>;
 
-lazy_static! {
-    static ref CLEAN_KEYS: HashSet<String> = {
-        let mut set = HashSet::new();
-        set.insert("remote ".to_string());
-        set.insert("remote-random-hostname".to_string());
-        set.insert("auth-user-pass".to_string());
-        set.insert("auth-federate".to_string());
-        set.insert("auth-retry interact".to_string());
-        set
-    };
+/// Directives that are replaced by command line arguments when OpenVPN is launched.
+const CLEAN_KEYS: &[&str] = &[
+    "remote",
+    "remote-random",
+    "remote-random-hostname",
+    "auth-user-pass",
+    "auth-federate",
+];
+
+/// Port OpenVPN uses when neither `remote` nor `port` specify one.
+const DEFAULT_PORT: u16 = 1194;
+
+/// Protocol OpenVPN uses when neither `remote` nor `proto` specify one.
+const DEFAULT_PROTO: Proto = Proto::Udp;
+
+/// Port the AWS login page posts the SAML response back to.
+pub const DEFAULT_SAML_PORT: u16 = 35001;
+
+/// OpenVPN `--verb` level used unless configured otherwise.
+pub const DEFAULT_VERBOSITY: u8 = 3;
+
+/// Highest `--verb` level OpenVPN accepts.
+pub const MAX_VERBOSITY: u8 = 11;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Parse(ParseError),
+    NoRemote,
+    InvalidPath(PathBuf),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "unable to read or write profile: {}", e),
+            ConfigError::Parse(e) => write!(f, "invalid profile at {}", e),
+            ConfigError::NoRemote => write!(f, "profile has no usable 'remote' directive"),
+            ConfigError::InvalidPath(p) => write!(f, "not a profile file path: {:?}", p),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(e: std::io::Error) -> Self {
+        ConfigError::Io(e)
+    }
+}
+
+impl From<ParseError> for ConfigError {
+    fn from(e: ParseError) -> Self {
+        ConfigError::Parse(e)
+    }
+}
+
+/// A `remote` entry of the profile with its effective port and protocol.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Endpoint {
+    pub host: String,
+    pub port: u16,
+    pub proto: Proto,
+}
+
+/// Transport settings forced by the user, taking precedence over the profile.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TransportOverride {
+    pub proto: Option<Proto>,
+    pub port: Option<u16>,
+}
+
+/// One resolved address of an [`Endpoint`]; the unit the client connects to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Candidate {
+    pub endpoint: Endpoint,
+    pub addr: IpAddr,
+}
+
+impl fmt::Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}) port {}/{}",
+            self.endpoint.host, self.addr, self.endpoint.port, self.endpoint.proto
+        )
+    }
+}
+
+/// Where the SAML callback server listens. The login page posts back to
+/// `http://127.0.0.1:<port>`, so the port is announced to the server as well.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SamlListen {
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+}
+
+impl SamlListen {
+    pub fn socket_addrs(&self) -> Vec<SocketAddr> {
+        self.addresses
+            .iter()
+            .map(|a| SocketAddr::new(*a, self.port))
+            .collect()
+    }
+}
+
+impl Default for SamlListen {
+    fn default() -> Self {
+        SamlListen {
+            addresses: vec![
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ],
+            port: DEFAULT_SAML_PORT,
+        }
+    }
+}
+
+/// How a tunnel that went down after connecting is brought back.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// Reconnects after a drop before giving up, 0 disables reconnecting.
+    pub max_attempts: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl ReconnectPolicy {
+    /// Exponential backoff for the given attempt, counting from 1. Jittered
+    /// between half and the full delay so clients dropped by the same server
+    /// restart do not come back in lockstep.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(16);
+        let delay = self
+            .initial_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay);
+        let half = delay / 2;
+        half + half.mul_f64(rand::random::<f64>())
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            max_attempts: 5,
+            initial_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+/// When to warn about and renew a tunnel that is about to be closed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionPolicy {
+    /// Maximum session length configured on the Client VPN endpoint.
+    pub max_session: Option<Duration>,
+    pub warn_before: Duration,
+    /// Start a new browser login in the background when a warning is due. The
+    /// tunnel is restarted with the new login, which interrupts traffic for the
+    /// few seconds the new tunnel takes to come up.
+    pub renew: bool,
+    /// OpenVPN's `--inactive`: the tunnel is closed after this long without traffic.
+    pub inactive_timeout: Option<Duration>,
+}
+
+impl Default for SessionPolicy {
+    fn default() -> Self {
+        SessionPolicy {
+            // AWS Client VPN default
+            max_session: Some(Duration::from_secs(24 * 3600)),
+            warn_before: Duration::from_secs(5 * 60),
+            renew: false,
+            inactive_timeout: Some(Duration::from_secs(3600)),
+        }
+    }
+}
+
+/// How the tunnel run of OpenVPN gets root privileges.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PrivilegeHelper {
+    /// sudo on macOS, pkexec on Linux when installed, sudo otherwise.
+    #[default]
+    Auto,
+    Sudo,
+    Pkexec,
+    /// Run OpenVPN directly, for a daemon that already runs as root.
+    None,
+}
+
+impl PrivilegeHelper {
+    pub fn parse(name: &str) -> Option<PrivilegeHelper> {
+        match name {
+            "auto" => Some(PrivilegeHelper::Auto),
+            "sudo" => Some(PrivilegeHelper::Sudo),
+            "pkexec" => Some(PrivilegeHelper::Pkexec),
+            "none" => Some(PrivilegeHelper::None),
+            _ => None,
+        }
+    }
+
+    /// The program to prefix OpenVPN with, if any.
+    pub fn command(&self) -> Option<&'static str> {
+        match self {
+            PrivilegeHelper::Auto if cfg!(target_os = "macos") => Some("sudo"),
+            PrivilegeHelper::Auto if Path::new("/usr/bin/pkexec").exists() => Some("pkexec"),
+            PrivilegeHelper::Auto | PrivilegeHelper::Sudo => Some("sudo"),
+            PrivilegeHelper::Pkexec => Some("pkexec"),
+            PrivilegeHelper::None => None,
+        }
+    }
+}
+
+/// How DNS options pushed by the server are applied to the host.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsBackend {
+    /// systemd-resolved when it runs, then resolvconf, then the file.
+    #[default]
+    Auto,
+    Resolved,
+    Resolvconf,
+    /// Rewrite `/etc/resolv.conf`, a backup is restored on disconnect.
+    File,
+    /// Leave the DNS configuration of the host alone.
+    None,
+}
+
+impl DnsBackend {
+    pub fn parse(name: &str) -> Option<DnsBackend> {
+        match name {
+            "auto" => Some(DnsBackend::Auto),
+            "resolved" => Some(DnsBackend::Resolved),
+            "resolvconf" => Some(DnsBackend::Resolvconf),
+            "file" => Some(DnsBackend::File),
+            "none" => Some(DnsBackend::None),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DnsBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsBackend::Auto => f.write_str("auto"),
+            DnsBackend::Resolved => f.write_str("systemd-resolved"),
+            DnsBackend::Resolvconf => f.write_str("resolvconf"),
+            DnsBackend::File => f.write_str("/etc/resolv.conf"),
+            DnsBackend::None => f.write_str("none"),
+        }
+    }
+}
+
+/// Domains resolved through the nameservers of the VPN, everything else keeps
+/// using the resolver of the host. Empty sends all queries to the VPN.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SplitDns {
+    /// Each covers itself and its subdomains.
+    pub domains: Vec<String>,
+    /// Where the built-in forwarder listens when systemd-resolved is not used.
+    pub forwarder: IpAddr,
+}
+
+/// Loopback address unlikely to be taken, 127.0.0.53 is the stub of systemd-resolved.
+pub const DEFAULT_FORWARDER: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 153));
+
+impl Default for SplitDns {
+    fn default() -> Self {
+        SplitDns::new(Vec::new())
+    }
+}
+
+impl SplitDns {
+    pub fn new(domains: Vec<String>) -> SplitDns {
+        SplitDns {
+            domains,
+            forwarder: DEFAULT_FORWARDER,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.domains.is_empty()
+    }
+
+    /// Whether `name` is one of the domains or below one of them.
+    pub fn matches(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        self.domains.iter().any(|domain| {
+            name == *domain
+                || name
+                    .strip_suffix(domain.as_str())
+                    .map_or(false, |rest| rest.ends_with('.'))
+        })
+    }
+}
+
+/// `internal.example.com` or `*.internal.example.com`, both cover the domain
+/// and its subdomains.
+pub fn parse_split_domain(value: &str) -> Option<String> {
+    let domain = value
+        .trim()
+        .trim_start_matches("*.")
+        .trim_end_matches('.')
+        .to_ascii_lowercase();
+    let valid = !domain.is_empty()
+        && domain.len() <= 253
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        });
+    valid.then_some(domain)
+}
+
+/// An IPv4 or IPv6 network such as `10.0.0.0/16`, with the host bits cleared.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+impl Cidr {
+    /// `10.0.0.0/16`, or a single address without a prefix length.
+    pub fn parse(value: &str) -> Option<Cidr> {
+        let value = value.trim();
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse().ok()?)),
+            None => (value.parse::<IpAddr>().ok()?, None),
+        };
+        let addr = match addr {
+            IpAddr::V4(addr) => {
+                let prefix = prefix.unwrap_or(32);
+                if prefix > 32 {
+                    return None;
+                }
+                let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+                return Some(Cidr {
+                    addr: IpAddr::V4(Ipv4Addr::from(u32::from(addr) & mask)),
+                    prefix,
+                });
+            }
+            IpAddr::V6(addr) => addr,
+        };
+        let prefix = prefix.unwrap_or(128);
+        if prefix > 128 {
+            return None;
+        }
+        let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+        Some(Cidr {
+            addr: IpAddr::V6(Ipv6Addr::from(u128::from(addr) & mask)),
+            prefix,
+        })
+    }
+
+    /// `255.255.0.0` for a `/16`, as OpenVPN wants IPv4 routes.
+    pub fn netmask(&self) -> Option<Ipv4Addr> {
+        match self.addr {
+            IpAddr::V4(_) => Some(Ipv4Addr::from(
+                u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0),
+            )),
+            IpAddr::V6(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Which networks go through the tunnel, instead of only the routes the
+/// server pushes.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RoutePolicy {
+    /// Routed through the tunnel, the pushed routes are ignored when set.
+    pub include: Vec<Cidr>,
+    /// Kept on the local gateway even when a pushed route covers them.
+    pub exclude: Vec<Cidr>,
+    /// Ignore the routes and DNS options the server pushes (`route-nopull`).
+    pub no_pull: bool,
+    /// Routed through the tunnel in addition to the pushed routes.
+    pub routes: Vec<Cidr>,
+}
+
+impl RoutePolicy {
+    /// Whether the routes pushed by the server are installed.
+    pub fn accepts_pushed(&self) -> bool {
+        !self.no_pull && self.include.is_empty()
+    }
+
+    /// Rejects what OpenVPN cannot apply, it only knows the local gateway of IPv4.
+    pub fn validate(&self) -> Result<(), String> {
+        match self.exclude.iter().find(|n| n.netmask().is_none()) {
+            Some(net) => Err(format!(
+                "'{}' is not an IPv4 network, only those can be excluded",
+                net
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Which addresses of an endpoint are connected to, and in what order.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AddressFamily {
+    #[default]
+    Ipv4,
+    Ipv6,
+    /// Both, alternating and starting with IPv6 as in RFC 8305.
+    HappyEyeballs,
+}
+
+impl AddressFamily {
+    pub fn parse(name: &str) -> Option<AddressFamily> {
+        match name {
+            "ipv4" => Some(AddressFamily::Ipv4),
+            "ipv6" => Some(AddressFamily::Ipv6),
+            "happy-eyeballs" => Some(AddressFamily::HappyEyeballs),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AddressFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressFamily::Ipv4 => f.write_str("ipv4"),
+            AddressFamily::Ipv6 => f.write_str("ipv6"),
+            AddressFamily::HappyEyeballs => f.write_str("happy-eyeballs"),
+        }
+    }
+}
+
+/// Whether endpoints are looked up under a random subdomain, like OpenVPN's
+/// `remote-random-hostname`. AWS answers these with a random server.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RandomHostname {
+    Always,
+    Never,
+    /// When the profile has `remote-random-hostname`.
+    #[default]
+    Profile,
+}
+
+impl RandomHostname {
+    pub fn parse(name: &str) -> Option<RandomHostname> {
+        match name {
+            "always" => Some(RandomHostname::Always),
+            "never" => Some(RandomHostname::Never),
+            "profile" => Some(RandomHostname::Profile),
+            _ => None,
+        }
+    }
+}
+
+/// How endpoint names are looked up.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResolverConfig {
+    /// Nameservers to ask, those of `/etc/resolv.conf` when empty.
+    pub nameservers: Vec<SocketAddr>,
+    /// How long to wait for each answer.
+    pub timeout: Duration,
+    /// Queries repeated after the first one times out.
+    pub retries: u32,
+    /// Repeat truncated answers over TCP.
+    pub tcp_fallback: bool,
+    /// Ask the system resolver (getaddrinfo) when the nameservers fail.
+    pub system_fallback: bool,
+    /// Ask a DNS-over-HTTPS service instead of the nameservers.
+    pub doh: Option<DohConfig>,
+}
+
+/// A DNS-over-HTTPS service, RFC 8484.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DohConfig {
+    pub url: Uri,
+    /// Address of the service, so that its name does not need a lookup.
+    pub bootstrap: Option<IpAddr>,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        ResolverConfig {
+            nameservers: Vec::new(),
+            timeout: Duration::from_secs(5),
+            retries: 1,
+            tcp_fallback: true,
+            system_fallback: true,
+            doh: None,
+        }
+    }
+}
+
+/// `192.0.2.53`, `192.0.2.53:5353`, `2001:db8::53` or `[2001:db8::53]:5353`.
+pub fn parse_nameserver(value: &str) -> Option<SocketAddr> {
+    value
+        .parse::<SocketAddr>()
+        .ok()
+        .or_else(|| Some(SocketAddr::new(value.parse::<IpAddr>().ok()?, 53)))
+}
+
+/// An `https://` URL with a host, such as `https://cloudflare-dns.com/dns-query`.
+pub fn parse_doh_url(value: &str) -> Option<Uri> {
+    let url = value.parse::<Uri>().ok()?;
+    if url.scheme_str() != Some("https") || url.host().map_or(true, str::is_empty) {
+        return None;
+    }
+    Some(url)
 }
 
 pub struct Config {
-    pub addresses: Arc<StdMutex<Option<Vec<IpAddr>>>>,
-    pub remote: Arc<StdMutex<Option<(String, u16)>>>,
+    pub addresses: Arc<StdMutex<Option<Vec<Candidate>>>>,
+    pub remotes: Arc<StdMutex<Option<Vec<Endpoint>>>>,
+    pub remote_random: Arc<StdMutex<bool>>,
+    /// The profile has `remote-random-hostname`.
+    pub remote_random_hostname: Arc<StdMutex<bool>>,
+    pub random_hostname: Arc<StdMutex<RandomHostname>>,
+    pub dns_backend: Arc<StdMutex<DnsBackend>>,
+    /// Split-DNS rules of the profile being connected.
+    pub split_dns: Arc<StdMutex<SplitDns>>,
+    /// Route policy of the profile being connected.
+    pub routes: Arc<StdMutex<RoutePolicy>>,
+    pub transport: Arc<StdMutex<TransportOverride>>,
+    pub saml_listen: Arc<StdMutex<SamlListen>>,
+    pub reconnect: Arc<StdMutex<ReconnectPolicy>>,
+    pub session: Arc<StdMutex<SessionPolicy>>,
+    /// OpenVPN executable chosen by the user, searched for when unset.
+    pub openvpn: Arc<StdMutex<Option<PathBuf>>>,
+    pub privilege: Arc<StdMutex<PrivilegeHelper>>,
+    /// OpenVPN `--verb` level.
+    pub verbosity: Arc<StdMutex<u8>>,
+    pub family: Arc<StdMutex<AddressFamily>>,
+    pub resolver: Arc<StdMutex<ResolverConfig>>,
+    /// Endpoint addresses found with the current lookup settings.
+    pub dns_cache: Arc<DnsCache>,
+    /// Why the last lookup found no addresses at all.
+    pub resolve_error: Arc<StdMutex<Option<ResolveError>>>,
     pub config: Arc<StdMutex<Option<PathBuf>>>,
     pub pwd: Arc<TokioMutex<Option<Pwd>>>,
 }
@@ -41,24 +576,52 @@ impl Config {
     pub fn new() -> Config {
         Config {
             addresses: Arc::new(StdMutex::new(None)),
-            remote: Arc::new(StdMutex::new(None)),
+            remotes: Arc::new(StdMutex::new(None)),
+            remote_random: Arc::new(StdMutex::new(false)),
+            remote_random_hostname: Arc::new(StdMutex::new(false)),
+            random_hostname: Arc::new(StdMutex::new(RandomHostname::default())),
+            dns_backend: Arc::new(StdMutex::new(DnsBackend::default())),
+            split_dns: Arc::new(StdMutex::new(SplitDns::default())),
+            routes: Arc::new(StdMutex::new(RoutePolicy::default())),
+            transport: Arc::new(StdMutex::new(TransportOverride::default())),
+            saml_listen: Arc::new(StdMutex::new(SamlListen::default())),
+            reconnect: Arc::new(StdMutex::new(ReconnectPolicy::default())),
+            session: Arc::new(StdMutex::new(SessionPolicy::default())),
+            openvpn: Arc::new(StdMutex::new(None)),
+            privilege: Arc::new(StdMutex::new(PrivilegeHelper::default())),
+            verbosity: Arc::new(StdMutex::new(DEFAULT_VERBOSITY)),
+            family: Arc::new(StdMutex::new(AddressFamily::default())),
+            resolver: Arc::new(StdMutex::new(ResolverConfig::default())),
+            dns_cache: Arc::new(DnsCache::default()),
+            resolve_error: Arc::new(StdMutex::new(None)),
             config: Arc::new(StdMutex::new(None)),
             pwd: Arc::new(TokioMutex::new(None)),
         }
     }
 
-    pub fn save_config<P: AsRef<Path>>(&self, file: P) {
+    /// Loads a profile and writes the sanitized copy OpenVPN is started with
+    /// to the data directory.
+    pub fn save_config<P: AsRef<Path>>(&self, file: P) -> Result<(), ConfigError> {
+        let dir = dirs::data_local_dir().map(|v| v.join("openaws-vpn-client"));
+        self.save_config_in(file, dir.as_deref())
+    }
+
+    /// Like `save_config`, with the sanitized copy written to `dir`, or next to
+    /// the working directory without one.
+    pub fn save_config_in<P: AsRef<Path>>(
+        &self,
+        file: P,
+        dir: Option<&Path>,
+    ) -> Result<(), ConfigError> {
         let path = file.as_ref();
-        let content = {
-            let mut file = File::open(path).unwrap();
-            let mut content = String::new();
-            file.read_to_string(&mut content).unwrap();
-            content
-        };
+        let content = std::fs::read_to_string(path)?;
+        let profile = OvpnProfile::parse(&content)?;
 
         let full_name = {
-            let file_name = path.file_stem().unwrap();
-            let extension = path.extension().unwrap();
+            let (file_name, extension) = match (path.file_stem(), path.extension()) {
+                (Some(f), Some(e)) => (f, e),
+                _ => return Err(ConfigError::InvalidPath(path.to_path_buf())),
+            };
             let mut str = OsString::new();
             str.push(file_name);
             str.push(OsStr::new("-oavc"));
@@ -67,56 +630,456 @@ impl Config {
             PathBuf::from(str)
         };
 
-        let remote = get_remote(&content);
-        let new_contents = content
-            .lines()
-            .filter(|l| !has_key(l.to_string()))
-            .map(|v| v.to_string())
-            .collect::<Vec<String>>()
-            .join("\n");
+        let remotes = get_remotes(&profile);
+        if remotes.is_empty() {
+            return Err(ConfigError::NoRemote);
+        }
+        let remote_random = profile.directive("remote-random").is_some();
+        let remote_random_hostname = profile.directive("remote-random-hostname").is_some();
+        let new_contents = sanitize(&profile).to_string();
 
-        let file_dir = dirs::data_local_dir()
-            .map(|v| v.join("openaws-vpn-client").join(full_name.clone()))
-            .unwrap_or(full_name);
+        let file_dir = dir.map(|v| v.join(full_name.clone())).unwrap_or(full_name);
 
         if file_dir.exists() {
-            remove_file(file_dir.clone()).unwrap();
+            remove_file(file_dir.clone())?;
         }
 
         if let Some(parent) = file_dir.parent() {
-            create_dir_all(parent).unwrap();
+            create_dir_all(parent)?;
         }
 
-        let mut file = File::create(file_dir.clone()).unwrap();
-        write!(file, "{}", new_contents).unwrap();
+        let mut file = File::create(file_dir.clone())?;
+        write!(file, "{}", new_contents)?;
         println!("Saved at {:?}", &file_dir);
-        println!("Remote {:?}", &remote);
+        for remote in &remotes {
+            println!("Remote {}:{}/{}", remote.host, remote.port, remote.proto);
+        }
         let mut config = self.config.lock().unwrap();
         *config = Some(file_dir);
-        let mut re = self.remote.lock().unwrap();
-        *re = Some(remote);
+        let mut re = self.remotes.lock().unwrap();
+        *re = Some(remotes);
+        let mut random = self.remote_random.lock().unwrap();
+        *random = remote_random;
+        *self.remote_random_hostname.lock().unwrap() = remote_random_hostname;
+        let mut addresses = self.addresses.lock().unwrap();
+        *addresses = None;
+        Ok(())
+    }
+
+    /// Resolved addresses in the order they should be tried. With `remote-random`
+    /// the order of the remotes is shuffled, addresses of one remote stay together.
+    /// The transport override is applied to every candidate.
+    pub fn candidates(&self) -> Vec<Candidate> {
+        let addresses = self.addresses.lock().unwrap().clone().unwrap_or_default();
+        let transport = *self.transport.lock().unwrap();
+
+        let ordered = if *self.remote_random.lock().unwrap() {
+            let mut remotes = self.remotes.lock().unwrap().clone().unwrap_or_default();
+            remotes.shuffle(&mut thread_rng());
+            remotes
+                .iter()
+                .flat_map(|r| addresses.iter().filter(move |c| &c.endpoint == r))
+                .cloned()
+                .collect()
+        } else {
+            addresses
+        };
+
+        ordered
+            .into_iter()
+            .map(|mut c| {
+                if let Some(proto) = transport.proto {
+                    c.endpoint.proto = proto;
+                }
+                if let Some(port) = transport.port {
+                    c.endpoint.port = port;
+                }
+                c
+            })
+            .collect()
+    }
+
+    pub fn set_transport(&self, transport: TransportOverride) {
+        let mut t = self.transport.lock().unwrap();
+        *t = transport;
+    }
+
+    pub fn saml_listen(&self) -> SamlListen {
+        self.saml_listen.lock().unwrap().clone()
+    }
+
+    pub fn set_saml_listen(&self, listen: SamlListen) {
+        let mut l = self.saml_listen.lock().unwrap();
+        *l = listen;
+    }
+
+    pub fn reconnect_policy(&self) -> ReconnectPolicy {
+        self.reconnect.lock().unwrap().clone()
+    }
+
+    pub fn set_reconnect_policy(&self, policy: ReconnectPolicy) {
+        let mut p = self.reconnect.lock().unwrap();
+        *p = policy;
+    }
+
+    pub fn session_policy(&self) -> SessionPolicy {
+        self.session.lock().unwrap().clone()
+    }
+
+    pub fn set_session_policy(&self, policy: SessionPolicy) {
+        let mut p = self.session.lock().unwrap();
+        *p = policy;
+    }
+
+    pub fn openvpn_path(&self) -> Option<PathBuf> {
+        self.openvpn.lock().unwrap().clone()
+    }
+
+    pub fn set_openvpn_path(&self, path: Option<PathBuf>) {
+        let mut p = self.openvpn.lock().unwrap();
+        *p = path;
+    }
+
+    pub fn privilege_helper(&self) -> PrivilegeHelper {
+        *self.privilege.lock().unwrap()
+    }
+
+    pub fn set_privilege_helper(&self, helper: PrivilegeHelper) {
+        let mut h = self.privilege.lock().unwrap();
+        *h = helper;
+    }
+
+    pub fn verbosity(&self) -> u8 {
+        *self.verbosity.lock().unwrap()
+    }
+
+    pub fn set_verbosity(&self, verbosity: u8) {
+        let mut v = self.verbosity.lock().unwrap();
+        *v = verbosity.min(MAX_VERBOSITY);
+    }
+
+    pub fn address_family(&self) -> AddressFamily {
+        *self.family.lock().unwrap()
+    }
+
+    pub fn set_address_family(&self, family: AddressFamily) {
+        let mut f = self.family.lock().unwrap();
+        *f = family;
+    }
+
+    pub fn set_random_hostname(&self, random: RandomHostname) {
+        let mut r = self.random_hostname.lock().unwrap();
+        *r = random;
+        self.dns_cache.clear();
+    }
+
+    pub fn dns_backend(&self) -> DnsBackend {
+        *self.dns_backend.lock().unwrap()
     }
-}
 
-fn has_key(key: String) -> bool {
-    for k in CLEAN_KEYS.iter() {
-        if key.starts_with(k) {
-            return true;
+    pub fn set_dns_backend(&self, backend: DnsBackend) {
+        let mut b = self.dns_backend.lock().unwrap();
+        *b = backend;
+    }
+
+    pub fn split_dns(&self) -> SplitDns {
+        self.split_dns.lock().unwrap().clone()
+    }
+
+    pub fn set_split_dns(&self, split: SplitDns) {
+        let mut s = self.split_dns.lock().unwrap();
+        *s = split;
+    }
+
+    pub fn route_policy(&self) -> RoutePolicy {
+        self.routes.lock().unwrap().clone()
+    }
+
+    pub fn set_route_policy(&self, policy: RoutePolicy) {
+        let mut r = self.routes.lock().unwrap();
+        *r = policy;
+    }
+
+    /// Whether the endpoints of the loaded profile get a random subdomain.
+    pub fn use_random_hostname(&self) -> bool {
+        match *self.random_hostname.lock().unwrap() {
+            RandomHostname::Always => true,
+            RandomHostname::Never => false,
+            RandomHostname::Profile => *self.remote_random_hostname.lock().unwrap(),
         }
     }
 
-    return false;
+    pub fn resolver(&self) -> ResolverConfig {
+        self.resolver.lock().unwrap().clone()
+    }
+
+    pub fn set_resolver(&self, resolver: ResolverConfig) {
+        let mut r = self.resolver.lock().unwrap();
+        *r = resolver;
+        self.dns_cache.clear();
+    }
+
+    pub fn resolve_error(&self) -> Option<ResolveError> {
+        self.resolve_error.lock().unwrap().clone()
+    }
 }
 
-fn get_remote(content: &String) -> (String, u16) {
-    return content
-        .lines()
-        .filter(|p| p.starts_with("remote "))
-        .map(|p| {
-            let addr = (&p["remote ".len()..p.rfind(" ").unwrap()]).to_string();
-            let port = (&p[p.rfind(" ").unwrap() + 1..]).parse::<u16>().unwrap();
-            (addr, port)
-        })
-        .next()
-        .unwrap();
+/// Removes the directives this client passes on the command line itself.
+fn sanitize(profile: &OvpnProfile) -> OvpnProfile {
+    profile.without(|d| {
+        CLEAN_KEYS.contains(&d.name.as_str())
+            || (d.name == "auth-retry" && d.arg(0) == Some("interact"))
+    })
+}
+
+fn get_remotes(profile: &OvpnProfile) -> Vec<Endpoint> {
+    // `rport` only sets the remote port and wins over `port`
+    let default_port = profile
+        .directive("rport")
+        .or_else(|| profile.directive("port"))
+        .and_then(|d| d.arg(0))
+        .and_then(|p| p.parse::<u16>().ok())
+        .unwrap_or(DEFAULT_PORT);
+    let default_proto = profile
+        .directive("proto")
+        .and_then(|d| d.arg(0))
+        .and_then(Proto::parse)
+        .unwrap_or(DEFAULT_PROTO);
+
+    let mut remotes: Vec<Endpoint> = Vec::new();
+    for r in profile.remotes() {
+        let endpoint = Endpoint {
+            host: r.host,
+            port: r.port.unwrap_or(default_port),
+            proto: r.proto.unwrap_or(default_proto),
+        };
+        if !remotes.contains(&endpoint) {
+            remotes.push(endpoint);
+        }
+    }
+    remotes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn endpoints(content: &str) -> Vec<Endpoint> {
+        get_remotes(&OvpnProfile::parse(content).unwrap())
+    }
+
+    #[test]
+    fn remote_suffix_wins_over_proto_and_port() {
+        let remotes =
+            endpoints("proto udp\nport 1194\nremote a.example.com 443 tcp\nremote b.example.com\n");
+
+        assert_eq!(
+            remotes,
+            vec![
+                Endpoint {
+                    host: "a.example.com".to_string(),
+                    port: 443,
+                    proto: Proto::Tcp,
+                },
+                Endpoint {
+                    host: "b.example.com".to_string(),
+                    port: 1194,
+                    proto: Proto::Udp,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn defaults_when_profile_is_silent() {
+        let remotes = endpoints("remote a.example.com\n");
+        assert_eq!(remotes[0].port, DEFAULT_PORT);
+        assert_eq!(remotes[0].proto, DEFAULT_PROTO);
+
+        let remotes = endpoints("proto tcp-client\nport 1194\nrport 443\nremote a.example.com\n");
+        assert_eq!(remotes[0].port, 443);
+        assert_eq!(remotes[0].proto, Proto::Tcp);
+    }
+
+    #[test]
+    fn repeated_remotes_are_tried_once() {
+        let remotes = endpoints(
+            "remote a.example.com 443\nremote b.example.com 443\n\
+             remote a.example.com 443\nremote a.example.com 1194\n",
+        );
+        let remotes: Vec<_> = remotes.iter().map(|r| (r.host.as_str(), r.port)).collect();
+        assert_eq!(
+            remotes,
+            [
+                ("a.example.com", 443),
+                ("b.example.com", 443),
+                ("a.example.com", 1194)
+            ]
+        );
+    }
+
+    /// A config with three remotes, two addresses each, resolved in profile order.
+    fn resolved(remote_random: bool) -> Config {
+        let config = Config::new();
+        let remotes =
+            endpoints("remote a.example.com\nremote b.example.com\nremote c.example.com\n");
+        let addresses = remotes
+            .iter()
+            .enumerate()
+            .flat_map(|(i, endpoint)| {
+                (1..=2).map(move |n| Candidate {
+                    endpoint: endpoint.clone(),
+                    addr: IpAddr::V4(Ipv4Addr::new(192, 0, 2, (i * 10 + n) as u8)),
+                })
+            })
+            .collect();
+        *config.remotes.lock().unwrap() = Some(remotes);
+        *config.addresses.lock().unwrap() = Some(addresses);
+        *config.remote_random.lock().unwrap() = remote_random;
+        config
+    }
+
+    fn order(candidates: &[Candidate]) -> Vec<String> {
+        candidates.iter().map(|c| c.addr.to_string()).collect()
+    }
+
+    #[test]
+    fn candidates_follow_the_profile_order() {
+        assert_eq!(
+            order(&resolved(false).candidates()),
+            [
+                "192.0.2.1",
+                "192.0.2.2",
+                "192.0.2.11",
+                "192.0.2.12",
+                "192.0.2.21",
+                "192.0.2.22"
+            ]
+        );
+    }
+
+    #[test]
+    fn remote_random_shuffles_remotes_but_not_their_addresses() {
+        let config = resolved(true);
+        let mut orders = HashSet::new();
+        for _ in 0..50 {
+            let candidates = config.candidates();
+            assert_eq!(candidates.len(), 6);
+            for pair in candidates.chunks(2) {
+                assert_eq!(pair[0].endpoint, pair[1].endpoint);
+                let first = match pair[0].addr {
+                    IpAddr::V4(v4) => v4.octets()[3],
+                    IpAddr::V6(_) => unreachable!(),
+                };
+                // Addresses of one remote keep their resolved order
+                assert_eq!(first % 10, 1);
+            }
+            orders.insert(
+                candidates
+                    .iter()
+                    .map(|c| c.endpoint.host.clone())
+                    .collect::<Vec<_>>(),
+            );
+        }
+        assert!(orders.len() > 1, "remote-random never changed the order");
+    }
+
+    #[test]
+    fn transport_override_applies_to_candidates() {
+        let config = Config::new();
+        *config.addresses.lock().unwrap() = Some(vec![Candidate {
+            endpoint: Endpoint {
+                host: "a.example.com".to_string(),
+                port: 1194,
+                proto: Proto::Udp,
+            },
+            addr: "192.0.2.1".parse().unwrap(),
+        }]);
+        config.set_transport(TransportOverride {
+            proto: Some(Proto::Tcp),
+            port: Some(443),
+        });
+
+        let candidates = config.candidates();
+        assert_eq!(candidates[0].endpoint.proto, Proto::Tcp);
+        assert_eq!(candidates[0].endpoint.port, 443);
+    }
+
+    #[test]
+    fn random_hostname_follows_the_profile() {
+        let dir = temp_dir::TempDir::new().unwrap();
+        let file = dir.child("aws.ovpn");
+        std::fs::write(&file, "remote a.example.com 443\nremote-random-hostname\n").unwrap();
+        let config = Config::new();
+        assert!(!config.use_random_hostname());
+
+        config.save_config_in(&file, Some(dir.path())).unwrap();
+        assert!(config.use_random_hostname());
+        assert_eq!(
+            config.config.lock().unwrap().as_deref(),
+            Some(dir.child("aws-oavc.ovpn").as_path())
+        );
+        config.set_random_hostname(RandomHostname::Never);
+        assert!(!config.use_random_hostname());
+        assert_eq!(
+            RandomHostname::parse("always"),
+            Some(RandomHostname::Always)
+        );
+    }
+
+    #[test]
+    fn reconnect_delay_backs_off_with_jitter() {
+        let policy = ReconnectPolicy {
+            max_attempts: 10,
+            initial_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(30),
+        };
+
+        for (attempt, full) in [(1, 2), (2, 4), (3, 8), (4, 16), (5, 30), (40, 30)] {
+            let full = Duration::from_secs(full);
+            let delay = policy.delay(attempt);
+            assert!(delay >= full / 2 && delay <= full, "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn split_dns_covers_subdomains() {
+        assert_eq!(
+            parse_split_domain("*.Internal.Example.com."),
+            Some("internal.example.com".to_string())
+        );
+        assert_eq!(parse_split_domain("corp..example.com"), None);
+        assert_eq!(parse_split_domain("-corp.example.com"), None);
+        assert_eq!(parse_split_domain("*."), None);
+
+        let split = SplitDns::new(vec!["internal.example.com".to_string()]);
+        assert!(split.matches("internal.example.com"));
+        assert!(split.matches("git.eu.Internal.Example.com."));
+        assert!(!split.matches("notinternal.example.com"));
+        assert!(!split.matches("example.com"));
+    }
+
+    #[test]
+    fn parses_networks() {
+        let net = Cidr::parse("10.1.2.3/16").unwrap();
+        assert_eq!(net.to_string(), "10.1.0.0/16");
+        assert_eq!(net.netmask(), Some(Ipv4Addr::new(255, 255, 0, 0)));
+        assert_eq!(
+            Cidr::parse("0.0.0.0/0").unwrap().netmask(),
+            Some(Ipv4Addr::UNSPECIFIED)
+        );
+        assert_eq!(
+            Cidr::parse("192.0.2.7").unwrap().to_string(),
+            "192.0.2.7/32"
+        );
+
+        let v6 = Cidr::parse("2001:db8:1::5/48").unwrap();
+        assert_eq!(v6.to_string(), "2001:db8:1::/48");
+        assert_eq!(v6.netmask(), None);
+
+        assert_eq!(Cidr::parse("10.0.0.0/33"), None);
+        assert_eq!(Cidr::parse("10.0.0/8"), None);
+        assert_eq!(Cidr::parse("corp.example.com/24"), None);
+    }
 }
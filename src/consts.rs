@@ -1,3 +1,7 @@
 pub const DISCONNECTED: &str = "Disconnected";
 pub const CONNECTED: &str = "Connected";
 pub const CONNECTING: &str = "Connecting";
+pub const AWAITING_BROWSER: &str = "Waiting for browser login";
+pub const AUTHENTICATING: &str = "Authenticating";
+pub const RECONNECTING: &str = "Reconnecting";
+pub const FAILED: &str = "Failed";
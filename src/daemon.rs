@@ -0,0 +1,534 @@
+use crate::app::VpnApp;
+use crate::config::{parse_split_domain, Cidr, RoutePolicy, TransportOverride};
+use crate::host_dns;
+use crate::ovpn::Proto;
+use crate::saml::format_time;
+use crate::state_manager::{State, Transition};
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+/// How long a `connect` request waits for the tunnel to come up.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How long the daemon waits for a client to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reply lines starting with this prefix report a failure.
+const ERROR_PREFIX: &str = "ERROR ";
+
+/// A command sent over the control socket, one tab-separated line per connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Request {
+    Connect {
+        config: PathBuf,
+        transport: TransportOverride,
+        /// Split-DNS domains of the profile.
+        split_dns: Vec<String>,
+        routes: RoutePolicy,
+    },
+    Disconnect,
+    Status,
+    Logs,
+    DnsCache,
+}
+
+impl Request {
+    pub fn parse(line: &str) -> Result<Request, String> {
+        let mut parts = line.trim_end_matches(['\r', '\n']).split('\t');
+        let request = match parts.next().unwrap_or("") {
+            "connect" => {
+                let config = parts.next().filter(|c| !c.is_empty());
+                let proto = parts.next().unwrap_or("-");
+                let port = parts.next().unwrap_or("0");
+                let split_dns = parse_list(parts.next(), "domain", parse_split_domain)?;
+                let routes = RoutePolicy {
+                    include: parse_list(parts.next(), "network", Cidr::parse)?,
+                    exclude: parse_list(parts.next(), "network", Cidr::parse)?,
+                    routes: parse_list(parts.next(), "network", Cidr::parse)?,
+                    no_pull: match parts.next().unwrap_or("pull") {
+                        "pull" => false,
+                        "nopull" => true,
+                        other => return Err(format!("invalid route mode '{}'", other)),
+                    },
+                };
+                routes.validate()?;
+                Request::Connect {
+                    config: PathBuf::from(config.ok_or("missing config file")?),
+                    transport: TransportOverride {
+                        proto: match proto {
+                            "-" => None,
+                            p => Some(Proto::parse(p).ok_or(format!("invalid proto '{}'", p))?),
+                        },
+                        port: match port.parse::<u16>() {
+                            Ok(0) => None,
+                            Ok(p) => Some(p),
+                            Err(_) => return Err(format!("invalid port '{}'", port)),
+                        },
+                    },
+                    split_dns,
+                    routes,
+                }
+            }
+            "disconnect" => Request::Disconnect,
+            "status" => Request::Status,
+            "logs" => Request::Logs,
+            "dns-cache" => Request::DnsCache,
+            other => return Err(format!("unknown request '{}'", other)),
+        };
+
+        Ok(request)
+    }
+
+    pub fn to_line(&self) -> String {
+        match self {
+            Request::Connect {
+                config,
+                transport,
+                split_dns,
+                routes,
+            } => format!(
+                "connect\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                config.display(),
+                transport
+                    .proto
+                    .map(|p| p.to_string())
+                    .unwrap_or_else(|| "-".to_string()),
+                transport.port.unwrap_or(0),
+                list_field(split_dns),
+                list_field(&routes.include),
+                list_field(&routes.exclude),
+                list_field(&routes.routes),
+                match routes.no_pull {
+                    true => "nopull",
+                    false => "pull",
+                }
+            ),
+            Request::Disconnect => "disconnect".to_string(),
+            Request::Status => "status".to_string(),
+            Request::Logs => "logs".to_string(),
+            Request::DnsCache => "dns-cache".to_string(),
+        }
+    }
+}
+
+/// Comma separated, `-` when empty.
+fn list_field<T: ToString>(items: &[T]) -> String {
+    match items.is_empty() {
+        true => "-".to_string(),
+        false => items
+            .iter()
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>()
+            .join(","),
+    }
+}
+
+fn parse_list<T>(
+    field: Option<&str>,
+    what: &str,
+    parse: fn(&str) -> Option<T>,
+) -> Result<Vec<T>, String> {
+    match field.unwrap_or("-") {
+        "-" => Ok(Vec::new()),
+        items => items
+            .split(',')
+            .map(|i| parse(i).ok_or(format!("invalid {} '{}'", what, i)))
+            .collect(),
+    }
+}
+
+enum Message {
+    Request(Request, UnboundedSender<String>),
+    Shutdown,
+}
+
+/// Location of the control socket, inside a directory only the current user can enter.
+pub fn socket_path() -> PathBuf {
+    dirs::runtime_dir()
+        .or_else(dirs::data_local_dir)
+        .unwrap_or_else(std::env::temp_dir)
+        .join("openaws-vpn-client")
+        .join("control.sock")
+}
+
+/// Sends `request` to the daemon and prints its reply as it arrives.
+/// Returns whether the daemon reported success.
+pub fn call(request: &Request) -> io::Result<bool> {
+    let mut stream = std::os::unix::net::UnixStream::connect(socket_path())?;
+    stream.write_all(request.to_line().as_bytes())?;
+    stream.write_all(b"\n")?;
+
+    let mut success = true;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        match line.strip_prefix(ERROR_PREFIX) {
+            Some(error) => {
+                eprintln!("{}", error);
+                success = false;
+            }
+            None => println!("{}", line),
+        }
+    }
+
+    Ok(success)
+}
+
+/// The control socket, bound before the application starts so that a second
+/// daemon fails early.
+pub struct Daemon {
+    path: PathBuf,
+    listener: std::os::unix::net::UnixListener,
+}
+
+impl Daemon {
+    pub fn bind() -> io::Result<Daemon> {
+        let path = socket_path();
+        let listener = bind(&path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Daemon { path, listener })
+    }
+
+    /// Serves the control socket until interrupted. Requests are handled on the
+    /// calling thread, which owns the application.
+    pub fn run(self, app: Arc<VpnApp>) -> io::Result<()> {
+        let Daemon { path, listener } = self;
+        let listener = {
+            let _guard = app.runtime.enter();
+            UnixListener::from_std(listener)?
+        };
+
+        let (tx, rx) = channel();
+        {
+            let tx = tx.clone();
+            ctrlc::set_handler(move || {
+                let _ = tx.send(Message::Shutdown);
+            })
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        }
+
+        app.log
+            .append(format!("Listening for commands on {}", path.display()));
+        app.runtime.spawn(serve(listener, tx));
+
+        for message in rx {
+            match message {
+                Message::Request(request, reply) => handle(&app, request, reply),
+                Message::Shutdown => break,
+            }
+        }
+
+        app.log.append("Shutting down...");
+        let settled = {
+            let manager = app.connection_manager.lock().unwrap();
+            manager.as_ref().map(|manager| {
+                manager.force_disconnect();
+                manager.dns_settled()
+            })
+        };
+        if let Some(settled) = settled {
+            let _ = app.runtime.block_on(settled);
+        }
+        let _ = std::fs::remove_file(&path);
+
+        Ok(())
+    }
+}
+
+fn bind(path: &Path) -> io::Result<std::os::unix::net::UnixListener> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+    }
+
+    if path.exists() {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "the daemon is already running",
+            ));
+        }
+        // Left behind by a daemon that did not shut down cleanly
+        std::fs::remove_file(path)?;
+    }
+
+    std::os::unix::net::UnixListener::bind(path)
+}
+
+async fn serve(listener: UnixListener, tx: Sender<Message>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(_) => continue,
+        };
+        tokio::spawn(answer(stream, tx.clone()));
+    }
+}
+
+async fn answer(stream: UnixStream, tx: Sender<Message>) {
+    let (read, mut write) = stream.into_split();
+    let mut line = String::new();
+    let mut reader = tokio::io::BufReader::new(read);
+
+    let (reply, mut lines) = unbounded_channel();
+    match tokio::time::timeout(REQUEST_TIMEOUT, reader.read_line(&mut line)).await {
+        Ok(Ok(_)) => match Request::parse(&line) {
+            Ok(request) => {
+                if tx.send(Message::Request(request, reply)).is_err() {
+                    return;
+                }
+            }
+            Err(e) => {
+                let _ = reply.send(format!("{}{}", ERROR_PREFIX, e));
+                drop(reply);
+            }
+        },
+        _ => return,
+    }
+
+    while let Some(line) = lines.recv().await {
+        if write.write_all(line.as_bytes()).await.is_err() || write.write_all(b"\n").await.is_err()
+        {
+            break;
+        }
+    }
+}
+
+fn handle(app: &Arc<VpnApp>, request: Request, reply: UnboundedSender<String>) {
+    let state = match app.state.lock().unwrap().as_ref() {
+        Some(state_manager) => state_manager.state(),
+        None => return,
+    };
+
+    match request {
+        Request::Connect {
+            config,
+            transport,
+            split_dns,
+            routes,
+        } => {
+            if !matches!(state, State::Disconnected | State::Failed(_)) {
+                let _ = reply.send(format!("{}Already {}", ERROR_PREFIX, state));
+                return;
+            }
+
+            app.config.set_transport(transport);
+            let mut split = app.config.split_dns();
+            split.domains = split_dns;
+            app.config.set_split_dns(split);
+            app.config.set_route_policy(routes);
+            if let Err(e) = app.config.save_config(&config) {
+                let _ = reply.send(format!(
+                    "{}Unable to load config {}: {}",
+                    ERROR_PREFIX,
+                    config.display(),
+                    e
+                ));
+                return;
+            }
+            let _ = reply.send(format!("Connecting using config: {}", config.display()));
+            app.dns.resolve_addresses();
+
+            let transitions = match app.state.lock().unwrap().as_ref() {
+                Some(state_manager) => state_manager.subscribe(),
+                None => return,
+            };
+
+            // The lock is released right away, background tasks need it to fail over
+            {
+                let manager = app.connection_manager.lock().unwrap();
+                if let Some(manager) = manager.as_ref() {
+                    manager.connect();
+                }
+            }
+
+            app.runtime.spawn(follow_connection(transitions, reply));
+        }
+        Request::Disconnect => {
+            if state == State::Disconnected {
+                let _ = reply.send("Not connected".to_string());
+                return;
+            }
+
+            let settled = {
+                let manager = app.connection_manager.lock().unwrap();
+                manager.as_ref().map(|manager| {
+                    manager.disconnect();
+                    manager.dns_settled()
+                })
+            };
+            // Answered once the DNS is restored, without holding up other requests
+            app.runtime.spawn(async move {
+                if let Some(settled) = settled {
+                    let _ = settled.await;
+                }
+                let _ = reply.send("Disconnected".to_string());
+            });
+        }
+        Request::Status => {
+            let _ = reply.send(format!("Status: {}", state));
+
+            let endpoint = {
+                let manager = app.connection_manager.lock().unwrap();
+                manager.as_ref().and_then(|m| m.current_endpoint())
+            };
+            if let Some(endpoint) = endpoint {
+                let _ = reply.send(format!("Endpoint: {}", endpoint));
+            }
+
+            let diagnosis = {
+                let manager = app.connection_manager.lock().unwrap();
+                manager.as_ref().and_then(|m| m.diagnosis())
+            };
+            if let Some(diagnosis) = diagnosis {
+                let _ = reply.send(format!("Diagnosis: {}", diagnosis));
+            }
+
+            let session = {
+                let manager = app.connection_manager.lock().unwrap();
+                manager.as_ref().and_then(|m| m.session())
+            };
+            if let Some(session) = session {
+                let _ = reply.send(format!("Identity: {}", session.identity()));
+                if let Some(expiry) = session.session_expiry() {
+                    let _ = reply.send(format!("Login expires: {}", format_time(expiry)));
+                }
+            }
+
+            let split = app.config.split_dns();
+            if !split.is_empty() {
+                let _ = reply.send(format!("Split DNS: {}", split.domains.join(", ")));
+            }
+            if let Some(applied) = host_dns::applied() {
+                let _ = reply.send(format!("DNS: {}", applied));
+            }
+
+            let end = {
+                let manager = app.connection_manager.lock().unwrap();
+                manager.as_ref().and_then(|m| m.session_end())
+            };
+            if let Some(end) = end {
+                let _ = reply.send(format!("Session ends: {}", end));
+            }
+
+            if let Some(state_manager) = app.state.lock().unwrap().as_ref() {
+                for transition in state_manager.history() {
+                    let _ = reply.send(format!("  {}", transition));
+                }
+            }
+        }
+        Request::Logs => app.log.get_all_logs(Box::new(move |lines: &[String]| {
+            for line in lines {
+                let _ = reply.send(line.clone());
+            }
+        })),
+        Request::DnsCache => {
+            for line in app.dns.cache.report() {
+                let _ = reply.send(line);
+            }
+        }
+    }
+}
+
+/// Reports the progress of a connection attempt until it settles.
+async fn follow_connection(
+    mut transitions: broadcast::Receiver<Transition>,
+    reply: UnboundedSender<String>,
+) {
+    let outcome = tokio::time::timeout(CONNECT_TIMEOUT, async {
+        loop {
+            let state = match transitions.recv().await {
+                Ok(transition) => transition.to,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return "Failed to connect to VPN".to_string(),
+            };
+
+            match state {
+                State::Connected => return "Successfully connected to VPN".to_string(),
+                State::Failed(reason) => {
+                    return format!("{}Failed to connect to VPN: {}", ERROR_PREFIX, reason)
+                }
+                State::Disconnected => return format!("{}Failed to connect to VPN", ERROR_PREFIX),
+                state => {
+                    let _ = reply.send(format!("Status: {}", state));
+                }
+            }
+        }
+    })
+    .await;
+
+    let _ = reply.send(outcome.unwrap_or_else(|_| {
+        format!(
+            "{}Connection timed out, it continues in the background",
+            ERROR_PREFIX
+        )
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_round_trip() {
+        let requests = [
+            Request::Connect {
+                config: PathBuf::from("/home/user/my vpn.ovpn"),
+                transport: TransportOverride::default(),
+                split_dns: Vec::new(),
+                routes: RoutePolicy::default(),
+            },
+            Request::Connect {
+                config: PathBuf::from("/tmp/prod.ovpn"),
+                transport: TransportOverride {
+                    proto: Some(Proto::Tcp),
+                    port: Some(443),
+                },
+                split_dns: vec![
+                    "internal.example.com".to_string(),
+                    "corp.example.com".to_string(),
+                ],
+                routes: RoutePolicy {
+                    include: vec![Cidr::parse("10.0.0.0/16").unwrap()],
+                    exclude: vec![Cidr::parse("10.0.5.0/24").unwrap()],
+                    no_pull: true,
+                    routes: vec![
+                        Cidr::parse("172.16.0.0/12").unwrap(),
+                        Cidr::parse("2001:db8::/32").unwrap(),
+                    ],
+                },
+            },
+            Request::Disconnect,
+            Request::Status,
+            Request::Logs,
+            Request::DnsCache,
+        ];
+
+        for request in requests {
+            assert_eq!(Request::parse(&request.to_line()), Ok(request));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        assert!(Request::parse("reboot").is_err());
+        assert!(Request::parse("connect").is_err());
+        assert!(Request::parse("connect\t/tmp/a.ovpn\tsctp\t0").is_err());
+        assert!(Request::parse("connect\t/tmp/a.ovpn\t-\t70000").is_err());
+        assert!(Request::parse("connect\t/tmp/a.ovpn\t-\t0\tcorp..example.com").is_err());
+        assert!(Request::parse("connect\t/tmp/a.ovpn\t-\t0\t-\t10.0.0.0/33").is_err());
+        assert_eq!(
+            Request::parse("connect\t/tmp/a.ovpn\t-\t0\t-\t-\t2001:db8::/32"),
+            Err("'2001:db8::/32' is not an IPv4 network, only those can be excluded".to_string())
+        );
+    }
+}
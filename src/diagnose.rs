@@ -0,0 +1,281 @@
+use crate::error::ConnectError;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Mutex;
+
+/// Lines of OpenVPN output kept to explain why it exited.
+const TAIL_SIZE: usize = 200;
+
+/// A known reason for OpenVPN to fail.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Problem {
+    TlsHandshake,
+    AuthFailed,
+    CertificateVerification,
+    TunPermission,
+    RouteConflict,
+    DnsFailure,
+    /// sudo/pkexec refused to run OpenVPN or the prompt was dismissed.
+    PrivilegeDenied,
+}
+
+/// Log patterns per problem. When several match, the first problem listed
+/// here is the cause: a failed certificate check also fails the handshake.
+const PATTERNS: &[(Problem, &[&str])] = &[
+    (
+        Problem::TunPermission,
+        &[
+            "Cannot open TUN/TAP dev",
+            "Cannot ioctl TUNSETIFF",
+            "Cannot allocate TUN/TAP dev dynamically",
+            "AF_SYS_CONTROL)): Operation not permitted",
+        ],
+    ),
+    (
+        Problem::RouteConflict,
+        &[
+            "route add command failed",
+            "RTNETLINK answers: File exists",
+            "writing to routing socket: File exists",
+        ],
+    ),
+    (Problem::AuthFailed, &["AUTH_FAILED"]),
+    (
+        Problem::CertificateVerification,
+        &[
+            "VERIFY ERROR",
+            "VERIFY X509NAME ERROR",
+            "VERIFY KU ERROR",
+            "VERIFY EKU ERROR",
+            "certificate verify failed",
+        ],
+    ),
+    (
+        Problem::DnsFailure,
+        &[
+            "RESOLVE: Cannot resolve host address",
+            "Could not determine IPv4/IPv6 protocol",
+            "Temporary failure in name resolution",
+        ],
+    ),
+    (
+        Problem::TlsHandshake,
+        &[
+            "TLS Error: TLS handshake failed",
+            "TLS key negotiation failed",
+            "TLS_ERROR",
+            "tls_read_plaintext error",
+        ],
+    ),
+];
+
+/// Exit codes of sudo/pkexec when OpenVPN was not started at all.
+const PRIVILEGE_EXIT_CODES: &[i32] = &[126, 127];
+
+impl Problem {
+    pub fn explanation(&self) -> &'static str {
+        match self {
+            Problem::TlsHandshake => "The TLS handshake with the VPN server did not complete.",
+            Problem::AuthFailed => "The VPN server rejected the login.",
+            Problem::CertificateVerification => {
+                "The certificate of the VPN server could not be verified."
+            }
+            Problem::TunPermission => "OpenVPN was not allowed to create the tunnel device.",
+            Problem::RouteConflict => {
+                "A route pushed by the VPN server conflicts with an existing route."
+            }
+            Problem::DnsFailure => "The address of the VPN server could not be resolved.",
+            Problem::PrivilegeDenied => "OpenVPN could not be started with administrator rights.",
+        }
+    }
+
+    pub fn fix(&self) -> &'static str {
+        match self {
+            Problem::TlsHandshake => {
+                "Check that the endpoint is reachable on the configured protocol and port, \
+                 and that no firewall or proxy intercepts the connection."
+            }
+            Problem::AuthFailed => {
+                "Log in again. If it keeps failing, ask your administrator whether your \
+                 account is authorized for this Client VPN endpoint."
+            }
+            Problem::CertificateVerification => {
+                "Download the profile again from the self-service portal, the `<ca>` \
+                 block of this one may be outdated."
+            }
+            Problem::TunPermission => {
+                "Make sure OpenVPN runs as root and the tun module is loaded \
+                 (`modprobe tun`)."
+            }
+            Problem::RouteConflict => {
+                "Disconnect other VPNs or remove the conflicting route, or ask your \
+                 administrator to change the route table of the endpoint."
+            }
+            Problem::DnsFailure => {
+                "Check your network connection and DNS settings, then try again."
+            }
+            Problem::PrivilegeDenied => {
+                "Accept the sudo/pkexec prompt, or allow your user to run OpenVPN as root."
+            }
+        }
+    }
+
+    /// The connection error this problem stands for.
+    pub fn error(&self, code: i32) -> ConnectError {
+        match self {
+            Problem::TlsHandshake | Problem::CertificateVerification => {
+                ConnectError::Tls(self.explanation().to_string())
+            }
+            Problem::AuthFailed => ConnectError::AuthRejected,
+            Problem::PrivilegeDenied => ConnectError::Spawn(self.explanation().to_string()),
+            _ => ConnectError::Exited(code),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnosis {
+    pub problem: Problem,
+    /// The output line that gave it away, if any.
+    pub evidence: Option<String>,
+}
+
+impl fmt::Display for Diagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.problem.explanation())?;
+        if let Some(evidence) = &self.evidence {
+            write!(f, " ({})", evidence)?;
+        }
+        write!(f, " Suggested fix: {}", self.problem.fix())
+    }
+}
+
+/// Explains why OpenVPN exited from its final output and exit code.
+pub fn diagnose<S: AsRef<str>>(code: i32, output: &[S]) -> Option<Diagnosis> {
+    for (problem, patterns) in PATTERNS {
+        let evidence = output
+            .iter()
+            .map(|l| l.as_ref())
+            .find(|l| patterns.iter().any(|p| l.contains(p)));
+        if let Some(line) = evidence {
+            return Some(Diagnosis {
+                problem: *problem,
+                evidence: Some(strip_timestamp(line).to_string()),
+            });
+        }
+    }
+
+    if PRIVILEGE_EXIT_CODES.contains(&code) {
+        return Some(Diagnosis {
+            problem: Problem::PrivilegeDenied,
+            evidence: None,
+        });
+    }
+
+    None
+}
+
+/// Drops the `2024-01-01 12:00:00 ` prefix OpenVPN puts on its output.
+fn strip_timestamp(line: &str) -> &str {
+    let line = line.trim();
+    let bytes = line.as_bytes();
+    let stamped = bytes.len() > 20
+        && bytes[4] == b'-'
+        && bytes[7] == b'-'
+        && bytes[13] == b':'
+        && bytes[16] == b':'
+        && bytes[19] == b' ';
+    if stamped {
+        &line[20..]
+    } else {
+        line
+    }
+}
+
+/// The last lines printed by an OpenVPN process.
+#[derive(Default)]
+pub struct Tail {
+    lines: Mutex<VecDeque<String>>,
+}
+
+impl Tail {
+    pub fn push(&self, line: &str) {
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() == TAIL_SIZE {
+            lines.pop_front();
+        }
+        lines.push_back(line.to_string());
+    }
+
+    pub fn diagnose(&self, code: i32) -> Option<Diagnosis> {
+        let mut lines = self.lines.lock().unwrap();
+        diagnose(code, lines.make_contiguous())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> Vec<String> {
+        let path = format!("{}/fixtures/openvpn/{}", env!("CARGO_MANIFEST_DIR"), name);
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    fn problem(name: &str, code: i32) -> Option<Problem> {
+        diagnose(code, &fixture(name)).map(|d| d.problem)
+    }
+
+    #[test]
+    fn classifies_captured_logs() {
+        assert_eq!(problem("tls_handshake.log", 1), Some(Problem::TlsHandshake));
+        assert_eq!(problem("auth_failed.log", 1), Some(Problem::AuthFailed));
+        assert_eq!(
+            problem("cert_verify.log", 1),
+            Some(Problem::CertificateVerification)
+        );
+        assert_eq!(
+            problem("tun_permission.log", 1),
+            Some(Problem::TunPermission)
+        );
+        assert_eq!(
+            problem("route_conflict.log", 1),
+            Some(Problem::RouteConflict)
+        );
+        assert_eq!(problem("dns_failure.log", 1), Some(Problem::DnsFailure));
+        assert_eq!(problem("clean_exit.log", 0), None);
+    }
+
+    #[test]
+    fn falls_back_to_the_exit_code() {
+        assert_eq!(
+            problem("clean_exit.log", 126),
+            Some(Problem::PrivilegeDenied)
+        );
+        assert_eq!(problem("clean_exit.log", 1), None);
+    }
+
+    #[test]
+    fn quotes_the_offending_line() {
+        let diagnosis = diagnose(1, &fixture("tun_permission.log")).unwrap();
+        assert_eq!(
+            diagnosis.evidence.as_deref(),
+            Some("ERROR: Cannot ioctl TUNSETIFF tun: Operation not permitted (errno=1)")
+        );
+        assert!(diagnosis.to_string().contains("Suggested fix: "));
+    }
+
+    #[test]
+    fn keeps_the_last_lines() {
+        let tail = Tail::default();
+        tail.push("2024-03-11 09:20:13 AUTH: Received control message: AUTH_FAILED");
+        for _ in 0..TAIL_SIZE {
+            tail.push("2024-03-11 09:20:14 SIGTERM[soft,auth-failure] received");
+        }
+        assert_eq!(tail.diagnose(1), None);
+    }
+}
@@ -1,74 +1,576 @@
-use crate::config::Config;
+use crate::config::{AddressFamily, Candidate, Config, DohConfig, ResolverConfig};
 use crate::log::Log;
-use domain::base::iana::Class;
-use domain::base::{Dname, Rtype};
-use domain::rdata::A;
+use domain::base::iana::{Class, Rcode};
+use domain::base::{Dname, Message, MessageBuilder, Rtype};
+use domain::rdata::{Aaaa, A};
+use domain::resolv::stub::conf::{ResolvConf, ServerConf, Transport};
+use domain::resolv::StubResolver;
+use hyper::body::Bytes;
+use hyper::header::{ACCEPT, CONTENT_TYPE, HOST};
+use hyper::{Body, StatusCode, Uri};
 use rand::prelude::*;
-use std::net::IpAddr;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::ops::Deref;
-use std::rc::Rc;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
 use tokio::runtime::Runtime;
+use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+
+/// getaddrinfo does not report TTLs, its answers are kept this long.
+const SYSTEM_TTL: Duration = Duration::from_secs(30);
+
+/// Upper bound for caching, whatever the records say.
+const MAX_TTL: Duration = Duration::from_secs(3600);
+
+/// Media type of DNS messages sent over HTTPS, RFC 8484.
+const DNS_MESSAGE: &str = "application/dns-message";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ResolveError {
+    /// The endpoint is not a valid host name.
+    InvalidName(String),
+    /// The name has no addresses of the wanted family.
+    NotFound(String),
+    /// No nameserver answered in time.
+    Timeout(String),
+    /// The nameservers could not be asked or refused to answer.
+    Failed { host: String, reason: String },
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResolveError::InvalidName(host) => write!(f, "'{}' is not a valid host name", host),
+            ResolveError::NotFound(host) => write!(f, "no addresses found for '{}'", host),
+            ResolveError::Timeout(host) => write!(f, "timed out looking up '{}'", host),
+            ResolveError::Failed { host, reason } => {
+                write!(f, "unable to look up '{}': {}", host, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+impl ResolveError {
+    /// Whether another resolver may do better, as opposed to an answer.
+    fn is_failure(&self) -> bool {
+        matches!(self, ResolveError::Timeout(_) | ResolveError::Failed { .. })
+    }
+}
+
+/// Addresses found for a name and how long they may be reused.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Lookup {
+    addrs: Vec<IpAddr>,
+    ttl: Duration,
+}
+
+struct CacheEntry {
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<(String, AddressFamily), CacheEntry>,
+    hits: u64,
+    misses: u64,
+}
+
+/// Addresses of the endpoints, kept until their records expire.
+#[derive(Default)]
+pub struct DnsCache {
+    state: Mutex<CacheState>,
+}
+
+impl DnsCache {
+    fn get(&self, host: &str, family: AddressFamily, now: Instant) -> Option<Vec<IpAddr>> {
+        let mut state = self.state.lock().unwrap();
+        let key = (host.to_string(), family);
+        let found = match state.entries.get(&key) {
+            Some(entry) if entry.expires > now => Some(entry.addrs.clone()),
+            Some(_) => {
+                state.entries.remove(&key);
+                None
+            }
+            None => None,
+        };
+        match found {
+            Some(_) => state.hits += 1,
+            None => state.misses += 1,
+        }
+        found
+    }
+
+    fn insert(&self, host: &str, family: AddressFamily, lookup: &Lookup, now: Instant) {
+        let ttl = lookup.ttl.min(MAX_TTL);
+        if ttl.is_zero() {
+            return;
+        }
+        self.state.lock().unwrap().entries.insert(
+            (host.to_string(), family),
+            CacheEntry {
+                addrs: lookup.addrs.clone(),
+                expires: now + ttl,
+            },
+        );
+    }
+
+    /// Forgets the entries, the statistics are kept.
+    pub fn clear(&self) {
+        self.state.lock().unwrap().entries.clear();
+    }
+
+    /// Statistics and live entries, one line each.
+    pub fn report(&self) -> Vec<String> {
+        let now = Instant::now();
+        let state = self.state.lock().unwrap();
+        let mut entries: Vec<_> = state
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.expires > now)
+            .collect();
+        entries.sort_by_key(|((host, family), _)| (host.clone(), family.to_string()));
+
+        let mut lines = vec![format!(
+            "Entries: {}, hits: {}, misses: {}",
+            entries.len(),
+            state.hits,
+            state.misses
+        )];
+        for ((host, family), entry) in entries {
+            let addrs: Vec<_> = entry.addrs.iter().map(|a| a.to_string()).collect();
+            lines.push(format!(
+                "  {} ({}): {}, expires in {}s",
+                host,
+                family,
+                addrs.join(", "),
+                (entry.expires - now).as_secs()
+            ));
+        }
+        lines
+    }
+}
 
 pub struct DnsResolver {
-    pub config: Rc<Config>,
+    pub config: Arc<Config>,
     pub log: Arc<Log>,
     pub runtime: Arc<Runtime>,
+    pub cache: Arc<DnsCache>,
 }
 
 impl DnsResolver {
-    pub fn new(config: Rc<Config>, log: Arc<Log>, runtime: Arc<Runtime>) -> Self {
+    pub fn new(config: Arc<Config>, log: Arc<Log>, runtime: Arc<Runtime>) -> Self {
+        let cache = config.dns_cache.clone();
         Self {
             config,
             log,
             runtime,
+            cache,
         }
     }
 
-    fn resolve_to_ip_list(&self, remote: String) -> Vec<IpAddr> {
+    fn resolve_to_ip_list(&self, remote: String) -> Result<Lookup, ResolveError> {
         self.log
             .append(format!("Looking up into '{}'...", remote).as_str());
 
-        let resolver = domain::resolv::StubResolver::new();
-        let d: domain::base::Dname<Vec<u8>> = Dname::from_str(&remote).unwrap();
-        let r = self
+        let resolver = self.config.resolver();
+        let family = self.config.address_family();
+        let found = self
             .runtime
-            .block_on(async { resolver.query((d, Rtype::A, Class::In)).await })
-            .unwrap();
+            .block_on(resolve(&resolver, &remote, family, &self.log))?;
+        for addr in &found.addrs {
+            self.log.append(format!("Resolved '{}'.", addr).as_str());
+        }
+        Ok(found)
+    }
 
-        let msg = r.into_message();
-        let ans = msg.answer().unwrap().limit_to::<A>();
-        let all = ans
-            .filter(|v| v.is_ok())
-            .map(|v| v.unwrap())
-            .map(|v| v.into_data())
-            .map(|v| v.addr())
-            .map(|v| IpAddr::V4(v))
-            .inspect(|v| self.log.append(format!("Resolved '{}'.", v).as_str()))
-            .collect::<Vec<_>>();
-        all
+    /// Looks the endpoint up, under a random subdomain first if wanted.
+    fn resolve_endpoint(&self, host: &str) -> Result<Lookup, ResolveError> {
+        if !self.config.use_random_hostname() {
+            return self.resolve_to_ip_list(host.to_string());
+        }
+
+        let random_start = rng_domain();
+        let remote_with_rng_domain = format!("{}.{}", random_start, host);
+        match self.resolve_to_ip_list(remote_with_rng_domain.clone()) {
+            Ok(found) => Ok(found),
+            Err(e) => {
+                self.log.append(format!(
+                    "Unable to resolve any addresses at '{}': {}",
+                    remote_with_rng_domain.as_str(),
+                    e
+                ));
+                self.log
+                    .append("Attempting to resolve without any randomized domain...");
+                self.resolve_to_ip_list(host.to_string())
+            }
+        }
     }
 
     pub fn resolve_addresses(&self) {
-        let remote = self.config.remote.lock().unwrap().deref().clone().unwrap();
+        let remotes = self
+            .config
+            .remotes
+            .lock()
+            .unwrap()
+            .deref()
+            .clone()
+            .unwrap_or_default();
 
-        let random_start = rng_domain();
-        let remote_with_rng_domain = format!("{}.{}", random_start, remote.0);
+        let mut candidates = Vec::new();
+        let mut error = None;
+        let family = self.config.address_family();
+        for endpoint in remotes {
+            let all = match self.cache.get(&endpoint.host, family, Instant::now()) {
+                Some(all) => {
+                    self.log
+                        .append(format!("Using cached addresses of '{}'", endpoint.host));
+                    Ok(all)
+                }
+                None => self.resolve_endpoint(&endpoint.host).map(|found| {
+                    self.cache
+                        .insert(&endpoint.host, family, &found, Instant::now());
+                    found.addrs
+                }),
+            };
 
-        let mut all = self.resolve_to_ip_list(remote_with_rng_domain.clone());
-        if all.is_empty() {
-            self.log.append(format!(
-                "Unable to resolve any addresses at '{}'.",
-                remote_with_rng_domain.as_str()
-            ));
-            self.log
-                .append("Attempting to resolve without any randomized domain...");
-            all = self.resolve_to_ip_list(remote.0);
-        };
+            match all {
+                Ok(all) => candidates.extend(all.into_iter().map(|addr| Candidate {
+                    endpoint: endpoint.clone(),
+                    addr,
+                })),
+                Err(e) => {
+                    self.log.append(format!("Error: {}", e));
+                    error = Some(e);
+                }
+            }
+        }
 
+        *self.config.resolve_error.lock().unwrap() = error.filter(|_| candidates.is_empty());
         let mut br = self.config.addresses.lock().unwrap();
-        *br = Some(all);
+        *br = Some(candidates);
+    }
+}
+
+/// Where queries are sent.
+enum Backend {
+    Stub(StubResolver),
+    Doh(DohClient),
+}
+
+fn backend(config: &ResolverConfig) -> Backend {
+    match &config.doh {
+        Some(doh) => Backend::Doh(DohClient::new(doh, config, web_roots())),
+        None => Backend::Stub(stub_resolver(config)),
+    }
+}
+
+/// Sends queries as HTTPS POST requests, RFC 8484, one connection each.
+struct DohClient {
+    url: Uri,
+    bootstrap: Option<IpAddr>,
+    tls: TlsConnector,
+    timeout: Duration,
+    attempts: u32,
+}
+
+impl DohClient {
+    fn new(doh: &DohConfig, config: &ResolverConfig, tls: Arc<ClientConfig>) -> Self {
+        DohClient {
+            url: doh.url.clone(),
+            bootstrap: doh.bootstrap,
+            tls: TlsConnector::from(tls),
+            timeout: config.timeout,
+            attempts: config.retries + 1,
+        }
+    }
+
+    async fn query(
+        &self,
+        name: &Dname<Vec<u8>>,
+        rtype: Rtype,
+    ) -> Result<Message<Bytes>, ResolveError> {
+        let host = name.to_string();
+        let mut request = MessageBuilder::new_vec();
+        // An ID of 0 keeps the answers cacheable by HTTP caches
+        request.header_mut().set_id(0);
+        request.header_mut().set_rd(true);
+        let mut request = request.question();
+        request
+            .push((name, rtype))
+            .map_err(|_| ResolveError::InvalidName(host.clone()))?;
+        let request = request.finish();
+
+        for _ in 0..self.attempts {
+            match tokio::time::timeout(self.timeout, self.exchange(request.clone())).await {
+                Ok(Ok(answer)) => {
+                    return Message::from_octets(answer).map_err(|_| ResolveError::Failed {
+                        host,
+                        reason: "malformed answer".to_string(),
+                    })
+                }
+                Ok(Err(e)) => {
+                    return Err(ResolveError::Failed {
+                        host,
+                        reason: format!("DoH service {}: {}", self.url, e),
+                    })
+                }
+                Err(_) => continue,
+            }
+        }
+        Err(ResolveError::Timeout(host))
+    }
+
+    async fn exchange(&self, request: Vec<u8>) -> io::Result<Bytes> {
+        let host = self.url.host().unwrap_or_default();
+        let port = self.url.port_u16().unwrap_or(443);
+        // IPv6 literals keep their brackets in URLs
+        let server = host.trim_start_matches('[').trim_end_matches(']');
+        let addrs: Vec<SocketAddr> = match self.bootstrap {
+            Some(addr) => vec![SocketAddr::new(addr, port)],
+            None => tokio::net::lookup_host((server, port)).await?.collect(),
+        };
+
+        let stream = TcpStream::connect(&addrs[..]).await?;
+        let server_name = ServerName::try_from(server)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let stream = self.tls.connect(server_name, stream).await?;
+        let (mut sender, connection) = hyper::client::conn::handshake(stream)
+            .await
+            .map_err(http_error)?;
+        tokio::spawn(connection);
+
+        let authority = self.url.authority().map_or(host, |a| a.as_str());
+        let path = self.url.path_and_query().map_or("/", |p| p.as_str());
+        let request = hyper::Request::post(path)
+            .header(HOST, authority)
+            .header(CONTENT_TYPE, DNS_MESSAGE)
+            .header(ACCEPT, DNS_MESSAGE)
+            .body(Body::from(request))
+            .map_err(http_error)?;
+        let response = sender.send_request(request).await.map_err(http_error)?;
+        if response.status() != StatusCode::OK {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("answered {}", response.status()),
+            ));
+        }
+        hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(http_error)
+    }
+}
+
+fn http_error<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+/// Trusts the Mozilla root certificates.
+fn web_roots() -> Arc<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    Arc::new(
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    )
+}
+
+/// Looks `host` up with the configured nameservers or DoH service, then with
+/// the system resolver if they fail to answer.
+async fn resolve(
+    config: &ResolverConfig,
+    host: &str,
+    family: AddressFamily,
+    log: &Log,
+) -> Result<Lookup, ResolveError> {
+    match lookup(&backend(config), host, family).await {
+        Err(e) if e.is_failure() && config.system_fallback => {
+            log.append(format!("{}, asking the system resolver", e));
+            system_lookup(host, family).await
+        }
+        result => result,
+    }
+}
+
+fn stub_resolver(config: &ResolverConfig) -> StubResolver {
+    let mut conf = if config.nameservers.is_empty() {
+        ResolvConf::default()
+    } else {
+        let mut conf = ResolvConf::new();
+        for addr in &config.nameservers {
+            conf.servers.push(ServerConf::new(*addr, Transport::Udp));
+            conf.servers.push(ServerConf::new(*addr, Transport::Tcp));
+        }
+        conf
+    };
+    if !config.tcp_fallback {
+        conf.servers.retain(|s| s.transport == Transport::Udp);
+    }
+    conf.options.ign_tc = !config.tcp_fallback;
+    conf.options.timeout = config.timeout;
+    conf.options.attempts = config.retries as usize + 1;
+    conf.finalize();
+    StubResolver::from_conf(conf)
+}
+
+/// Addresses of `host` in the order they should be tried.
+async fn lookup(
+    backend: &Backend,
+    host: &str,
+    family: AddressFamily,
+) -> Result<Lookup, ResolveError> {
+    let name: Dname<Vec<u8>> =
+        Dname::from_str(host).map_err(|_| ResolveError::InvalidName(host.to_string()))?;
+
+    let all = match family {
+        AddressFamily::Ipv4 => query(backend, &name, Rtype::A).await?,
+        AddressFamily::Ipv6 => query(backend, &name, Rtype::Aaaa).await?,
+        AddressFamily::HappyEyeballs => {
+            let (v6, v4) = tokio::join!(
+                query(backend, &name, Rtype::Aaaa),
+                query(backend, &name, Rtype::A)
+            );
+            match (v6, v4) {
+                (Err(e), Err(_)) => return Err(e),
+                (v6, v4) => {
+                    let (v6, v4) = (v6.unwrap_or_default(), v4.unwrap_or_default());
+                    // An empty answer says nothing about how long the other one holds
+                    let ttl = [&v6, &v4]
+                        .iter()
+                        .filter(|l| !l.addrs.is_empty())
+                        .map(|l| l.ttl)
+                        .min()
+                        .unwrap_or_default();
+                    Lookup {
+                        addrs: interleave(v6.addrs, v4.addrs),
+                        ttl,
+                    }
+                }
+            }
+        }
+    };
+
+    if all.addrs.is_empty() {
+        return Err(ResolveError::NotFound(host.to_string()));
+    }
+    Ok(all)
+}
+
+/// Addresses of one record type, valid as long as the shortest TTL.
+async fn query(
+    backend: &Backend,
+    name: &Dname<Vec<u8>>,
+    rtype: Rtype,
+) -> Result<Lookup, ResolveError> {
+    let host = name.to_string();
+    let answer = match backend {
+        Backend::Stub(resolver) => match resolver.query((name, rtype, Class::In)).await {
+            Ok(answer) => answer.into_message(),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                return Err(ResolveError::Timeout(host))
+            }
+            Err(e) => {
+                return Err(ResolveError::Failed {
+                    host,
+                    reason: e.to_string(),
+                })
+            }
+        },
+        Backend::Doh(client) => client.query(name, rtype).await?,
+    };
+    match answer.header().rcode() {
+        Rcode::NoError => {}
+        Rcode::NXDomain => return Err(ResolveError::NotFound(host)),
+        rcode => {
+            return Err(ResolveError::Failed {
+                host,
+                reason: format!("server answered {}", rcode),
+            })
+        }
+    }
+    let records = answer.answer().map_err(|e| ResolveError::Failed {
+        host: host.clone(),
+        reason: format!("malformed answer: {}", e),
+    })?;
+
+    let found: Vec<(IpAddr, u32)> = if rtype == Rtype::Aaaa {
+        records
+            .limit_to::<Aaaa>()
+            .filter_map(|r| r.ok())
+            // `::ffff:a.b.c.d` is an IPv4 address for OpenVPN
+            .map(|r| (IpAddr::V6(r.data().addr()).to_canonical(), r.ttl()))
+            .collect()
+    } else {
+        records
+            .limit_to::<A>()
+            .filter_map(|r| r.ok())
+            .map(|r| (IpAddr::V4(r.data().addr()), r.ttl()))
+            .collect()
+    };
+    let ttl = found.iter().map(|(_, ttl)| *ttl).min().unwrap_or(0);
+    Ok(Lookup {
+        addrs: found.into_iter().map(|(addr, _)| addr).collect(),
+        ttl: Duration::from_secs(ttl.into()),
+    })
+}
+
+/// getaddrinfo, through the blocking pool of tokio.
+async fn system_lookup(host: &str, family: AddressFamily) -> Result<Lookup, ResolveError> {
+    let found = tokio::net::lookup_host((host, 0))
+        .await
+        .map_err(|e| ResolveError::Failed {
+            host: host.to_string(),
+            reason: e.to_string(),
+        })?;
+
+    let (mut v6, mut v4) = (Vec::new(), Vec::new());
+    for addr in found.map(|a| a.ip().to_canonical()) {
+        let list = if addr.is_ipv6() { &mut v6 } else { &mut v4 };
+        if !list.contains(&addr) {
+            list.push(addr);
+        }
+    }
+    let all = match family {
+        AddressFamily::Ipv4 => v4,
+        AddressFamily::Ipv6 => v6,
+        AddressFamily::HappyEyeballs => interleave(v6, v4),
+    };
+
+    if all.is_empty() {
+        return Err(ResolveError::NotFound(host.to_string()));
+    }
+    Ok(Lookup {
+        addrs: all,
+        ttl: SYSTEM_TTL,
+    })
+}
+
+/// Alternates between both lists, starting with the first.
+fn interleave(first: Vec<IpAddr>, second: Vec<IpAddr>) -> Vec<IpAddr> {
+    let mut ordered = Vec::with_capacity(first.len() + second.len());
+    let mut first = first.into_iter();
+    let mut second = second.into_iter();
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
     }
 }
 
@@ -78,3 +580,323 @@ fn rng_domain() -> String {
     rng.fill_bytes(&mut bts);
     hex::encode(bts)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+    use hyper::server::conn::Http;
+    use hyper::service::service_fn;
+    use hyper::{Method, Response};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use tokio::net::{TcpListener, UdpSocket};
+    use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+    use tokio_rustls::TlsAcceptor;
+
+    const HOST: &str = "vpn.example.com";
+
+    /// The answer to an A or AAAA query for `HOST`, with TTLs of 60 and 30
+    /// seconds.
+    fn answer(request: &[u8], v4: &[Ipv4Addr], v6: &[Ipv6Addr]) -> Option<Vec<u8>> {
+        let request = Message::from_octets(request.to_vec()).ok()?;
+        let question = request.first_question()?;
+        let known = question.qname().to_string() == HOST;
+        let rcode = if known {
+            Rcode::NoError
+        } else {
+            Rcode::NXDomain
+        };
+        let mut answer = MessageBuilder::new_vec()
+            .start_answer(&request, rcode)
+            .unwrap();
+        let name = Dname::<Vec<u8>>::from_str(HOST).unwrap();
+        if known && question.qtype() == Rtype::A {
+            for a in v4 {
+                answer.push((&name, 60, A::new(*a))).unwrap();
+            }
+        }
+        if known && question.qtype() == Rtype::Aaaa {
+            for a in v6 {
+                answer.push((&name, 30, Aaaa::new(*a))).unwrap();
+            }
+        }
+        Some(answer.finish())
+    }
+
+    /// Answers queries over UDP until dropped.
+    async fn stub_server(v4: Vec<Ipv4Addr>, v6: Vec<Ipv6Addr>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                if let Some(answer) = answer(&buf[..len], &v4, &v6) {
+                    let _ = socket.send_to(&answer, peer).await;
+                }
+            }
+        });
+        addr
+    }
+
+    fn fixture(name: &str) -> Vec<u8> {
+        std::fs::read(format!(
+            "{}/fixtures/doh/{}",
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        ))
+        .unwrap()
+    }
+
+    /// Answers DoH POST requests until dropped, with a certificate for
+    /// `doh.test` and 127.0.0.1 issued by `fixtures/doh/ca.pem`.
+    async fn doh_server(v4: Vec<Ipv4Addr>, v6: Vec<Ipv6Addr>) -> SocketAddr {
+        let certs = rustls_pemfile::certs(&mut &fixture("server.pem")[..]).unwrap();
+        let key = rustls_pemfile::pkcs8_private_keys(&mut &fixture("server.key")[..]).unwrap();
+        let tls = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                certs.into_iter().map(Certificate).collect(),
+                PrivateKey(key[0].clone()),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(tls));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                let (v4, v6) = (v4.clone(), v6.clone());
+                tokio::spawn(async move {
+                    let stream = match acceptor.accept(stream).await {
+                        Ok(stream) => stream,
+                        Err(_) => return,
+                    };
+                    let service = service_fn(move |request: hyper::Request<Body>| {
+                        let (v4, v6) = (v4.clone(), v6.clone());
+                        async move {
+                            let is_query = request.method() == Method::POST
+                                && request.headers().get(CONTENT_TYPE)
+                                    == Some(&HeaderValue::from_static(DNS_MESSAGE));
+                            let body = hyper::body::to_bytes(request.into_body()).await?;
+                            let response = match answer(&body, &v4, &v6).filter(|_| is_query) {
+                                Some(answer) => Response::builder()
+                                    .header(CONTENT_TYPE, DNS_MESSAGE)
+                                    .body(Body::from(answer)),
+                                None => Response::builder()
+                                    .status(StatusCode::BAD_REQUEST)
+                                    .body(Body::empty()),
+                            };
+                            Ok::<_, hyper::Error>(response.unwrap())
+                        }
+                    });
+                    let _ = Http::new().serve_connection(stream, service).await;
+                });
+            }
+        });
+        addr
+    }
+
+    fn test_roots() -> Arc<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut &fixture("ca.pem")[..]).unwrap() {
+            roots.add(&Certificate(cert)).unwrap();
+        }
+        Arc::new(
+            ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        )
+    }
+
+    fn doh_client(url: &str, bootstrap: Option<IpAddr>, tls: Arc<ClientConfig>) -> Backend {
+        let doh = DohConfig {
+            url: url.parse().unwrap(),
+            bootstrap,
+        };
+        Backend::Doh(DohClient::new(&doh, &ResolverConfig::default(), tls))
+    }
+
+    fn resolver_config(server: SocketAddr) -> ResolverConfig {
+        ResolverConfig {
+            nameservers: vec![server],
+            timeout: Duration::from_millis(200),
+            retries: 0,
+            tcp_fallback: false,
+            system_fallback: false,
+            doh: None,
+        }
+    }
+
+    fn ips(addrs: &[&str]) -> Vec<IpAddr> {
+        addrs.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn resolves_by_family() {
+        let server = stub_server(
+            vec!["192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap()],
+            vec![
+                "2001:db8::1".parse().unwrap(),
+                "::ffff:192.0.2.3".parse().unwrap(),
+            ],
+        )
+        .await;
+        let resolver = backend(&resolver_config(server));
+
+        assert_eq!(
+            lookup(&resolver, HOST, AddressFamily::Ipv4).await,
+            Ok(Lookup {
+                addrs: ips(&["192.0.2.1", "192.0.2.2"]),
+                ttl: Duration::from_secs(60),
+            })
+        );
+        assert_eq!(
+            lookup(&resolver, HOST, AddressFamily::Ipv6).await,
+            Ok(Lookup {
+                addrs: ips(&["2001:db8::1", "192.0.2.3"]),
+                ttl: Duration::from_secs(30),
+            })
+        );
+        assert_eq!(
+            lookup(&resolver, HOST, AddressFamily::HappyEyeballs).await,
+            Ok(Lookup {
+                addrs: ips(&["2001:db8::1", "192.0.2.1", "192.0.2.3", "192.0.2.2"]),
+                ttl: Duration::from_secs(30),
+            })
+        );
+        assert_eq!(
+            lookup(&resolver, "other.example.com", AddressFamily::HappyEyeballs).await,
+            Err(ResolveError::NotFound("other.example.com".to_string()))
+        );
+        assert_eq!(
+            lookup(&resolver, "vpn..example.com", AddressFamily::Ipv4).await,
+            Err(ResolveError::InvalidName("vpn..example.com".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_system_resolver() {
+        // Never answers
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut config = resolver_config(silent.local_addr().unwrap());
+        let log = Log::new();
+
+        assert_eq!(
+            resolve(&config, "localhost", AddressFamily::Ipv4, &log).await,
+            Err(ResolveError::Timeout("localhost".to_string()))
+        );
+
+        config.system_fallback = true;
+        let found = resolve(&config, "localhost", AddressFamily::Ipv4, &log)
+            .await
+            .unwrap();
+        assert!(found.addrs.contains(&IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        assert_eq!(found.ttl, SYSTEM_TTL);
+    }
+
+    #[tokio::test]
+    async fn resolves_over_https() {
+        let server = doh_server(
+            vec!["192.0.2.1".parse().unwrap()],
+            vec!["2001:db8::1".parse().unwrap()],
+        )
+        .await;
+        let expected = Ok(Lookup {
+            addrs: ips(&["2001:db8::1", "192.0.2.1"]),
+            ttl: Duration::from_secs(30),
+        });
+
+        let url = format!("https://doh.test:{}/dns-query", server.port());
+        let bootstrapped = doh_client(&url, Some(server.ip()), test_roots());
+        assert_eq!(
+            lookup(&bootstrapped, HOST, AddressFamily::HappyEyeballs).await,
+            expected
+        );
+        assert_eq!(
+            lookup(&bootstrapped, "other.example.com", AddressFamily::Ipv4).await,
+            Err(ResolveError::NotFound("other.example.com".to_string()))
+        );
+
+        let url = format!("https://{}/dns-query", server);
+        let direct = doh_client(&url, None, test_roots());
+        assert_eq!(
+            lookup(&direct, HOST, AddressFamily::HappyEyeballs).await,
+            expected
+        );
+
+        // The stand-in is not trusted by the real roots
+        let untrusted = doh_client(&url, None, web_roots());
+        assert!(matches!(
+            lookup(&untrusted, HOST, AddressFamily::Ipv4).await,
+            Err(ResolveError::Failed { .. })
+        ));
+    }
+
+    #[test]
+    fn caches_until_the_ttl_expires() {
+        let cache = DnsCache::default();
+        let now = Instant::now();
+        let found = Lookup {
+            addrs: ips(&["192.0.2.1"]),
+            ttl: Duration::from_secs(60),
+        };
+
+        assert_eq!(cache.get(HOST, AddressFamily::Ipv4, now), None);
+        cache.insert(HOST, AddressFamily::Ipv4, &found, now);
+        assert_eq!(
+            cache.get(HOST, AddressFamily::Ipv4, now + Duration::from_secs(59)),
+            Some(found.addrs.clone())
+        );
+        assert_eq!(cache.get(HOST, AddressFamily::Ipv6, now), None);
+        assert_eq!(
+            cache.get(HOST, AddressFamily::Ipv4, now + Duration::from_secs(60)),
+            None
+        );
+
+        // A TTL of zero forbids caching
+        let uncached = Lookup {
+            ttl: Duration::ZERO,
+            ..found
+        };
+        cache.insert("other.example.com", AddressFamily::Ipv4, &uncached, now);
+        assert_eq!(
+            cache.get("other.example.com", AddressFamily::Ipv4, now),
+            None
+        );
+        assert_eq!(cache.report(), ["Entries: 0, hits: 1, misses: 4"]);
+    }
+
+    #[test]
+    fn lookup_settings_clear_the_cache() {
+        let config = Config::new();
+        let now = Instant::now();
+        let found = Lookup {
+            addrs: ips(&["192.0.2.1"]),
+            ttl: Duration::from_secs(60),
+        };
+
+        config
+            .dns_cache
+            .insert(HOST, AddressFamily::Ipv4, &found, now);
+        config.set_resolver(resolver_config("192.0.2.53:53".parse().unwrap()));
+        assert_eq!(config.dns_cache.get(HOST, AddressFamily::Ipv4, now), None);
+
+        config
+            .dns_cache
+            .insert(HOST, AddressFamily::Ipv4, &found, now);
+        config.set_random_hostname(crate::config::RandomHostname::Never);
+        assert_eq!(config.dns_cache.get(HOST, AddressFamily::Ipv4, now), None);
+    }
+
+    #[test]
+    fn interleaves_uneven_lists() {
+        assert_eq!(
+            interleave(ips(&["2001:db8::1"]), ips(&["192.0.2.1", "192.0.2.2"])),
+            ips(&["2001:db8::1", "192.0.2.1", "192.0.2.2"])
+        );
+        assert_eq!(interleave(Vec::new(), Vec::new()), Vec::<IpAddr>::new());
+    }
+}
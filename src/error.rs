@@ -0,0 +1,126 @@
+use crate::dns::ResolveError;
+use crate::openvpn::BinaryError;
+use std::fmt;
+
+/// Why a connection attempt failed. The codes are part of the FFI and must
+/// not change.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectError {
+    /// No profile was loaded before connecting.
+    NoProfile,
+    /// None of the remotes of the profile resolved.
+    NoAddresses,
+    /// Looking up the remotes failed.
+    Resolve(ResolveError),
+    /// No OpenVPN executable was found in the listed places.
+    MissingBinary(String),
+    /// The OpenVPN executable lacks the AWS SAML patch.
+    UnpatchedBinary(String),
+    /// OpenVPN or the privilege helper could not be started.
+    Spawn(String),
+    /// The management interface could not be set up or failed.
+    Management(String),
+    /// The server did not answer with a SAML login URL.
+    MissingSamlChallenge,
+    /// The SAML response posted by the browser was not usable.
+    SamlRejected(String),
+    /// The server refused the credentials.
+    AuthRejected,
+    /// The TLS handshake with the server failed.
+    Tls(String),
+    /// OpenVPN did not get back to us in time.
+    Timeout,
+    /// OpenVPN exited for another reason.
+    Exited(i32),
+    /// Every endpoint of the profile was tried, `attempts` describes each failure.
+    AllEndpointsFailed {
+        endpoints: usize,
+        attempts: Vec<String>,
+        last: Box<ConnectError>,
+    },
+    /// The tunnel dropped and could not be brought back.
+    ConnectionLost { attempts: u32 },
+}
+
+impl ConnectError {
+    pub fn code(&self) -> i32 {
+        match self {
+            ConnectError::NoProfile => 1,
+            ConnectError::NoAddresses => 2,
+            ConnectError::MissingBinary(_) => 3,
+            ConnectError::Spawn(_) => 4,
+            ConnectError::Management(_) => 5,
+            ConnectError::MissingSamlChallenge => 6,
+            ConnectError::SamlRejected(_) => 7,
+            ConnectError::AuthRejected => 8,
+            ConnectError::Tls(_) => 9,
+            ConnectError::Timeout => 10,
+            ConnectError::Exited(_) => 11,
+            ConnectError::AllEndpointsFailed { .. } => 12,
+            ConnectError::ConnectionLost { .. } => 13,
+            ConnectError::UnpatchedBinary(_) => 14,
+            ConnectError::Resolve(_) => 15,
+        }
+    }
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectError::NoProfile => write!(f, "no VPN profile loaded"),
+            ConnectError::NoAddresses => write!(f, "no addresses found for the VPN endpoint"),
+            ConnectError::Resolve(e) => write!(f, "DNS lookup failed: {}", e),
+            ConnectError::MissingBinary(searched) => {
+                write!(f, "no OpenVPN executable found, searched {}", searched)
+            }
+            ConnectError::UnpatchedBinary(e) => write!(f, "unusable OpenVPN: {}", e),
+            ConnectError::Spawn(e) => write!(f, "unable to start OpenVPN: {}", e),
+            ConnectError::Management(e) => write!(f, "OpenVPN management interface: {}", e),
+            ConnectError::MissingSamlChallenge => {
+                write!(f, "the VPN server did not send a SAML challenge")
+            }
+            ConnectError::SamlRejected(e) => write!(f, "SAML response rejected: {}", e),
+            ConnectError::AuthRejected => write!(f, "the VPN server rejected the login"),
+            ConnectError::Tls(e) => write!(f, "TLS error: {}", e),
+            ConnectError::Timeout => write!(f, "timed out waiting for OpenVPN"),
+            ConnectError::Exited(code) => write!(f, "OpenVPN exited with code {}", code),
+            ConnectError::AllEndpointsFailed {
+                endpoints,
+                attempts,
+                last,
+            } => {
+                write!(f, "all {} VPN endpoints failed", endpoints)?;
+                if attempts.is_empty() {
+                    return write!(f, ", last: {}", last);
+                }
+                for (i, attempt) in attempts.iter().enumerate() {
+                    write!(f, "; {}. {}", i + 1, attempt)?;
+                }
+                Ok(())
+            }
+            ConnectError::ConnectionLost { attempts: 0 } => write!(f, "connection lost"),
+            ConnectError::ConnectionLost { attempts } => write!(
+                f,
+                "connection lost, gave up after {} reconnect attempts",
+                attempts
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+impl From<BinaryError> for ConnectError {
+    fn from(e: BinaryError) -> Self {
+        match e {
+            BinaryError::NotFound(searched) => {
+                let searched: Vec<_> = searched.iter().map(|p| p.display().to_string()).collect();
+                ConnectError::MissingBinary(searched.join(", "))
+            }
+            BinaryError::Unpatched { .. } => ConnectError::UnpatchedBinary(e.to_string()),
+            BinaryError::Probe(..) | BinaryError::NotOpenVpn(_) => {
+                ConnectError::Spawn(e.to_string())
+            }
+        }
+    }
+}
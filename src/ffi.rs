@@ -1,25 +1,92 @@
-use crate::app::{State, VpnApp};
-use crate::cmd::{kill_openvpn, ProcessInfo};
+use crate::app::VpnApp;
+use crate::cmd::kill_openvpn;
+use crate::config::{
+    parse_doh_url, parse_nameserver, parse_split_domain, AddressFamily, Cidr, DnsBackend,
+    DohConfig, PrivilegeHelper, RandomHostname, ReconnectPolicy, ResolverConfig, RoutePolicy,
+    SamlListen, SessionPolicy, TransportOverride, MAX_VERBOSITY,
+};
+use crate::error::ConnectError;
+use crate::host_dns;
 use crate::local_config::LocalConfig;
-use crate::manager::ConnectionManager;
+use crate::manager::{restore_dns, ConnectionManager};
+use crate::openvpn::{discover, Source};
+use crate::ovpn::Proto;
+use crate::saml::Assertion;
 use crate::saml_server::SamlServer;
-use std::ffi::{c_char, CStr, CString};
+use crate::state_manager::State;
+use std::ffi::{c_char, c_void, CStr, CString};
 use std::path::PathBuf;
 use std::ptr;
-use std::rc::Rc;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
 
 // Global state for FFI
-static mut APP_INSTANCE: Option<Arc<Mutex<Rc<VpnApp>>>> = None;
+static mut APP_INSTANCE: Option<Arc<Mutex<Arc<VpnApp>>>> = None;
+static mut STATE_SUBSCRIPTION: Option<JoinHandle<()>> = None;
+
+/// Receives the new state code (as returned by `openaws_get_state`), a
+/// description valid only for the duration of the call, and the user data
+/// pointer given at registration.
+pub type StateCallback =
+    extern "C" fn(state: i32, description: *const c_char, user_data: *mut c_void);
+
+/// User data pointer handed back to the embedder, which owns its thread safety.
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+
+impl UserData {
+    fn get(&self) -> *mut c_void {
+        self.0
+    }
+}
 
 #[no_mangle]
 pub extern "C" fn openaws_init() -> bool {
-    initialize_app()
+    initialize_app(SamlListen::default())
 }
 
-fn initialize_app() -> bool {
-    let vpn_app = Rc::new(VpnApp::new());
+/// Like `openaws_init`, with the SAML callback server listening on `address`
+/// (null for the loopback addresses) and `port` (0 for the default).
+///
+/// # Safety
+///
+/// `address` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn openaws_init_with_saml_listen(address: *const c_char, port: i32) -> bool {
+    let mut listen = SamlListen::default();
+
+    if !address.is_null() {
+        match CStr::from_ptr(address)
+            .to_str()
+            .ok()
+            .and_then(|a| a.parse().ok())
+        {
+            Some(address) => listen.addresses = vec![address],
+            None => {
+                log_to_android("Error: invalid SAML listen address");
+                return false;
+            }
+        }
+    }
+
+    match port {
+        0 => {}
+        1..=65535 => listen.port = port as u16,
+        _ => {
+            log_to_android("Error: invalid SAML listen port");
+            return false;
+        }
+    }
+
+    initialize_app(listen)
+}
+
+fn initialize_app(saml_listen: SamlListen) -> bool {
+    let vpn_app = Arc::new(VpnApp::new());
+    vpn_app.config.set_saml_listen(saml_listen);
 
     // Setup connection manager
     let connection_manager = ConnectionManager::new();
@@ -36,13 +103,20 @@ fn initialize_app() -> bool {
             "Last OpenVPN session (PID: {}) was not closed properly",
             p
         ));
-        let process_info = Arc::new(ProcessInfo::new());
         let pid = p;
+        let privilege = vpn_app.config.privilege_helper();
         std::thread::spawn(move || {
             std::thread::sleep(Duration::from_secs(5));
-            kill_openvpn(pid);
+            kill_openvpn(pid, privilege);
         });
     }
+    if let Some(applied) = host_dns::applied() {
+        vpn_app.log.append(format!(
+            "Restoring the DNS changed by the last session: {}",
+            applied
+        ));
+        restore_dns(&vpn_app.log, vpn_app.config.privilege_helper());
+    }
 
     unsafe {
         APP_INSTANCE = Some(Arc::new(Mutex::new(vpn_app)));
@@ -51,6 +125,9 @@ fn initialize_app() -> bool {
     true
 }
 
+/// # Safety
+///
+/// `config_path` must be null or point to a valid NUL-terminated string.
 #[no_mangle]
 pub unsafe extern "C" fn openaws_connect(config_path: *const c_char) -> bool {
     if config_path.is_null() {
@@ -85,7 +162,12 @@ pub unsafe extern "C" fn openaws_connect(config_path: *const c_char) -> bool {
 
     app.log
         .append(format!("Connecting using config: {}", config_str));
-    app.config.save_config(&config_path);
+    if let Err(e) = app.config.save_config(&config_path) {
+        app.log
+            .append(format!("Unable to load config {}: {}", config_str, e));
+        log_to_android(&format!("Unable to load config {}: {}", config_str, e));
+        return false;
+    }
     app.dns.resolve_addresses();
 
     // Initiate connection
@@ -98,6 +180,44 @@ pub unsafe extern "C" fn openaws_connect(config_path: *const c_char) -> bool {
     false
 }
 
+/// Forces the transport used by the next `openaws_connect`.
+/// `proto`: 0 = from profile, 1 = UDP, 2 = TCP. `port`: 0 = from profile.
+#[no_mangle]
+pub extern "C" fn openaws_set_transport(proto: i32, port: i32) -> bool {
+    let app = unsafe {
+        match &APP_INSTANCE {
+            Some(app) => app.lock().unwrap().clone(),
+            None => {
+                log_to_android("Error: call openaws_init() first");
+                return false;
+            }
+        }
+    };
+
+    let proto = match proto {
+        0 => None,
+        1 => Some(Proto::Udp),
+        2 => Some(Proto::Tcp),
+        _ => {
+            log_to_android(&format!("Error: unknown protocol {}", proto));
+            return false;
+        }
+    };
+    let port = match port {
+        0 => None,
+        p => match u16::try_from(p) {
+            Ok(p) => Some(p),
+            Err(_) => {
+                log_to_android(&format!("Error: invalid port {}", p));
+                return false;
+            }
+        },
+    };
+
+    app.config.set_transport(TransportOverride { proto, port });
+    true
+}
+
 #[no_mangle]
 pub extern "C" fn openaws_disconnect() -> bool {
     let app = unsafe {
@@ -134,19 +254,729 @@ pub extern "C" fn openaws_get_state() -> i32 {
     let state = {
         let state_manager = app.state.lock().unwrap();
         if let Some(ref state_manager) = *state_manager {
-            *state_manager.state.borrow()
+            state_manager.state()
         } else {
             return -1;
         }
     };
 
+    state_code(&state)
+}
+
+fn state_code(state: &State) -> i32 {
     match state {
         State::Disconnected => 0,
         State::Connecting => 1,
         State::Connected => 2,
+        State::AwaitingBrowser => 3,
+        State::Authenticating => 4,
+        State::Reconnecting(_) => 5,
+        State::Failed(_) => 6,
+    }
+}
+
+/// Why the connection failed while in the failed state, 0 otherwise:
+/// 1 no profile, 2 no addresses, 3 missing OpenVPN, 4 spawn failure,
+/// 5 management interface, 6 missing SAML challenge, 7 SAML response rejected,
+/// 8 login rejected, 9 TLS error, 10 timeout, 11 OpenVPN exited,
+/// 12 all endpoints failed, 13 connection lost, 14 OpenVPN lacks the AWS patch,
+/// 15 DNS lookup failed.
+#[no_mangle]
+pub extern "C" fn openaws_get_error_code() -> i32 {
+    current_error().map(|e| e.code()).unwrap_or(0)
+}
+
+/// Message of the failure reported by `openaws_get_error_code`, null without one.
+///
+/// # Safety
+///
+/// The returned string must be released with `openaws_free_string`.
+#[no_mangle]
+pub unsafe extern "C" fn openaws_get_error_message() -> *mut c_char {
+    match current_error().and_then(|e| CString::new(e.to_string()).ok()) {
+        Some(message) => message.into_raw(),
+        None => ptr::null_mut(),
+    }
+}
+
+/// Explanation and suggested fix for the last OpenVPN failure of the current
+/// connection, null without one.
+///
+/// # Safety
+///
+/// The returned string must be released with `openaws_free_string`.
+#[no_mangle]
+pub unsafe extern "C" fn openaws_get_diagnosis() -> *mut c_char {
+    let app = match &APP_INSTANCE {
+        Some(app) => app.lock().unwrap().clone(),
+        None => return ptr::null_mut(),
+    };
+
+    let diagnosis = {
+        let manager = app.connection_manager.lock().unwrap();
+        manager.as_ref().and_then(|m| m.diagnosis())
+    };
+    match diagnosis.and_then(|d| CString::new(d.to_string()).ok()) {
+        Some(diagnosis) => diagnosis.into_raw(),
+        None => ptr::null_mut(),
+    }
+}
+
+fn current_error() -> Option<ConnectError> {
+    let app = unsafe { APP_INSTANCE.as_ref()?.lock().unwrap().clone() };
+    let state = app.state.lock().unwrap().as_ref()?.state();
+    match state {
+        State::Failed(error) => Some(error),
+        _ => None,
+    }
+}
+
+/// Registers `callback` to be called on every state transition, replacing any
+/// previous one. Passing a null callback unregisters it.
+///
+/// # Safety
+///
+/// The callback is invoked from a background thread, `user_data` must stay
+/// valid and usable from that thread until the callback is replaced or
+/// `openaws_cleanup` is called.
+#[no_mangle]
+pub unsafe extern "C" fn openaws_set_state_callback(
+    callback: Option<StateCallback>,
+    user_data: *mut c_void,
+) -> bool {
+    let app = match &APP_INSTANCE {
+        Some(app) => app.lock().unwrap().clone(),
+        None => {
+            log_to_android("Error: call openaws_init() first");
+            return false;
+        }
+    };
+
+    if let Some(previous) = STATE_SUBSCRIPTION.take() {
+        previous.abort();
+    }
+
+    let callback = match callback {
+        Some(callback) => callback,
+        None => return true,
+    };
+
+    let mut transitions = match app.state.lock().unwrap().as_ref() {
+        Some(state_manager) => state_manager.subscribe(),
+        None => return false,
+    };
+
+    let user_data = UserData(user_data);
+    STATE_SUBSCRIPTION = Some(app.runtime.spawn(async move {
+        loop {
+            let state = match transitions.recv().await {
+                Ok(transition) => transition.to,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+
+            let description = CString::new(state.to_string()).unwrap_or_default();
+            callback(state_code(&state), description.as_ptr(), user_data.get());
+        }
+    }));
+
+    true
+}
+
+/// Sets how a tunnel that drops after connecting is brought back: up to
+/// `max_attempts` reconnects (0 disables), waiting from `initial_delay_ms`
+/// doubling up to `max_delay_ms` between them.
+#[no_mangle]
+pub extern "C" fn openaws_set_reconnect_policy(
+    max_attempts: i32,
+    initial_delay_ms: i32,
+    max_delay_ms: i32,
+) -> bool {
+    let app = unsafe {
+        match &APP_INSTANCE {
+            Some(app) => app.lock().unwrap().clone(),
+            None => {
+                log_to_android("Error: call openaws_init() first");
+                return false;
+            }
+        }
+    };
+
+    if max_attempts < 0 || initial_delay_ms < 0 || max_delay_ms < initial_delay_ms {
+        log_to_android("Error: invalid reconnect policy");
+        return false;
+    }
+
+    app.config.set_reconnect_policy(ReconnectPolicy {
+        max_attempts: max_attempts as u32,
+        initial_delay: Duration::from_millis(initial_delay_ms as u64),
+        max_delay: Duration::from_millis(max_delay_ms as u64),
+    });
+
+    true
+}
+
+/// Sets how OpenVPN gets root privileges, one of "auto", "sudo", "pkexec" or
+/// "none", and its `--verb` level.
+///
+/// # Safety
+///
+/// `privilege_helper` must be a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn openaws_set_openvpn_options(
+    privilege_helper: *const c_char,
+    verbosity: i32,
+) -> bool {
+    let app = match &APP_INSTANCE {
+        Some(app) => app.lock().unwrap().clone(),
+        None => {
+            log_to_android("Error: call openaws_init() first");
+            return false;
+        }
+    };
+
+    if privilege_helper.is_null() || !(0..=MAX_VERBOSITY as i32).contains(&verbosity) {
+        log_to_android("Error: invalid OpenVPN options");
+        return false;
+    }
+    let helper = match CStr::from_ptr(privilege_helper)
+        .to_str()
+        .ok()
+        .and_then(PrivilegeHelper::parse)
+    {
+        Some(helper) => helper,
+        None => {
+            log_to_android("Error: unknown privilege helper");
+            return false;
+        }
+    };
+
+    app.config.set_privilege_helper(helper);
+    app.config.set_verbosity(verbosity as u8);
+    true
+}
+
+/// Sets which endpoint addresses are used: "ipv4", "ipv6" or "happy-eyeballs".
+/// Applies from the next connect.
+///
+/// # Safety
+///
+/// `family` must be a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn openaws_set_address_family(family: *const c_char) -> bool {
+    let app = match &APP_INSTANCE {
+        Some(app) => app.lock().unwrap().clone(),
+        None => {
+            log_to_android("Error: call openaws_init() first");
+            return false;
+        }
+    };
+
+    if family.is_null() {
+        log_to_android("Error: invalid address family");
+        return false;
+    }
+    match CStr::from_ptr(family)
+        .to_str()
+        .ok()
+        .and_then(AddressFamily::parse)
+    {
+        Some(family) => {
+            app.config.set_address_family(family);
+            true
+        }
+        None => {
+            log_to_android("Error: unknown address family");
+            false
+        }
+    }
+}
+
+/// Sets whether endpoints are looked up under a random subdomain: "always",
+/// "never" or "profile" to follow `remote-random-hostname`. Applies from the
+/// next connect.
+///
+/// # Safety
+///
+/// `when` must be a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn openaws_set_random_hostname(when: *const c_char) -> bool {
+    let app = match &APP_INSTANCE {
+        Some(app) => app.lock().unwrap().clone(),
+        None => {
+            log_to_android("Error: call openaws_init() first");
+            return false;
+        }
+    };
+
+    if when.is_null() {
+        log_to_android("Error: invalid random hostname setting");
+        return false;
+    }
+    match CStr::from_ptr(when)
+        .to_str()
+        .ok()
+        .and_then(RandomHostname::parse)
+    {
+        Some(when) => {
+            app.config.set_random_hostname(when);
+            true
+        }
+        None => {
+            log_to_android("Error: unknown random hostname setting");
+            false
+        }
+    }
+}
+
+/// Sets how endpoint names are looked up. `nameservers` is a comma separated
+/// list such as "1.1.1.1,[2606:4700::1111]:53", null or empty for those of the
+/// system. Each query waits `timeout_ms` and is repeated `retries` times.
+///
+/// # Safety
+///
+/// `nameservers` must be null or a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn openaws_set_resolver(
+    nameservers: *const c_char,
+    timeout_ms: i32,
+    retries: i32,
+    tcp_fallback: bool,
+    system_fallback: bool,
+) -> bool {
+    let app = match &APP_INSTANCE {
+        Some(app) => app.lock().unwrap().clone(),
+        None => {
+            log_to_android("Error: call openaws_init() first");
+            return false;
+        }
+    };
+
+    if timeout_ms <= 0 || retries < 0 {
+        log_to_android("Error: invalid resolver settings");
+        return false;
+    }
+    let list = match nameservers.is_null() {
+        true => "",
+        false => CStr::from_ptr(nameservers).to_str().unwrap_or_default(),
+    };
+    let mut parsed = Vec::new();
+    for server in list.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        match parse_nameserver(server) {
+            Some(addr) => parsed.push(addr),
+            None => {
+                log_to_android(&format!("Error: invalid nameserver '{}'", server));
+                return false;
+            }
+        }
+    }
+
+    app.config.set_resolver(ResolverConfig {
+        nameservers: parsed,
+        timeout: Duration::from_millis(timeout_ms as u64),
+        retries: retries as u32,
+        tcp_fallback,
+        system_fallback,
+        doh: app.config.resolver().doh,
+    });
+    true
+}
+
+/// Looks endpoint names up with the DNS-over-HTTPS service at `url` instead of
+/// the nameservers. `bootstrap` is the address of the service, or null to look
+/// its name up. A null `url` goes back to plain DNS.
+///
+/// # Safety
+///
+/// `url` and `bootstrap` must be null or valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn openaws_set_doh(url: *const c_char, bootstrap: *const c_char) -> bool {
+    let app = match &APP_INSTANCE {
+        Some(app) => app.lock().unwrap().clone(),
+        None => {
+            log_to_android("Error: call openaws_init() first");
+            return false;
+        }
+    };
+
+    let mut resolver = app.config.resolver();
+    if url.is_null() {
+        resolver.doh = None;
+        app.config.set_resolver(resolver);
+        return true;
+    }
+    let url = match CStr::from_ptr(url).to_str().ok().and_then(parse_doh_url) {
+        Some(url) => url,
+        None => {
+            log_to_android("Error: invalid DoH URL, use https://");
+            return false;
+        }
+    };
+    let bootstrap = match bootstrap.is_null() {
+        true => None,
+        false => match CStr::from_ptr(bootstrap).to_str().ok().map(str::parse) {
+            Some(Ok(addr)) => Some(addr),
+            _ => {
+                log_to_android("Error: invalid DoH bootstrap address");
+                return false;
+            }
+        },
+    };
+
+    resolver.doh = Some(DohConfig { url, bootstrap });
+    app.config.set_resolver(resolver);
+    true
+}
+
+/// Sets how the DNS servers pushed by the VPN are applied to the host: "auto",
+/// "resolved", "resolvconf", "file" or "none". Applies from the next connect.
+///
+/// # Safety
+///
+/// `backend` must be a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn openaws_set_dns_backend(backend: *const c_char) -> bool {
+    let app = match &APP_INSTANCE {
+        Some(app) => app.lock().unwrap().clone(),
+        None => {
+            log_to_android("Error: call openaws_init() first");
+            return false;
+        }
+    };
+
+    if backend.is_null() {
+        log_to_android("Error: invalid DNS backend");
+        return false;
+    }
+    match CStr::from_ptr(backend)
+        .to_str()
+        .ok()
+        .and_then(DnsBackend::parse)
+    {
+        Some(backend) => {
+            app.config.set_dns_backend(backend);
+            true
+        }
+        None => {
+            log_to_android("Error: unknown DNS backend");
+            false
+        }
+    }
+}
+
+/// Resolves only `domains` through the VPN, a comma separated list such as
+/// "internal.example.com,corp.example.com". Null or empty sends every query to
+/// the VPN. Applies from the next connect.
+///
+/// # Safety
+///
+/// `domains` must be null or a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn openaws_set_split_dns(domains: *const c_char) -> bool {
+    let app = match &APP_INSTANCE {
+        Some(app) => app.lock().unwrap().clone(),
+        None => {
+            log_to_android("Error: call openaws_init() first");
+            return false;
+        }
+    };
+
+    let domains = match domains.is_null() {
+        true => "",
+        false => match CStr::from_ptr(domains).to_str() {
+            Ok(domains) => domains,
+            Err(_) => {
+                log_to_android("Error: invalid split-DNS domains");
+                return false;
+            }
+        },
+    };
+    let mut parsed = Vec::new();
+    for domain in domains.split(',').filter(|d| !d.trim().is_empty()) {
+        match parse_split_domain(domain) {
+            Some(domain) => parsed.push(domain),
+            None => {
+                log_to_android(&format!("Error: invalid split-DNS domain '{}'", domain));
+                return false;
+            }
+        }
+    }
+
+    let mut split = app.config.split_dns();
+    split.domains = parsed;
+    app.config.set_split_dns(split);
+    true
+}
+
+/// Sets which networks go through the tunnel. `include`, `exclude` and `routes`
+/// are comma separated networks such as "10.0.0.0/16", or null. With `include`
+/// only those networks are routed, `exclude` keeps IPv4 networks outside the
+/// tunnel and `no_pull` ignores the pushed routes, leaving only `routes`.
+/// Applies from the next connect.
+///
+/// # Safety
+///
+/// `include`, `exclude` and `routes` must be null or valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn openaws_set_routes(
+    include: *const c_char,
+    exclude: *const c_char,
+    routes: *const c_char,
+    no_pull: bool,
+) -> bool {
+    let app = match &APP_INSTANCE {
+        Some(app) => app.lock().unwrap().clone(),
+        None => {
+            log_to_android("Error: call openaws_init() first");
+            return false;
+        }
+    };
+
+    let mut nets = Vec::new();
+    for list in [include, exclude, routes] {
+        let list = match list.is_null() {
+            true => "",
+            false => CStr::from_ptr(list).to_str().unwrap_or("-"),
+        };
+        let mut parsed = Vec::new();
+        for net in list.split(',').filter(|n| !n.trim().is_empty()) {
+            match Cidr::parse(net) {
+                Some(net) => parsed.push(net),
+                None => {
+                    log_to_android(&format!("Error: invalid network '{}'", net));
+                    return false;
+                }
+            }
+        }
+        nets.push(parsed);
     }
+    let (routes, exclude, include) = (nets.pop(), nets.pop(), nets.pop());
+    let policy = RoutePolicy {
+        include: include.unwrap_or_default(),
+        exclude: exclude.unwrap_or_default(),
+        no_pull,
+        routes: routes.unwrap_or_default(),
+    };
+    if let Err(e) = policy.validate() {
+        log_to_android(&format!("Error: {}", e));
+        return false;
+    }
+
+    app.config.set_route_policy(policy);
+    true
 }
 
+/// Number of log lines kept in memory, the oldest are dropped first.
+#[no_mangle]
+pub extern "C" fn openaws_set_log_retention(lines: i32) -> bool {
+    let app = unsafe {
+        match &APP_INSTANCE {
+            Some(app) => app.lock().unwrap().clone(),
+            None => {
+                log_to_android("Error: call openaws_init() first");
+                return false;
+            }
+        }
+    };
+
+    if lines <= 0 {
+        log_to_android("Error: invalid log retention");
+        return false;
+    }
+
+    app.log.set_retention(lines as usize);
+    true
+}
+
+/// Uses the OpenVPN executable at `path` instead of searching for one. A null
+/// `path` restores the search. Fails when the executable lacks the AWS patch.
+///
+/// # Safety
+///
+/// `path` must be null or a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn openaws_set_openvpn_path(path: *const c_char) -> bool {
+    let app = match &APP_INSTANCE {
+        Some(app) => app.lock().unwrap().clone(),
+        None => {
+            log_to_android("Error: call openaws_init() first");
+            return false;
+        }
+    };
+
+    if path.is_null() {
+        app.config.set_openvpn_path(None);
+        return true;
+    }
+
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(p) => PathBuf::from(p),
+        Err(_) => {
+            log_to_android("Error: path is not a valid UTF-8 string");
+            return false;
+        }
+    };
+
+    match discover(Some(&path)) {
+        Ok(openvpn) if openvpn.source == Source::Setting => {
+            app.log.append(format!("Using OpenVPN {}", openvpn));
+            app.config.set_openvpn_path(Some(path));
+            true
+        }
+        Ok(_) => {
+            log_to_android(&format!("Error: {} does not exist", path.display()));
+            false
+        }
+        Err(e) => {
+            app.log.append(format!("Error: {}", e));
+            log_to_android(&format!("Error: {}", e));
+            false
+        }
+    }
+}
+
+/// Sets when the session of a connected tunnel ends and what to do ahead of
+/// it: `max_session_secs` as configured on the endpoint (0 for none), warn
+/// `warn_before_secs` early, and log in again in the background if `renew`.
+/// The renewed login restarts the tunnel, so traffic stops for the few seconds
+/// the new tunnel takes to come up. OpenVPN closes the tunnel after
+/// `inactive_secs` without traffic (0 for never), from the next connect.
+#[no_mangle]
+pub extern "C" fn openaws_set_session_policy(
+    max_session_secs: i64,
+    warn_before_secs: i64,
+    renew: bool,
+    inactive_secs: i64,
+) -> bool {
+    let app = unsafe {
+        match &APP_INSTANCE {
+            Some(app) => app.lock().unwrap().clone(),
+            None => {
+                log_to_android("Error: call openaws_init() first");
+                return false;
+            }
+        }
+    };
+
+    if max_session_secs < 0 || warn_before_secs < 0 || inactive_secs < 0 {
+        log_to_android("Error: invalid session policy");
+        return false;
+    }
+
+    app.config.set_session_policy(SessionPolicy {
+        max_session: match max_session_secs {
+            0 => None,
+            s => Some(Duration::from_secs(s as u64)),
+        },
+        warn_before: Duration::from_secs(warn_before_secs as u64),
+        renew,
+        inactive_timeout: match inactive_secs {
+            0 => None,
+            s => Some(Duration::from_secs(s as u64)),
+        },
+    });
+
+    true
+}
+
+/// Unix time at which the connected tunnel is going to be closed, by the login
+/// expiry, the maximum session length or inactivity. 0 when not connected.
+#[no_mangle]
+pub extern "C" fn openaws_get_session_end() -> i64 {
+    let app = match unsafe { APP_INSTANCE.as_ref() } {
+        Some(app) => app.lock().unwrap().clone(),
+        None => return 0,
+    };
+
+    let manager = app.connection_manager.lock().unwrap();
+    manager
+        .as_ref()
+        .and_then(|m| m.session_end())
+        .and_then(|e| e.at.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Identity of the current login, null when not authenticated.
+///
+/// # Safety
+///
+/// The returned string must be released with `openaws_free_string`.
+#[no_mangle]
+pub unsafe extern "C" fn openaws_get_session_identity() -> *mut c_char {
+    match current_session().and_then(|s| CString::new(s.identity()).ok()) {
+        Some(identity) => identity.into_raw(),
+        None => ptr::null_mut(),
+    }
+}
+
+/// Unix time at which the identity provider ends the current login session,
+/// 0 when unknown.
+#[no_mangle]
+pub extern "C" fn openaws_get_session_expiry() -> i64 {
+    current_session()
+        .and_then(|s| s.session_expiry())
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+fn current_session() -> Option<Assertion> {
+    let app = unsafe { APP_INSTANCE.as_ref()?.lock().unwrap().clone() };
+    let manager = app.connection_manager.lock().unwrap();
+    manager.as_ref().and_then(|m| m.session())
+}
+
+/// Recent state transitions, one `<unix time> <from> -> <to>` per line, oldest first.
+///
+/// # Safety
+///
+/// The returned string must be released with `openaws_free_string`.
+#[no_mangle]
+pub unsafe extern "C" fn openaws_get_state_history() -> *mut c_char {
+    let app = match &APP_INSTANCE {
+        Some(app) => app.lock().unwrap().clone(),
+        None => return ptr::null_mut(),
+    };
+
+    let history = match app.state.lock().unwrap().as_ref() {
+        Some(state_manager) => state_manager.history(),
+        None => return ptr::null_mut(),
+    };
+
+    let buffer = history
+        .iter()
+        .map(|t| t.to_string())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    match CString::new(buffer) {
+        Ok(c_string) => c_string.into_raw(),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Statistics of the endpoint address cache, then one line per cached name.
+///
+/// # Safety
+///
+/// The returned string must be released with `openaws_free_string`.
+#[no_mangle]
+pub unsafe extern "C" fn openaws_get_dns_cache() -> *mut c_char {
+    let app = match &APP_INSTANCE {
+        Some(app) => app.lock().unwrap().clone(),
+        None => return ptr::null_mut(),
+    };
+
+    match CString::new(app.dns.cache.report().join("\n")) {
+        Ok(c_string) => c_string.into_raw(),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// # Safety
+///
+/// The returned string must be released with `openaws_free_string`.
 #[no_mangle]
 pub unsafe extern "C" fn openaws_get_last_log() -> *mut c_char {
     let app = match &APP_INSTANCE {
@@ -176,6 +1006,9 @@ pub unsafe extern "C" fn openaws_get_last_log() -> *mut c_char {
     }
 }
 
+/// # Safety
+///
+/// `s` must be null or a pointer previously returned by this library.
 #[no_mangle]
 pub unsafe extern "C" fn openaws_free_string(s: *mut c_char) {
     if !s.is_null() {
@@ -202,6 +1035,9 @@ pub extern "C" fn openaws_cleanup() -> bool {
     }
 
     unsafe {
+        if let Some(subscription) = STATE_SUBSCRIPTION.take() {
+            subscription.abort();
+        }
         APP_INSTANCE = None;
     }
 
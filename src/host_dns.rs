@@ -0,0 +1,509 @@
+use crate::config::{DnsBackend, PrivilegeHelper, SplitDns};
+use crate::local_config::LocalConfig;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// Copy of `/etc/resolv.conf` taken before it is rewritten, in the state directory.
+const RESOLV_BACKUP: &str = "resolv.conf.backup";
+
+/// Content for `/etc/resolv.conf`, copied into place as root.
+const RESOLV_STAGED: &str = "resolv.conf.vpn";
+
+/// Exists while systemd-resolved runs.
+const RESOLVED_DIR: &str = "/run/systemd/resolve";
+
+/// Searched after PATH, the tools live in sbin directories on some systems.
+const SYSTEM_DIRS: &[&str] = &["/usr/sbin", "/usr/bin", "/sbin", "/bin"];
+
+lazy_static! {
+    /// Keeps an apply and a restore from interleaving.
+    static ref CHANGING: Mutex<()> = Mutex::new(());
+}
+
+/// DNS options pushed by the server with `dhcp-option`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PushedDns {
+    pub servers: Vec<IpAddr>,
+    pub domains: Vec<String>,
+}
+
+impl PushedDns {
+    /// Reads `PUSH: Received control message: 'PUSH_REPLY,...'`.
+    pub fn from_log(message: &str) -> Option<PushedDns> {
+        let (_, reply) = message.split_once("PUSH_REPLY,")?;
+        let mut dns = PushedDns::default();
+        for option in reply.trim_end_matches('\'').split(',') {
+            let mut words = option.split_whitespace();
+            if words.next() != Some("dhcp-option") {
+                continue;
+            }
+            match (words.next(), words.next()) {
+                (Some("DNS" | "DNS6"), Some(addr)) => {
+                    if let Ok(addr) = addr.parse() {
+                        if !dns.servers.contains(&addr) {
+                            dns.servers.push(addr);
+                        }
+                    }
+                }
+                (Some("DOMAIN" | "DOMAIN-SEARCH"), Some(domain)) => {
+                    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+                    if !dns.domains.contains(&domain) {
+                        dns.domains.push(domain);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Some(dns)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.servers.is_empty()
+    }
+}
+
+/// What OpenVPN reported about the tunnel while it came up.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TunnelInfo {
+    pub device: Option<String>,
+    pub dns: PushedDns,
+}
+
+impl TunnelInfo {
+    /// Picks the device and the pushed options out of a log line of OpenVPN.
+    pub fn observe(&mut self, message: &str) {
+        if let Some(dns) = PushedDns::from_log(message) {
+            self.dns = dns;
+        } else if let Some(device) = tun_device(message) {
+            self.device = Some(device);
+        }
+    }
+}
+
+/// `TUN/TAP device tun0 opened` on Linux, `Opened utun device utun5` on macOS.
+fn tun_device(message: &str) -> Option<String> {
+    let device = match message.split_once("TUN/TAP device ") {
+        Some((_, rest)) => rest.strip_suffix(" opened")?,
+        None => message.split_once("Opened utun device ")?.1,
+    };
+    Some(device.trim().to_string()).filter(|d| !d.is_empty() && !d.contains(' '))
+}
+
+/// A DNS change made for a tunnel, kept in the state file until it is undone.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AppliedDns {
+    pub backend: DnsBackend,
+    pub device: String,
+    pub servers: Vec<IpAddr>,
+    pub domains: Vec<String>,
+    /// Split-DNS domains, the only ones sent to `servers` when not empty.
+    #[serde(default)]
+    pub routing_domains: Vec<String>,
+    /// Built-in forwarder the host asks instead of `servers`.
+    #[serde(default)]
+    pub forwarder: Option<IpAddr>,
+}
+
+impl AppliedDns {
+    /// Nameservers written for the host.
+    fn host_servers(&self) -> Vec<IpAddr> {
+        match self.forwarder {
+            Some(forwarder) => vec![forwarder],
+            None => self.servers.clone(),
+        }
+    }
+}
+
+impl fmt::Display for AppliedDns {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let servers: Vec<_> = self.servers.iter().map(|s| s.to_string()).collect();
+        write!(f, "{}", servers.join(", "))?;
+        if !self.domains.is_empty() {
+            write!(f, " search {}", self.domains.join(" "))?;
+        }
+        if !self.routing_domains.is_empty() {
+            write!(f, " only for {}", self.routing_domains.join(", "))?;
+        }
+        if let Some(forwarder) = self.forwarder {
+            write!(f, " forwarded by {}", forwarder)?;
+        }
+        write!(f, " on {} via {}", self.device, self.backend)
+    }
+}
+
+/// A command run as root.
+#[derive(Debug, PartialEq, Eq)]
+struct Step {
+    args: Vec<String>,
+    input: Option<String>,
+}
+
+impl Step {
+    fn new(args: &[&str]) -> Step {
+        Step {
+            args: args.iter().map(|a| a.to_string()).collect(),
+            input: None,
+        }
+    }
+}
+
+/// The backend `Auto` stands for on this host, `None` when there is nothing
+/// that can be changed.
+pub fn detect(backend: DnsBackend) -> Option<DnsBackend> {
+    match backend {
+        DnsBackend::Auto if !cfg!(target_os = "linux") => None,
+        DnsBackend::Auto if Path::new(RESOLVED_DIR).is_dir() && installed("resolvectl") => {
+            Some(DnsBackend::Resolved)
+        }
+        DnsBackend::Auto if installed("resolvconf") => Some(DnsBackend::Resolvconf),
+        DnsBackend::Auto if Path::new(RESOLV_CONF).is_file() => Some(DnsBackend::File),
+        DnsBackend::Auto | DnsBackend::None => None,
+        backend => Some(backend),
+    }
+}
+
+fn installed(program: &str) -> bool {
+    let path = env::var_os("PATH").unwrap_or_default();
+    env::split_paths(&path)
+        .chain(SYSTEM_DIRS.iter().map(PathBuf::from))
+        .any(|dir| dir.join(program).is_file())
+}
+
+/// The DNS change recorded for the current or last tunnel.
+pub fn applied() -> Option<AppliedDns> {
+    LocalConfig::read_applied_dns()
+}
+
+/// Points the host at the pushed nameservers, or only the split domains when
+/// there are any. Without systemd-resolved, the forwarder on `split.forwarder`
+/// must already run. The change is recorded before it is made, so that
+/// `restore` undoes it even after a crash.
+pub fn apply(
+    backend: DnsBackend,
+    privilege: PrivilegeHelper,
+    device: &str,
+    dns: &PushedDns,
+    split: &SplitDns,
+) -> io::Result<AppliedDns> {
+    let _changing = CHANGING.lock().unwrap();
+    let backend = detect(backend).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "no way to change the DNS configuration of this host",
+        )
+    })?;
+    // Left behind by a tunnel that was not cleaned up, its backup must not be overwritten
+    restore_locked(privilege)?;
+
+    let dir = state_dir()?;
+    let applied = applied_dns(backend, device, dns, split);
+    if backend == DnsBackend::File {
+        std::fs::copy(RESOLV_CONF, dir.join(RESOLV_BACKUP))?;
+        std::fs::write(dir.join(RESOLV_STAGED), resolv_conf(&applied))?;
+    }
+    LocalConfig::save_applied_dns(Some(applied.clone()))?;
+
+    for step in apply_steps(&applied, &dir.join(RESOLV_STAGED)) {
+        if let Err(e) = run(privilege, &step) {
+            let _ = restore_locked(privilege);
+            return Err(e);
+        }
+    }
+    Ok(applied)
+}
+
+/// Undoes the recorded DNS change, if any. A failed restore keeps the record
+/// for the next attempt.
+pub fn restore(privilege: PrivilegeHelper) -> io::Result<Option<AppliedDns>> {
+    let _changing = CHANGING.lock().unwrap();
+    restore_locked(privilege)
+}
+
+fn restore_locked(privilege: PrivilegeHelper) -> io::Result<Option<AppliedDns>> {
+    let applied = match LocalConfig::read_applied_dns() {
+        Some(applied) => applied,
+        None => return Ok(None),
+    };
+    let dir = state_dir()?;
+    let backup = dir.join(RESOLV_BACKUP);
+
+    // systemd-resolved forgets the settings of a link once it is gone
+    let linked = Path::new("/sys/class/net").join(&applied.device).exists();
+    if applied.backend != DnsBackend::Resolved || linked {
+        for step in revert_steps(&applied, &backup) {
+            run(privilege, &step)?;
+        }
+    }
+    if applied.backend == DnsBackend::File {
+        let _ = std::fs::remove_file(&backup);
+        let _ = std::fs::remove_file(dir.join(RESOLV_STAGED));
+    }
+    LocalConfig::save_applied_dns(None)?;
+    Ok(Some(applied))
+}
+
+fn applied_dns(backend: DnsBackend, device: &str, dns: &PushedDns, split: &SplitDns) -> AppliedDns {
+    let mut domains = dns.domains.clone();
+    // A pushed search domain outside the split domains would pull its queries in
+    domains.retain(|d| split.is_empty() || split.matches(d));
+    AppliedDns {
+        backend,
+        device: device.to_string(),
+        servers: dns.servers.clone(),
+        domains,
+        routing_domains: split.domains.clone(),
+        forwarder: (!split.is_empty() && backend != DnsBackend::Resolved)
+            .then_some(split.forwarder),
+    }
+}
+
+fn state_dir() -> io::Result<PathBuf> {
+    let dir = LocalConfig::dir()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no config directory"))?;
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Name of the resolvconf record, the one OpenVPN's own scripts use.
+fn record(device: &str) -> String {
+    format!("{}.openvpn", device)
+}
+
+fn resolv_conf(applied: &AppliedDns) -> String {
+    let mut content = "# Written by openaws-vpn-client, restored on disconnect\n".to_string();
+    for server in applied.host_servers() {
+        content.push_str(&format!("nameserver {}\n", server));
+    }
+    if !applied.domains.is_empty() {
+        content.push_str(&format!("search {}\n", applied.domains.join(" ")));
+    }
+    content
+}
+
+fn apply_steps(applied: &AppliedDns, staged: &Path) -> Vec<Step> {
+    let device = applied.device.as_str();
+    match applied.backend {
+        DnsBackend::Resolved => {
+            let servers: Vec<_> = applied.servers.iter().map(|s| s.to_string()).collect();
+            let mut dns = Step::new(&["resolvectl", "dns", device]);
+            dns.args.extend(servers);
+            let mut steps = vec![dns];
+            // `~` makes a routing domain, used for lookups but not searched
+            let routing = applied.routing_domains.iter().map(|d| format!("~{}", d));
+            if !applied.domains.is_empty() || !applied.routing_domains.is_empty() {
+                let mut domain = Step::new(&["resolvectl", "domain", device]);
+                domain
+                    .args
+                    .extend(applied.domains.iter().cloned().chain(routing));
+                steps.push(domain);
+            }
+            if !applied.routing_domains.is_empty() {
+                steps.push(Step::new(&["resolvectl", "default-route", device, "false"]));
+            }
+            steps
+        }
+        DnsBackend::Resolvconf => vec![Step {
+            input: Some(resolv_conf(applied)),
+            ..Step::new(&["resolvconf", "-a", &record(device)])
+        }],
+        DnsBackend::File => vec![Step::new(&["cp", &staged.to_string_lossy(), RESOLV_CONF])],
+        DnsBackend::Auto | DnsBackend::None => Vec::new(),
+    }
+}
+
+fn revert_steps(applied: &AppliedDns, backup: &Path) -> Vec<Step> {
+    let device = applied.device.as_str();
+    match applied.backend {
+        DnsBackend::Resolved => vec![Step::new(&["resolvectl", "revert", device])],
+        DnsBackend::Resolvconf => vec![Step::new(&["resolvconf", "-d", &record(device)])],
+        DnsBackend::File if backup.exists() => {
+            vec![Step::new(&["cp", &backup.to_string_lossy(), RESOLV_CONF])]
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Runs `step` through the privilege helper, like OpenVPN itself.
+fn run(privilege: PrivilegeHelper, step: &Step) -> io::Result<()> {
+    let mut command = match privilege.command() {
+        Some(helper) => {
+            let mut command = Command::new(helper);
+            command.arg(&step.args[0]);
+            command
+        }
+        None => Command::new(&step.args[0]),
+    };
+    command
+        .args(&step.args[1..])
+        .stdin(match step.input {
+            Some(_) => Stdio::piped(),
+            None => Stdio::null(),
+        })
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+
+    let mut child = command.spawn()?;
+    if let (Some(input), Some(mut stdin)) = (&step.input, child.stdin.take()) {
+        stdin.write_all(input.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "'{}' failed: {}",
+                step.args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUSH_REPLY: &str =
+        "PUSH: Received control message: 'PUSH_REPLY,route 10.0.0.0 255.255.0.0,\
+        route-gateway 10.1.0.1,topology subnet,dhcp-option DNS 10.0.0.2,dhcp-option DNS 10.0.0.2,\
+        dhcp-option DOMAIN Corp.Example.com.,dhcp-option DOMAIN-SEARCH eu.corp.example.com,\
+        ifconfig 10.1.0.5 255.255.255.224,peer-id 0'";
+
+    fn applied(backend: DnsBackend) -> AppliedDns {
+        AppliedDns {
+            backend,
+            device: "tun0".to_string(),
+            servers: vec!["10.0.0.2".parse().unwrap()],
+            domains: vec!["corp.example.com".to_string()],
+            routing_domains: Vec::new(),
+            forwarder: None,
+        }
+    }
+
+    fn args(steps: Vec<Step>) -> Vec<String> {
+        steps.into_iter().map(|s| s.args.join(" ")).collect()
+    }
+
+    #[test]
+    fn reads_the_pushed_options() {
+        let mut tunnel = TunnelInfo::default();
+        tunnel.observe("TUN/TAP device tun0 opened");
+        tunnel.observe(PUSH_REPLY);
+        tunnel.observe("Initialization Sequence Completed");
+
+        assert_eq!(tunnel.device.as_deref(), Some("tun0"));
+        assert_eq!(tunnel.dns.servers, ["10.0.0.2".parse::<IpAddr>().unwrap()]);
+        assert_eq!(
+            tunnel.dns.domains,
+            ["corp.example.com", "eu.corp.example.com"]
+        );
+        assert_eq!(
+            tun_device("Opened utun device utun5"),
+            Some("utun5".to_string())
+        );
+        assert_eq!(
+            tun_device("TUN/TAP device /dev/net/tun exists previously"),
+            None
+        );
+        assert!(PushedDns::from_log(
+            "PUSH: Received control message: 'PUSH_REPLY,route-gateway 10.1.0.1'"
+        )
+        .unwrap()
+        .is_empty());
+    }
+
+    #[test]
+    fn builds_the_commands_of_each_backend() {
+        let staged = Path::new("/home/user/.config/openaws-vpn-client/resolv.conf.vpn");
+        let backup = Path::new("/tmp/openaws-no-backup");
+
+        assert_eq!(
+            args(apply_steps(&applied(DnsBackend::Resolved), staged)),
+            [
+                "resolvectl dns tun0 10.0.0.2",
+                "resolvectl domain tun0 corp.example.com"
+            ]
+        );
+        assert_eq!(
+            args(revert_steps(&applied(DnsBackend::Resolved), backup)),
+            ["resolvectl revert tun0"]
+        );
+
+        let resolvconf = apply_steps(&applied(DnsBackend::Resolvconf), staged);
+        assert_eq!(resolvconf[0].args, ["resolvconf", "-a", "tun0.openvpn"]);
+        assert_eq!(
+            resolvconf[0].input.as_deref(),
+            Some(
+                "# Written by openaws-vpn-client, restored on disconnect\n\
+                 nameserver 10.0.0.2\nsearch corp.example.com\n"
+            )
+        );
+        assert_eq!(
+            args(revert_steps(&applied(DnsBackend::Resolvconf), backup)),
+            ["resolvconf -d tun0.openvpn"]
+        );
+
+        assert_eq!(
+            args(apply_steps(&applied(DnsBackend::File), staged)),
+            [format!("cp {} /etc/resolv.conf", staged.display())]
+        );
+        // Without a backup there is nothing to put back
+        assert!(revert_steps(&applied(DnsBackend::File), backup).is_empty());
+        assert_eq!(detect(DnsBackend::None), None);
+        assert_eq!(detect(DnsBackend::File), Some(DnsBackend::File));
+    }
+
+    #[test]
+    fn routes_only_the_split_domains() {
+        let staged = Path::new("/tmp/resolv.conf.vpn");
+        let dns = PushedDns {
+            servers: vec!["10.0.0.2".parse().unwrap()],
+            domains: vec![
+                "corp.example.com".to_string(),
+                "internal.example.com".to_string(),
+            ],
+        };
+        let split = SplitDns::new(vec!["internal.example.com".to_string()]);
+
+        let resolved = applied_dns(DnsBackend::Resolved, "tun0", &dns, &split);
+        assert_eq!(resolved.forwarder, None);
+        assert_eq!(
+            args(apply_steps(&resolved, staged)),
+            [
+                "resolvectl dns tun0 10.0.0.2",
+                "resolvectl domain tun0 internal.example.com ~internal.example.com",
+                "resolvectl default-route tun0 false"
+            ]
+        );
+
+        let resolvconf = applied_dns(DnsBackend::Resolvconf, "tun0", &dns, &split);
+        assert_eq!(resolvconf.forwarder, Some(split.forwarder));
+        assert_eq!(
+            apply_steps(&resolvconf, staged)[0].input.as_deref(),
+            Some(
+                "# Written by openaws-vpn-client, restored on disconnect\n\
+                 nameserver 127.0.0.153\nsearch internal.example.com\n"
+            )
+        );
+        assert_eq!(
+            resolvconf.to_string(),
+            "10.0.0.2 search internal.example.com only for internal.example.com \
+             forwarded by 127.0.0.153 on tun0 via resolvconf"
+        );
+
+        let full = applied_dns(DnsBackend::Resolved, "tun0", &dns, &SplitDns::default());
+        assert_eq!(full.domains, dns.domains);
+        assert!(full.routing_domains.is_empty());
+    }
+}
@@ -3,12 +3,21 @@ mod app;
 mod cmd;
 mod config;
 mod consts;
+mod diagnose;
 mod dns;
+mod error;
 mod ffi;
+mod host_dns;
 mod local_config;
 mod log;
+mod management;
 mod manager;
+mod openvpn;
+mod ovpn;
+mod saml;
 mod saml_server;
+mod session;
+mod split_dns;
 mod state_manager;
 mod task;
 
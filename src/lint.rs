@@ -0,0 +1,403 @@
+use crate::ovpn::{Entry, OvpnProfile, Proto, Span};
+use serde::Serialize;
+use std::fmt;
+use std::path::Path;
+
+/// Directives that clash with how this client runs OpenVPN.
+const UNSUPPORTED: &[(&str, &str)] = &[
+    ("management", "the client runs its own management interface"),
+    (
+        "management-hold",
+        "the client runs its own management interface",
+    ),
+    (
+        "management-query-passwords",
+        "the client runs its own management interface",
+    ),
+    ("daemon", "OpenVPN must stay in the foreground"),
+    ("log", "the client reads the output of OpenVPN"),
+    ("log-append", "the client reads the output of OpenVPN"),
+    ("auth-user-pass-verify", "only used on servers"),
+];
+
+/// Directives that may appear once, a later one silently wins.
+const SINGLE: &[&str] = &["proto", "port", "rport", "dev", "ca", "cipher", "verb"];
+
+/// Directives replaced by command line arguments when connecting.
+/// `remote-random-hostname` is followed unless the settings say otherwise.
+const OVERRIDDEN: &[&str] = &["verb"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Info => f.write_str("info"),
+            Severity::Warning => f.write_str("warning"),
+            Severity::Error => f.write_str("error"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Finding {
+    pub severity: Severity,
+    /// Stable identifier of the check, for CI filters.
+    pub code: &'static str,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl Finding {
+    fn new(severity: Severity, code: &'static str, span: Option<Span>, message: String) -> Self {
+        Finding {
+            severity,
+            code,
+            line: span.map(|s| s.line),
+            column: span.map(|s| s.column),
+            message,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub file: String,
+    pub errors: usize,
+    pub warnings: usize,
+    pub findings: Vec<Finding>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.errors == 0
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for finding in &self.findings {
+            match (finding.line, finding.column) {
+                (Some(line), Some(column)) => write!(f, "{}:{}:{}: ", self.file, line, column)?,
+                _ => write!(f, "{}: ", self.file)?,
+            }
+            writeln!(
+                f,
+                "{}[{}]: {}",
+                finding.severity, finding.code, finding.message
+            )?;
+        }
+        write!(f, "{} error(s), {} warning(s)", self.errors, self.warnings)
+    }
+}
+
+/// Checks a profile file without starting OpenVPN.
+pub fn validate_file(path: &Path) -> Report {
+    let findings = match std::fs::read_to_string(path) {
+        Ok(content) => validate(&content),
+        Err(e) => vec![Finding::new(
+            Severity::Error,
+            "unreadable",
+            None,
+            format!("unable to read the profile: {}", e),
+        )],
+    };
+    Report {
+        file: path.display().to_string(),
+        errors: count(&findings, Severity::Error),
+        warnings: count(&findings, Severity::Warning),
+        findings,
+    }
+}
+
+fn count(findings: &[Finding], severity: Severity) -> usize {
+    findings.iter().filter(|f| f.severity == severity).count()
+}
+
+pub fn validate(content: &str) -> Vec<Finding> {
+    let profile = match OvpnProfile::parse(content) {
+        Ok(profile) => profile,
+        Err(e) => {
+            return vec![Finding::new(
+                Severity::Error,
+                "syntax",
+                Some(e.span),
+                e.message,
+            )]
+        }
+    };
+
+    let mut findings = Vec::new();
+    check_remotes(&profile, &mut findings);
+    check_ca(&profile, &mut findings);
+    check_auth(&profile, &mut findings);
+    check_directives(&profile, &mut findings);
+    findings.sort_by_key(|f| (f.line.unwrap_or(0), std::cmp::Reverse(f.severity)));
+    findings
+}
+
+fn check_remotes(profile: &OvpnProfile, findings: &mut Vec<Finding>) {
+    let remotes: Vec<_> = profile.directives_named("remote").collect();
+    if remotes.is_empty() {
+        findings.push(Finding::new(
+            Severity::Error,
+            "missing-remote",
+            None,
+            "no 'remote' directive, there is no endpoint to connect to".to_string(),
+        ));
+    }
+
+    for remote in remotes {
+        if remote.args.is_empty() {
+            findings.push(Finding::new(
+                Severity::Error,
+                "invalid-remote",
+                Some(remote.span),
+                "'remote' without a host".to_string(),
+            ));
+            continue;
+        }
+        if let Some(port) = remote.arg(1) {
+            if !matches!(port.parse::<u16>(), Ok(p) if p > 0) {
+                findings.push(Finding::new(
+                    Severity::Error,
+                    "invalid-remote",
+                    Some(remote.span),
+                    format!("invalid port '{}'", port),
+                ));
+            }
+        }
+        if let Some(proto) = remote.arg(2) {
+            if Proto::parse(proto).is_none() {
+                findings.push(Finding::new(
+                    Severity::Error,
+                    "invalid-remote",
+                    Some(remote.span),
+                    format!("unsupported protocol '{}', use udp or tcp", proto),
+                ));
+            }
+        }
+    }
+
+    if let Some(proto) = profile.directive("proto") {
+        if proto.arg(0).and_then(Proto::parse).is_none() {
+            findings.push(Finding::new(
+                Severity::Error,
+                "invalid-proto",
+                Some(proto.span),
+                format!(
+                    "unsupported protocol '{}', use udp or tcp",
+                    proto.arg(0).unwrap_or_default()
+                ),
+            ));
+        }
+    }
+}
+
+fn check_ca(profile: &OvpnProfile, findings: &mut Vec<Finding>) {
+    let inline = profile
+        .entries
+        .iter()
+        .any(|e| matches!(e, Entry::Block(b) if b.tag == "ca" && !b.body.trim().is_empty()));
+    let file = profile.directive("ca");
+    match (inline, file) {
+        (false, None) => findings.push(Finding::new(
+            Severity::Error,
+            "missing-ca",
+            None,
+            "no <ca> block or 'ca' directive, the server cannot be verified".to_string(),
+        )),
+        (true, Some(file)) => findings.push(Finding::new(
+            Severity::Warning,
+            "conflicting-ca",
+            Some(file.span),
+            "'ca' directive next to an inline <ca> block".to_string(),
+        )),
+        _ => {}
+    }
+}
+
+fn check_auth(profile: &OvpnProfile, findings: &mut Vec<Finding>) {
+    let federated = profile.directive("auth-federate").is_some();
+    let certificate = profile.directive("cert").is_some()
+        || profile
+            .entries
+            .iter()
+            .any(|e| matches!(e, Entry::Block(b) if b.tag == "cert"));
+
+    if !federated {
+        let message = if certificate {
+            "mutual certificate authentication profile, only SAML profiles are supported"
+        } else {
+            "no 'auth-federate' directive, only SAML profiles are supported"
+        };
+        findings.push(Finding::new(
+            Severity::Error,
+            "not-saml",
+            None,
+            message.to_string(),
+        ));
+    } else if let Some(cert) = profile.directive("cert") {
+        findings.push(Finding::new(
+            Severity::Warning,
+            "conflicting-auth",
+            Some(cert.span),
+            "client certificate in a SAML profile".to_string(),
+        ));
+    }
+
+    for auth in profile.directives_named("auth-user-pass") {
+        if let Some(file) = auth.arg(0) {
+            findings.push(Finding::new(
+                Severity::Warning,
+                "auth-user-pass-file",
+                Some(auth.span),
+                format!(
+                    "credentials file '{}' is ignored, the login happens in the browser",
+                    file
+                ),
+            ));
+        }
+    }
+    for retry in profile.directives_named("auth-retry") {
+        if retry.arg(0) == Some("interact") {
+            findings.push(Finding::new(
+                Severity::Info,
+                "removed",
+                Some(retry.span),
+                "'auth-retry interact' is removed when connecting".to_string(),
+            ));
+        }
+    }
+}
+
+fn check_directives(profile: &OvpnProfile, findings: &mut Vec<Finding>) {
+    for directive in profile.directives() {
+        let name = directive.name.as_str();
+        if let Some((_, reason)) = UNSUPPORTED.iter().find(|(n, _)| *n == name) {
+            findings.push(Finding::new(
+                Severity::Error,
+                "unsupported",
+                Some(directive.span),
+                format!("'{}' is not supported: {}", name, reason),
+            ));
+        }
+        if OVERRIDDEN.contains(&name) {
+            findings.push(Finding::new(
+                Severity::Info,
+                "overridden",
+                Some(directive.span),
+                format!("'{}' is replaced by the client settings", name),
+            ));
+        }
+        if name == "dev" && !directive.arg(0).map_or(false, |d| d.starts_with("tun")) {
+            findings.push(Finding::new(
+                Severity::Error,
+                "unsupported",
+                Some(directive.span),
+                "AWS Client VPN only supports 'dev tun'".to_string(),
+            ));
+        }
+    }
+
+    for name in SINGLE {
+        let repeated: Vec<_> = profile.directives_named(name).collect();
+        if let [first, .., last] = repeated.as_slice() {
+            if first.args != last.args {
+                findings.push(Finding::new(
+                    Severity::Warning,
+                    "conflicting",
+                    Some(last.span),
+                    format!("'{}' overrides the one on line {}", name, first.span.line),
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AWS_PROFILE: &str = "client
+dev tun
+proto udp
+remote cvpn-endpoint-0123.prod.clientvpn.eu-west-1.amazonaws.com 443
+remote-random-hostname
+nobind
+<ca>
+-----BEGIN CERTIFICATE-----
+MIIB
+-----END CERTIFICATE-----
+</ca>
+auth-user-pass
+auth-federate
+auth-retry interact
+";
+
+    fn codes(content: &str) -> Vec<(Severity, &'static str, Option<usize>)> {
+        validate(content)
+            .into_iter()
+            .map(|f| (f.severity, f.code, f.line))
+            .collect()
+    }
+
+    #[test]
+    fn accepts_an_aws_profile() {
+        assert_eq!(codes(AWS_PROFILE), [(Severity::Info, "removed", Some(14))]);
+        assert_eq!(
+            codes(&format!("{}verb 3\n", AWS_PROFILE)),
+            [
+                (Severity::Info, "removed", Some(14)),
+                (Severity::Info, "overridden", Some(15)),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_problems_with_their_lines() {
+        let profile = "client\ndev tap\nproto udp\nproto tcp\nremote vpn.example.com 70000\n\
+                       auth-user-pass creds.txt\nmanagement 127.0.0.1 7505\n";
+        assert_eq!(
+            codes(profile),
+            [
+                (Severity::Error, "missing-ca", None),
+                (Severity::Error, "not-saml", None),
+                (Severity::Error, "unsupported", Some(2)),
+                (Severity::Warning, "conflicting", Some(4)),
+                (Severity::Error, "invalid-remote", Some(5)),
+                (Severity::Warning, "auth-user-pass-file", Some(6)),
+                (Severity::Error, "unsupported", Some(7)),
+            ]
+        );
+
+        assert_eq!(
+            codes("client\n<ca>\n"),
+            [(Severity::Error, "syntax", Some(2))]
+        );
+    }
+
+    #[test]
+    fn reports_as_json() {
+        let findings = validate("client\ncert client.crt\n");
+        let report = Report {
+            file: "mutual.ovpn".to_string(),
+            errors: count(&findings, Severity::Error),
+            warnings: count(&findings, Severity::Warning),
+            findings,
+        };
+        assert!(!report.passed());
+        let json: serde_json::Value = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["errors"], 3);
+        assert_eq!(json["findings"][2]["code"], "not-saml");
+        assert_eq!(json["findings"][2]["severity"], "error");
+        assert!(json["findings"][0]["line"].is_null());
+    }
+}
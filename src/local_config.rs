@@ -1,82 +1,80 @@
+use crate::host_dns::AppliedDns;
 use dirs::config_dir;
-use std::fs::{create_dir_all, File};
-use std::io::Read;
-use std::io::Write;
-use std::os::unix::prelude::OsStrExt;
-use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use std::fs::create_dir_all;
+use std::io;
+use std::path::PathBuf;
+
+/// File keeping what the client needs to know across runs, as opposed to the
+/// settings chosen by the user.
+const STATE_FILE: &str = "state.toml";
+
+/// Pid file of versions before `state.toml`, migrated on first read.
+const LEGACY_PID_FILE: &str = "last_ovpn_pid";
+
+#[derive(Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+struct LocalState {
+    /// OpenVPN process of a tunnel that is or may still be up.
+    openvpn_pid: Option<u32>,
+    /// DNS change to undo once that tunnel is down.
+    applied_dns: Option<AppliedDns>,
+}
 
 pub struct LocalConfig {}
 
 impl LocalConfig {
-    pub fn read_last_file() -> Option<PathBuf> {
-        LocalConfig::config_file("last_ovpn")
-            .filter(|f| f.metadata().map(|m| m.len()).unwrap_or(0) > 0)
-            .map(|mut f| {
-                let mut s = String::new();
-                f.read_to_string(&mut s).unwrap();
-                s.replace("\n", "")
-            })
-            .filter(|s| s.len() > 0)
-            .map(|s| PathBuf::from(s))
-            .filter(|p| p.exists())
+    /// Directory of the settings and state files.
+    pub fn dir() -> Option<PathBuf> {
+        config_dir().map(|d| d.join("openaws-vpn-client"))
     }
 
-    pub fn save_last_file<P: AsRef<Path>>(last: P) {
-        let p = LocalConfig::config_file("last_ovpn");
+    pub fn read_last_pid() -> Option<u32> {
+        let dir = LocalConfig::dir()?;
+        let legacy = dir.join(LEGACY_PID_FILE);
+        if legacy.exists() {
+            let pid = std::fs::read_to_string(&legacy)
+                .ok()
+                .and_then(|s| s.trim().parse::<u32>().ok());
+            LocalConfig::save_last_pid(pid);
+            let _ = std::fs::remove_file(legacy);
+            return pid;
+        }
 
-        if let Ok(c) = std::fs::canonicalize(last) {
-            if let Some(mut p) = p {
-                p.set_len(0).unwrap();
-                let os_str = c.into_os_string();
-                let all = os_str.as_bytes();
-                p.write_all(all).unwrap();
-            }
+        LocalConfig::read_state().openvpn_pid
+    }
+
+    pub fn save_last_pid(last: Option<u32>) {
+        let mut state = LocalConfig::read_state();
+        state.openvpn_pid = last;
+        if let Err(e) = LocalConfig::write_state(&state) {
+            eprintln!("Unable to save the OpenVPN pid: {}", e);
         }
     }
 
-    pub fn read_last_pid() -> Option<u32> {
-        LocalConfig::config_file("last_ovpn_pid")
-            .filter(|f| f.metadata().map(|m| m.len()).unwrap_or(0) > 0)
-            .map(|mut f| {
-                let mut s = String::new();
-                f.read_to_string(&mut s).unwrap();
-                s.replace("\n", "")
-            })
-            .filter(|s| s.len() > 0)
-            .map(|s| s.parse::<u32>())
-            .filter(|p| p.is_ok())
-            .map(|p| p.unwrap())
+    pub fn read_applied_dns() -> Option<AppliedDns> {
+        LocalConfig::read_state().applied_dns
     }
 
-    pub fn save_last_pid(last: Option<u32>) {
-        let p = LocalConfig::config_file("last_ovpn_pid");
+    pub fn save_applied_dns(applied: Option<AppliedDns>) -> io::Result<()> {
+        let mut state = LocalConfig::read_state();
+        state.applied_dns = applied;
+        LocalConfig::write_state(&state)
+    }
 
-        if let Some(mut p) = p {
-            p.set_len(0).unwrap();
-            if let Some(last) = last {
-                write!(p, "{}", last).unwrap();
-            } else {
-                write!(p, "").unwrap();
-            }
-        }
+    /// A missing or unreadable file counts as no state, it only matters after a crash.
+    fn read_state() -> LocalState {
+        LocalConfig::dir()
+            .and_then(|d| std::fs::read_to_string(d.join(STATE_FILE)).ok())
+            .and_then(|s| toml::from_str(&s).ok())
+            .unwrap_or_default()
     }
 
-    fn config_file(name: &str) -> Option<File> {
-        config_dir()
-            .map(|d| d.join("openaws-vpn-client"))
-            .map(|d| {
-                if !d.exists() {
-                    create_dir_all(&d).unwrap();
-                }
-                d.join(name)
-            })
-            .map(|d| {
-                File::options()
-                    .write(true)
-                    .read(true)
-                    .create(true)
-                    .open(&d)
-                    .unwrap()
-            })
+    fn write_state(state: &LocalState) -> io::Result<()> {
+        let dir = LocalConfig::dir()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no config directory"))?;
+        create_dir_all(&dir)?;
+        let content =
+            toml::to_string(state).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        std::fs::write(dir.join(STATE_FILE), content)
     }
 }
@@ -1,12 +1,18 @@
+use std::collections::VecDeque;
 use std::sync::{Arc, Mutex};
 
+/// Lines kept in memory unless configured otherwise.
+pub const DEFAULT_RETENTION: usize = 10_000;
+
 #[derive(Clone)]
 pub struct Log {
     view: Arc<View>,
 }
 
 pub struct View {
-    buffer: Arc<Mutex<Vec<String>>>,
+    buffer: Arc<Mutex<VecDeque<String>>>,
+    /// Most lines kept, the oldest are dropped first.
+    retention: Mutex<usize>,
 }
 
 unsafe impl Send for Log {}
@@ -19,7 +25,8 @@ impl Log {
     pub fn new() -> Log {
         Log {
             view: Arc::new(View {
-                buffer: Arc::new(Mutex::new(Vec::new())),
+                buffer: Arc::new(Mutex::new(VecDeque::new())),
+                retention: Mutex::new(DEFAULT_RETENTION),
             }),
         }
     }
@@ -27,17 +34,30 @@ impl Log {
     pub fn append<S: AsRef<str>>(&self, text: S) {
         let text = text.as_ref().to_string();
         println!("{}", text);
-
-        let mut buffer = self.view.buffer.lock().unwrap();
-        buffer.push(text.clone());
+        self.push(text);
     }
 
     pub fn append_process(&self, pid: u32, text: &str) {
         let text = format!("[{}] {}", pid, text);
         println!("{}", text);
+        self.push(text);
+    }
 
+    fn push(&self, text: String) {
+        let retention = *self.view.retention.lock().unwrap();
         let mut buffer = self.view.buffer.lock().unwrap();
-        buffer.push(text.clone());
+        buffer.push_back(text);
+        while buffer.len() > retention {
+            buffer.pop_front();
+        }
+    }
+
+    pub fn set_retention(&self, lines: usize) {
+        *self.view.retention.lock().unwrap() = lines.max(1);
+        let mut buffer = self.view.buffer.lock().unwrap();
+        while buffer.len() > lines.max(1) {
+            buffer.pop_front();
+        }
     }
 
     // New method to get the last log message with a callback
@@ -46,7 +66,7 @@ impl Log {
         F: FnOnce(&str) + Send + 'static,
     {
         let buffer = self.view.buffer.lock().unwrap();
-        if let Some(last) = buffer.last() {
+        if let Some(last) = buffer.back() {
             callback(last);
         } else {
             callback("");
@@ -54,15 +74,17 @@ impl Log {
     }
 
     // New method to get all log messages
+    #[allow(dead_code)]
     pub fn get_all_logs<F>(&self, callback: Box<F>)
     where
         F: FnOnce(&[String]) + Send + 'static,
     {
-        let buffer = self.view.buffer.lock().unwrap();
-        callback(&buffer);
+        let mut buffer = self.view.buffer.lock().unwrap();
+        callback(buffer.make_contiguous());
     }
 
     // New method to clear logs
+    #[allow(dead_code)]
     pub fn clear(&self) {
         let mut buffer = self.view.buffer.lock().unwrap();
         buffer.clear();
@@ -2,23 +2,48 @@ mod app;
 mod cmd;
 mod config;
 mod consts;
+mod daemon;
+mod diagnose;
 mod dns;
+mod error;
 mod ffi; // Add FFI module
+mod host_dns;
+mod lint;
 mod local_config;
 mod log;
+mod management;
 mod manager;
+mod openvpn;
+mod ovpn;
+mod profiles;
+mod saml;
 mod saml_server;
+mod session;
+mod settings;
+mod split_dns;
 mod state_manager;
 mod task;
 
-use crate::app::{State, VpnApp};
-use crate::cmd::kill_openvpn;
+use crate::app::VpnApp;
+use crate::cmd::{kill_openvpn, route_args};
+use crate::config::{
+    parse_doh_url, parse_nameserver, AddressFamily, DnsBackend, DohConfig, PrivilegeHelper,
+    RandomHostname, ReconnectPolicy, SamlListen, SessionPolicy, SplitDns, TransportOverride,
+    MAX_VERBOSITY,
+};
+use crate::lint::validate_file;
 use crate::local_config::LocalConfig;
-use crate::manager::ConnectionManager;
+use crate::manager::{restore_dns, ConnectionManager};
+use crate::openvpn::discover;
+use crate::ovpn::{OvpnProfile, Proto};
+use crate::profiles::{format_secs, Outcome, ProfileError, ProfileStore};
 use crate::saml_server::SamlServer;
-use clap::{App, Arg, SubCommand};
-use std::path::PathBuf;
-use std::rc::Rc;
+use crate::settings::Settings;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use daemon::{Daemon, Request};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Duration;
 
 fn main() {
@@ -35,25 +60,461 @@ fn main() {
                 .help("Sets the OVPN config file to use")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("settings")
+                .long("settings")
+                .value_name("FILE")
+                .help("Settings file to use [default: settings.toml in the config directory]")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("proto")
+                .long("proto")
+                .value_name("PROTO")
+                .help("Overrides the protocol of the profile")
+                .possible_values(&["udp", "tcp"])
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("port")
+                .long("port")
+                .value_name("PORT")
+                .help("Overrides the remote port of the profile")
+                .validator(|v| {
+                    v.parse::<u16>()
+                        .map(|_| ())
+                        .map_err(|_| format!("invalid port '{}'", v))
+                })
+                .takes_value(true)
+                .global(true),
+        )
         .subcommand(
             SubCommand::with_name("connect")
                 .about("Connect to VPN using specified config")
+                .arg(
+                    Arg::with_name("profile")
+                        .value_name("PROFILE")
+                        .help("Name of a stored profile, or a config file")
+                        .conflicts_with("config"),
+                )
                 .arg(
                     Arg::with_name("config")
                         .short("c")
                         .long("config")
                         .value_name("FILE")
-                        .help("Sets the OVPN config file to use")
-                        .takes_value(true)
-                        .required(true),
+                        .help("Sets the OVPN config file to use [default: default_profile of the settings]")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("profile")
+                .about("Manage the stored profiles")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("import")
+                        .about("Store a copy of a config file under a name")
+                        .arg(Arg::with_name("file").value_name("FILE").required(true))
+                        .arg(
+                            Arg::with_name("name")
+                                .long("name")
+                                .value_name("NAME")
+                                .help("Name of the profile [default: file name]")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("force")
+                                .long("force")
+                                .help("Replace a profile with the same name"),
+                        ),
+                )
+                .subcommand(SubCommand::with_name("list").about("List the stored profiles"))
+                .subcommand(
+                    SubCommand::with_name("show")
+                        .about("Show a stored profile")
+                        .arg(Arg::with_name("name").value_name("NAME").required(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name("rm")
+                        .about("Remove a stored profile")
+                        .arg(Arg::with_name("name").value_name("NAME").required(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name("rename")
+                        .about("Rename a stored profile")
+                        .arg(Arg::with_name("name").value_name("NAME").required(true))
+                        .arg(Arg::with_name("new-name").value_name("NEW_NAME").required(true)),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("validate")
+                .about("Check config files for problems without connecting")
+                .arg(
+                    Arg::with_name("file")
+                        .value_name("FILE")
+                        .required(true)
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("Output format [default: text]")
+                        .possible_values(&["text", "json"])
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("routes")
+                .about("Show how a profile routes traffic, without connecting")
+                .arg(
+                    Arg::with_name("profile")
+                        .value_name("PROFILE")
+                        .help("Name of a stored profile, or a config file")
+                        .conflicts_with("config"),
+                )
+                .arg(
+                    Arg::with_name("config")
+                        .short("c")
+                        .long("config")
+                        .value_name("FILE")
+                        .help("Sets the OVPN config file to use [default: default_profile of the settings]")
+                        .takes_value(true),
                 ),
         )
         .subcommand(SubCommand::with_name("disconnect").about("Disconnect from VPN"))
         .subcommand(SubCommand::with_name("status").about("Show connection status"))
+        .subcommand(SubCommand::with_name("logs").about("Show the daemon log"))
+        .subcommand(
+            SubCommand::with_name("debug")
+                .about("Inspect the internals of the daemon")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("dns-cache")
+                        .about("Show the cached endpoint addresses and cache statistics"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("daemon")
+                .about("Run in the background and accept commands from the other subcommands")
+                .arg(
+                    Arg::with_name("saml-address")
+                        .long("saml-address")
+                        .value_name("IP")
+                        .help("Address the SAML callback server listens on [default: 127.0.0.1 and ::1]")
+                        .validator(|v| {
+                            v.parse::<IpAddr>()
+                                .map(|_| ())
+                                .map_err(|_| format!("invalid address '{}'", v))
+                        })
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("saml-port")
+                        .long("saml-port")
+                        .value_name("PORT")
+                        .help("Port the SAML callback server listens on [default: 35001]")
+                        .validator(|v| {
+                            v.parse::<u16>()
+                                .map(|_| ())
+                                .map_err(|_| format!("invalid port '{}'", v))
+                        })
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("max-session-hours")
+                        .long("max-session-hours")
+                        .value_name("HOURS")
+                        .help("Maximum session length of the Client VPN endpoint, 0 for none [default: 24]")
+                        .validator(|v| parse_limit(&v, HOUR).map(|_| ()))
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("renew-session")
+                        .long("renew-session")
+                        .help("Log in again in the background before the session ends, restarting the tunnel"),
+                )
+                .arg(
+                    Arg::with_name("inactive-minutes")
+                        .long("inactive-minutes")
+                        .value_name("MINUTES")
+                        .help("Close the tunnel after this long without traffic, 0 for never [default: 60]")
+                        .validator(|v| parse_limit(&v, MINUTE).map(|_| ()))
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("reconnect-attempts")
+                        .long("reconnect-attempts")
+                        .value_name("N")
+                        .help("Reconnects after the tunnel drops before giving up, 0 disables [default: 5]")
+                        .validator(|v| {
+                            v.parse::<u32>()
+                                .map(|_| ())
+                                .map_err(|_| format!("invalid number '{}'", v))
+                        })
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("openvpn")
+                        .long("openvpn")
+                        .value_name("PATH")
+                        .help("OpenVPN executable with the AWS SAML patch [default: $OPENAWS_OPENVPN, bundled, then PATH]")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("privilege-helper")
+                        .long("privilege-helper")
+                        .value_name("HELPER")
+                        .help("How OpenVPN gets root privileges [default: auto]")
+                        .possible_values(&["auto", "sudo", "pkexec", "none"])
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("address-family")
+                        .long("address-family")
+                        .value_name("FAMILY")
+                        .help("Endpoint addresses to connect to, happy-eyeballs tries IPv6 and IPv4 alternately [default: ipv4]")
+                        .possible_values(&["ipv4", "ipv6", "happy-eyeballs"])
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("nameserver")
+                        .long("nameserver")
+                        .value_name("ADDRESS")
+                        .help("Nameserver to look the endpoint up with, as address or address:port [default: system]")
+                        .validator(|v| {
+                            parse_nameserver(&v)
+                                .map(|_| ())
+                                .ok_or(format!("invalid nameserver '{}'", v))
+                        })
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("doh-url")
+                        .long("doh-url")
+                        .value_name("URL")
+                        .help("DNS-over-HTTPS service to look the endpoint up with instead of the nameservers")
+                        .validator(|v| {
+                            parse_doh_url(&v)
+                                .map(|_| ())
+                                .ok_or(format!("invalid DoH URL '{}', use https://", v))
+                        })
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("doh-bootstrap")
+                        .long("doh-bootstrap")
+                        .value_name("IP")
+                        .help("Address of the DNS-over-HTTPS service, its name is looked up otherwise")
+                        .requires("doh-url")
+                        .validator(|v| {
+                            v.parse::<IpAddr>()
+                                .map(|_| ())
+                                .map_err(|_| format!("invalid address '{}'", v))
+                        })
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("dns")
+                        .long("dns")
+                        .value_name("BACKEND")
+                        .help("How the DNS servers pushed by the VPN are applied to the host [default: auto]")
+                        .possible_values(&["auto", "resolved", "resolvconf", "file", "none"])
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("random-hostname")
+                        .long("random-hostname")
+                        .value_name("WHEN")
+                        .help("Look endpoints up under a random subdomain, profile follows remote-random-hostname [default: profile]")
+                        .possible_values(&["always", "never", "profile"])
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("verb")
+                        .long("verb")
+                        .value_name("LEVEL")
+                        .help("OpenVPN log verbosity, 0 to 11 [default: 3]")
+                        .validator(|v| match v.parse::<u8>() {
+                            Ok(v) if v <= MAX_VERBOSITY => Ok(()),
+                            _ => Err(format!("invalid verbosity '{}'", v)),
+                        })
+                        .takes_value(true),
+                ),
+        )
         .get_matches();
 
+    let settings = match Settings::load(matches.value_of("settings").map(Path::new)) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("Unable to load the settings: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(matches) = matches.subcommand_matches("daemon") {
+        if let Err(e) = run_daemon(matches, settings) {
+            eprintln!("Unable to start the daemon: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("profile") {
+        if let Err(e) = run_profile(matches) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("routes") {
+        if !run_routes(matches, &settings) {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("validate") {
+        if !run_validate(matches) {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let mut profile = None;
+    let request = if let Some(matches) = matches.subcommand_matches("connect") {
+        match connect_request(matches, &settings) {
+            Some((request, name)) => {
+                profile = name;
+                request
+            }
+            None => std::process::exit(1),
+        }
+    } else if matches.subcommand_matches("disconnect").is_some() {
+        Request::Disconnect
+    } else if matches.subcommand_matches("status").is_some() {
+        Request::Status
+    } else if matches.subcommand_matches("logs").is_some() {
+        Request::Logs
+    } else if matches.subcommand_matches("debug").is_some() {
+        Request::DnsCache
+    } else if matches.value_of("config").is_some() {
+        // If only config is provided with no subcommand, treat it like connect
+        match connect_request(&matches, &settings) {
+            Some((request, _)) => request,
+            None => std::process::exit(1),
+        }
+    } else {
+        println!("No command specified. Use --help for usage information.");
+        return;
+    };
+
+    let result = daemon::call(&request);
+    if let (Some(name), Ok(connected)) = (&profile, &result) {
+        let outcome = match connected {
+            true => Outcome::Connected,
+            false => Outcome::Failed,
+        };
+        if let Err(e) = ProfileStore::open().record(name, outcome) {
+            eprintln!("Unable to update profile '{}': {}", name, e);
+        }
+    }
+
+    match result {
+        Ok(true) => {}
+        Ok(false) => std::process::exit(1),
+        Err(e) => {
+            eprintln!(
+                "Unable to reach the daemon at {}: {}",
+                daemon::socket_path().display(),
+                e
+            );
+            eprintln!("Start it with `openaws-vpn-client daemon`");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Flags given to the daemon take precedence over the settings file.
+fn run_daemon(matches: &ArgMatches, settings: Settings) -> std::io::Result<()> {
+    let daemon = Daemon::bind()?;
+    let openvpn = matches
+        .value_of("openvpn")
+        .map(PathBuf::from)
+        .or_else(|| settings.openvpn.clone());
+
+    // Connecting checks again, this only reports a problem early
+    match discover(openvpn.as_deref()) {
+        Ok(openvpn) => println!("Using OpenVPN {}", openvpn),
+        Err(e) => eprintln!("Warning: {}", e),
+    }
+
     // Initialize the VPN application
-    let vpn_app = Rc::new(VpnApp::new());
+    let vpn_app = Arc::new(VpnApp::new());
+    vpn_app.log.set_retention(settings.logs.max_lines);
+    vpn_app
+        .config
+        .set_saml_listen(saml_listen(matches, &settings));
+    vpn_app
+        .config
+        .set_reconnect_policy(reconnect_policy(matches, &settings));
+    vpn_app.config.set_session_policy(session_policy(matches));
+    vpn_app.config.set_openvpn_path(openvpn);
+    vpn_app.config.set_privilege_helper(
+        matches
+            .value_of("privilege-helper")
+            .and_then(PrivilegeHelper::parse)
+            .unwrap_or(settings.privilege_helper),
+    );
+    vpn_app.config.set_verbosity(
+        matches
+            .value_of("verb")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(settings.verbosity),
+    );
+    vpn_app.config.set_address_family(
+        matches
+            .value_of("address-family")
+            .and_then(AddressFamily::parse)
+            .unwrap_or(settings.address_family),
+    );
+    vpn_app.config.set_random_hostname(
+        matches
+            .value_of("random-hostname")
+            .and_then(RandomHostname::parse)
+            .unwrap_or(settings.random_hostname),
+    );
+    let mut resolver = settings.resolver_config();
+    if let Some(servers) = matches.values_of("nameserver") {
+        resolver.nameservers = servers.filter_map(parse_nameserver).collect();
+    }
+    if let Some(url) = matches.value_of("doh-url").and_then(parse_doh_url) {
+        resolver.doh = Some(DohConfig {
+            url,
+            bootstrap: matches
+                .value_of("doh-bootstrap")
+                .and_then(|v| v.parse().ok()),
+        });
+    }
+    vpn_app.config.set_resolver(resolver);
+    // The domains come with each connect, from the settings of its profile
+    vpn_app.config.set_split_dns(SplitDns {
+        domains: Vec::new(),
+        forwarder: settings.dns_forwarder,
+    });
+    vpn_app.config.set_dns_backend(
+        matches
+            .value_of("dns")
+            .and_then(DnsBackend::parse)
+            .unwrap_or(settings.dns_backend),
+    );
+    settings.hooks.watch(&vpn_app);
 
     // Setup connection manager
     let connection_manager = ConnectionManager::new();
@@ -68,122 +529,298 @@ fn main() {
     if let Some(p) = LocalConfig::read_last_pid() {
         println!("Last OpenVPN session (PID: {}) was not closed properly", p);
         println!("Killing it in 5 seconds...");
+        let privilege = vpn_app.config.privilege_helper();
         std::thread::spawn(move || {
             std::thread::sleep(Duration::from_secs(5));
-            kill_openvpn(p);
+            kill_openvpn(p, privilege);
         });
     }
+    if let Some(applied) = host_dns::applied() {
+        println!("Restoring the DNS changed by the last session: {}", applied);
+        restore_dns(&vpn_app.log, vpn_app.config.privilege_helper());
+    }
 
-    // Handle command-line arguments
-    if let Some(matches) = matches.subcommand_matches("connect") {
-        if let Some(config_file) = matches.value_of("config") {
-            let config_path = PathBuf::from(config_file);
-            if config_path.exists() {
-                println!("Connecting using config: {}", config_file);
-                vpn_app.config.save_config(&config_path);
-                vpn_app.dns.resolve_addresses();
-
-                // Get the connection manager and initiate connection
-                let manager = vpn_app.connection_manager.lock().unwrap();
-                if let Some(ref manager) = *manager {
-                    manager.connect();
-
-                    // Wait for connection to complete or fail
-                    let mut attempts = 0;
-                    loop {
-                        let state = {
-                            let app = vpn_app.clone();
-                            let state_manager = app.state.lock().unwrap();
-                            if let Some(ref state_manager) = *state_manager {
-                                *state_manager.state.borrow()
-                            } else {
-                                break;
-                            }
-                        };
-
-                        match state {
-                            State::Connected => {
-                                println!("Successfully connected to VPN");
-
-                                loop {
-                                    std::thread::sleep(Duration::from_secs(1));
-                                }
-
-                                //break;
-                            }
-                            State::Disconnected => {
-                                if attempts > 0 {
-                                    // If we've looped at least once
-                                    println!("Failed to connect to VPN");
-                                    break;
-                                }
-                            }
-                            _ => {}
-                        }
-
-                        attempts += 1;
-                        if attempts > 120 {
-                            // Wait up to 2 minutes (120 seconds)
-                            println!("Connection timed out");
-                            break;
-                        }
-                        std::thread::sleep(Duration::from_secs(1));
-                    }
-                }
-            } else {
-                eprintln!("Config file does not exist: {}", config_file);
+    daemon.run(vpn_app)
+}
+
+/// The daemon runs in its own directory, so the profile is sent as an absolute path.
+/// Returns the name of the stored profile used, if any.
+fn connect_request(matches: &ArgMatches, settings: &Settings) -> Option<(Request, Option<String>)> {
+    let (config, name) = find_profile(matches, settings)?;
+    let key = settings_key(&config, name.as_deref());
+    Some((
+        Request::Connect {
+            config,
+            transport: transport_override(matches),
+            split_dns: settings.split_dns(&key).domains,
+            routes: settings.route_policy(&key),
+        },
+        name,
+    ))
+}
+
+/// The absolute path of the profile to use and its stored name, if any.
+/// Unless `--config` is given, a stored profile of that name wins over a file.
+fn find_profile(matches: &ArgMatches, settings: &Settings) -> Option<(PathBuf, Option<String>)> {
+    let (config_file, lookup) = match (matches.value_of("config"), matches.value_of("profile")) {
+        (Some(file), _) => (PathBuf::from(file), false),
+        (None, Some(profile)) => (PathBuf::from(profile), true),
+        (None, None) => match &settings.default_profile {
+            Some(profile) => (profile.clone(), true),
+            None => {
+                eprintln!("No profile given and no default_profile in the settings");
+                return None;
             }
+        },
+    };
+
+    let stored = config_file
+        .to_str()
+        .filter(|_| lookup)
+        .and_then(|name| ProfileStore::open().get(name).ok());
+    let (config_file, name) = match stored {
+        Some(profile) => (profile.path, Some(profile.name)),
+        None => (config_file, None),
+    };
+    let config = match std::fs::canonicalize(&config_file) {
+        Ok(path) => path,
+        Err(_) => {
+            eprintln!(
+                "No stored profile or config file named {}",
+                config_file.display()
+            );
+            return None;
+        }
+    };
+
+    Some((config, name))
+}
+
+/// Settings of a profile are found by its stored name or its file name.
+fn settings_key(config: &Path, name: Option<&str>) -> String {
+    match name {
+        Some(name) => name.to_string(),
+        None => config
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default(),
+    }
+}
+
+/// Prints the routing plan of a profile. Returns whether the profile was found.
+fn run_routes(matches: &ArgMatches, settings: &Settings) -> bool {
+    let (config, name) = match find_profile(matches, settings) {
+        Some(found) => found,
+        None => return false,
+    };
+    let key = settings_key(&config, name.as_deref());
+    let policy = settings.route_policy(&key);
+
+    println!("Profile: {} ({})", key, config.display());
+    let pushed = if policy.accepts_pushed() {
+        "accepted"
+    } else if policy.no_pull {
+        "ignored with route-nopull, the pushed DNS options as well"
+    } else {
+        "ignored, only the included networks go through the VPN"
+    };
+    println!("Pushed routes: {}", pushed);
+    if let Ok(profile) = std::fs::read_to_string(&config).map(|c| OvpnProfile::parse(&c)) {
+        for route in profile.iter().flat_map(|p| p.directives_named("route")) {
+            println!("Profile route: {}", route.args.join(" "));
         }
-    } else if let Some(_) = matches.subcommand_matches("disconnect") {
-        println!("Disconnecting from VPN...");
-        let manager = vpn_app.connection_manager.lock().unwrap();
-        if let Some(ref manager) = *manager {
-            manager.disconnect();
-            println!("Disconnected");
-        }
-    } else if let Some(_) = matches.subcommand_matches("status") {
-        let state = {
-            let app = vpn_app.clone();
-            let state_manager = app.state.lock().unwrap();
-            if let Some(ref state_manager) = *state_manager {
-                *state_manager.state.borrow()
-            } else {
-                State::Disconnected
+    }
+    for net in policy.include.iter().chain(&policy.routes) {
+        println!("Through the VPN: {}", net);
+    }
+    for net in &policy.exclude {
+        println!("Outside the VPN: {}", net);
+    }
+
+    let args: Vec<String> = route_args(&policy)
+        .iter()
+        .map(|a| a.to_string_lossy().to_string())
+        .map(|a| match a.contains(' ') {
+            true => format!("\"{}\"", a),
+            false => a,
+        })
+        .collect();
+    if !args.is_empty() {
+        println!("OpenVPN options: {}", args.join(" "));
+    }
+    true
+}
+
+/// Prints a report per file, returns whether all of them are free of errors.
+fn run_validate(matches: &ArgMatches) -> bool {
+    let reports: Vec<_> = matches
+        .values_of("file")
+        .into_iter()
+        .flatten()
+        .map(|f| validate_file(Path::new(f)))
+        .collect();
+
+    if matches.value_of("format") == Some("json") {
+        match serde_json::to_string_pretty(&reports) {
+            Ok(json) => println!("{}", json),
+            Err(e) => {
+                eprintln!("Unable to write the report: {}", e);
+                return false;
             }
-        };
+        }
+    } else {
+        for report in &reports {
+            println!("{}", report);
+        }
+    }
+    reports.iter().all(|r| r.passed())
+}
 
-        match state {
-            State::Connected => println!("Status: Connected"),
-            State::Connecting => println!("Status: Connecting"),
-            State::Disconnected => println!("Status: Disconnected"),
+fn run_profile(matches: &ArgMatches) -> Result<(), ProfileError> {
+    let store = ProfileStore::open();
+    match matches.subcommand() {
+        ("import", Some(matches)) => {
+            let file = Path::new(matches.value_of("file").unwrap_or_default());
+            let name = match matches.value_of("name") {
+                Some(name) => name.to_string(),
+                None => file
+                    .file_stem()
+                    .map(|s| s.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+            };
+            let profile = store.import(file, &name, matches.is_present("force"))?;
+            println!("Imported {} as '{}'", file.display(), profile.name);
         }
-    } else if let Some(config_file) = matches.value_of("config") {
-        // If only config is provided with no subcommand, treat it like connect
-        let config_path = PathBuf::from(config_file);
-        if config_path.exists() {
-            println!("Connecting using config: {}", config_file);
-            vpn_app.config.save_config(&config_path);
-            vpn_app.dns.resolve_addresses();
-
-            let manager = vpn_app.connection_manager.lock().unwrap();
-            if let Some(ref manager) = *manager {
-                manager.connect();
-
-                // Keep the main thread alive to maintain connection
-                loop {
-                    std::thread::sleep(Duration::from_secs(1));
+        ("list", _) => {
+            for profile in store.list()? {
+                let last_used = profile
+                    .meta
+                    .last_used
+                    .map(format_secs)
+                    .unwrap_or_else(|| "never used".to_string());
+                match profile.meta.last_result {
+                    Some(result) => println!("{}\t{}\t{}", profile.name, last_used, result),
+                    None => println!("{}\t{}", profile.name, last_used),
                 }
             }
-        } else {
-            eprintln!("Config file does not exist: {}", config_file);
         }
-    } else {
-        println!("No command specified. Use --help for usage information.");
+        ("show", Some(matches)) => {
+            let profile = store.get(matches.value_of("name").unwrap_or_default())?;
+            println!("Name: {}", profile.name);
+            println!("Path: {}", profile.path.display());
+            let changed = match profile.source_changed() {
+                Some(true) => " (changed since import)",
+                Some(false) => "",
+                None => " (missing)",
+            };
+            println!("Source: {}{}", profile.meta.source.display(), changed);
+            println!("Checksum: sha256:{}", profile.meta.checksum);
+            println!("Imported: {}", format_secs(profile.meta.imported));
+            if let Some(last_used) = profile.meta.last_used {
+                println!("Last used: {}", format_secs(last_used));
+            }
+            if let Some(result) = profile.meta.last_result {
+                println!("Last result: {}", result);
+            }
+            for remote in profile.remotes()? {
+                println!("Remote: {}", remote);
+            }
+        }
+        ("rm", Some(matches)) => {
+            let name = matches.value_of("name").unwrap_or_default();
+            store.remove(name)?;
+            println!("Removed '{}'", name);
+        }
+        ("rename", Some(matches)) => {
+            let name = matches.value_of("name").unwrap_or_default();
+            let profile = store.rename(name, matches.value_of("new-name").unwrap_or_default())?;
+            println!("Renamed '{}' to '{}'", name, profile.name);
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn saml_listen(matches: &ArgMatches, settings: &Settings) -> SamlListen {
+    let mut listen = settings.saml_listen();
+    if let Some(addresses) = matches.values_of("saml-address") {
+        listen.addresses = addresses.filter_map(|a| a.parse().ok()).collect();
     }
+    if let Some(port) = matches.value_of("saml-port").and_then(|p| p.parse().ok()) {
+        listen.port = port;
+    }
+    listen
+}
+
+fn reconnect_policy(matches: &ArgMatches, settings: &Settings) -> ReconnectPolicy {
+    let mut policy = settings.reconnect_policy();
+    if let Some(n) = matches
+        .value_of("reconnect-attempts")
+        .and_then(|n| n.parse().ok())
+    {
+        policy.max_attempts = n;
+    }
+    policy
+}
+
+fn session_policy(matches: &ArgMatches) -> SessionPolicy {
+    let mut policy = SessionPolicy::default();
+    if let Some(max_session) = matches
+        .value_of("max-session-hours")
+        .and_then(|h| parse_limit(h, HOUR).ok())
+    {
+        policy.max_session = max_session;
+    }
+    policy.renew = matches.is_present("renew-session");
+    if let Some(inactive_timeout) = matches
+        .value_of("inactive-minutes")
+        .and_then(|m| parse_limit(m, MINUTE).ok())
+    {
+        policy.inactive_timeout = inactive_timeout;
+    }
+    policy
+}
+
+const MINUTE: Duration = Duration::from_secs(60);
+const HOUR: Duration = Duration::from_secs(3600);
+
+/// Longest session limit accepted, added to the current time without overflowing.
+const MAX_LIMIT: Duration = Duration::from_secs(365 * 24 * 3600);
+
+/// Parses a session limit given in `unit`s, 0 for none.
+fn parse_limit(value: &str, unit: Duration) -> Result<Option<Duration>, String> {
+    let count = value
+        .parse::<u64>()
+        .map_err(|_| format!("invalid number '{}'", value))?;
+    let limit = u32::try_from(count)
+        .ok()
+        .and_then(|count| unit.checked_mul(count));
+    match limit {
+        _ if count == 0 => Ok(None),
+        Some(limit) if limit <= MAX_LIMIT => Ok(Some(limit)),
+        _ => Err(format!("'{}' is longer than a year", value)),
+    }
+}
+
+fn transport_override(matches: &ArgMatches) -> TransportOverride {
+    TransportOverride {
+        proto: matches.value_of("proto").and_then(Proto::parse),
+        port: matches.value_of("port").and_then(|p| p.parse::<u16>().ok()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // Clean up when exiting
-    let manager = vpn_app.connection_manager.lock().unwrap();
-    if let Some(manager) = manager.as_ref() {
-        manager.force_disconnect();
+    #[test]
+    fn session_limits_are_bounded() {
+        assert_eq!(parse_limit("0", HOUR), Ok(None));
+        assert_eq!(parse_limit("24", HOUR), Ok(Some(24 * HOUR)));
+        assert_eq!(parse_limit("8760", HOUR), Ok(Some(MAX_LIMIT)));
+        assert!(parse_limit("8761", HOUR).is_err());
+        assert!(parse_limit("5124095576030431", HOUR).is_err());
+        assert!(parse_limit(&u64::MAX.to_string(), MINUTE).is_err());
+        assert!(parse_limit("-1", MINUTE).is_err());
     }
 }
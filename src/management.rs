@@ -0,0 +1,371 @@
+use std::ffi::OsString;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use temp_dir::TempDir;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::unix::OwnedWriteHalf;
+use tokio::net::UnixListener;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+/// Called for every event received from OpenVPN's management interface.
+pub type EventHandler = Arc<dyn Fn(&ManagementEvent) + Send + Sync>;
+
+/// A real-time notification or command reply from the management interface.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ManagementEvent {
+    State(StateEvent),
+    Password(PasswordEvent),
+    ByteCount {
+        bytes_in: u64,
+        bytes_out: u64,
+    },
+    Log {
+        time: u64,
+        flags: String,
+        message: String,
+    },
+    Fatal(String),
+    Hold(String),
+    Info(String),
+    Success(String),
+    Error(String),
+    Other {
+        kind: String,
+        payload: String,
+    },
+}
+
+/// `>STATE:<time>,<name>,<description>,<local ip>,<remote ip>,...`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StateEvent {
+    pub time: u64,
+    pub name: String,
+    pub description: String,
+    pub local_ip: String,
+    pub remote_ip: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PasswordEvent {
+    /// `>PASSWORD:Need 'Auth' username/password`
+    Need {
+        realm: String,
+    },
+    /// `>PASSWORD:Verification Failed: 'Auth' ['CRV1:...']`
+    VerificationFailed {
+        realm: String,
+        challenge: Option<Challenge>,
+    },
+    Other(String),
+}
+
+/// Dynamic challenge sent by the server, `CRV1:<flags>:<state id>:<username>:<text>`.
+/// For AWS Client VPN the text is the SAML login URL.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Challenge {
+    pub flags: String,
+    pub state_id: String,
+    pub username: String,
+    pub text: String,
+}
+
+impl Challenge {
+    pub fn parse(value: &str) -> Option<Challenge> {
+        let mut parts = value.strip_prefix("CRV1:")?.splitn(4, ':');
+        Some(Challenge {
+            flags: parts.next()?.to_string(),
+            state_id: parts.next()?.to_string(),
+            username: parts.next()?.to_string(),
+            text: parts.next()?.to_string(),
+        })
+    }
+}
+
+impl ManagementEvent {
+    pub fn parse(line: &str) -> ManagementEvent {
+        let line = line.trim_end_matches(['\r', '\n']);
+
+        if let Some(rest) = line.strip_prefix("SUCCESS:") {
+            return ManagementEvent::Success(rest.trim().to_string());
+        }
+        if let Some(rest) = line.strip_prefix("ERROR:") {
+            return ManagementEvent::Error(rest.trim().to_string());
+        }
+
+        let (kind, payload) = match line.strip_prefix('>').and_then(|l| l.split_once(':')) {
+            Some(v) => v,
+            None => {
+                return ManagementEvent::Other {
+                    kind: String::new(),
+                    payload: line.to_string(),
+                }
+            }
+        };
+
+        let parsed = match kind {
+            "STATE" => parse_state(payload).map(ManagementEvent::State),
+            "PASSWORD" => Some(ManagementEvent::Password(parse_password(payload))),
+            "BYTECOUNT" => parse_bytecount(payload),
+            "LOG" => parse_log(payload),
+            "FATAL" => Some(ManagementEvent::Fatal(payload.to_string())),
+            "HOLD" => Some(ManagementEvent::Hold(payload.to_string())),
+            "INFO" => Some(ManagementEvent::Info(payload.to_string())),
+            _ => None,
+        };
+
+        parsed.unwrap_or_else(|| ManagementEvent::Other {
+            kind: kind.to_string(),
+            payload: payload.to_string(),
+        })
+    }
+}
+
+fn parse_state(payload: &str) -> Option<StateEvent> {
+    let mut parts = payload.split(',');
+    Some(StateEvent {
+        time: parts.next()?.parse().ok()?,
+        name: parts.next()?.to_string(),
+        description: parts.next().unwrap_or("").to_string(),
+        local_ip: parts.next().unwrap_or("").to_string(),
+        remote_ip: parts.next().unwrap_or("").to_string(),
+    })
+}
+
+fn parse_bytecount(payload: &str) -> Option<ManagementEvent> {
+    let (bytes_in, bytes_out) = payload.split_once(',')?;
+    Some(ManagementEvent::ByteCount {
+        bytes_in: bytes_in.parse().ok()?,
+        bytes_out: bytes_out.parse().ok()?,
+    })
+}
+
+fn parse_log(payload: &str) -> Option<ManagementEvent> {
+    let mut parts = payload.splitn(3, ',');
+    Some(ManagementEvent::Log {
+        time: parts.next()?.parse().ok()?,
+        flags: parts.next()?.to_string(),
+        message: parts.next().unwrap_or("").to_string(),
+    })
+}
+
+fn parse_password(payload: &str) -> PasswordEvent {
+    if let Some(rest) = payload.strip_prefix("Need ") {
+        if let Some(realm) = quoted_realm(rest) {
+            return PasswordEvent::Need { realm };
+        }
+    }
+
+    if let Some(rest) = payload.strip_prefix("Verification Failed: ") {
+        if let Some(realm) = quoted_realm(rest) {
+            let challenge = rest
+                .find("['")
+                .and_then(|start| {
+                    let reason = &rest[start + 2..];
+                    reason.rfind("']").map(|end| &reason[..end])
+                })
+                .and_then(Challenge::parse);
+            return PasswordEvent::VerificationFailed { realm, challenge };
+        }
+    }
+
+    PasswordEvent::Other(payload.to_string())
+}
+
+fn quoted_realm(text: &str) -> Option<String> {
+    let rest = text.strip_prefix('\'')?;
+    rest.find('\'').map(|end| rest[..end].to_string())
+}
+
+/// Quotes a command parameter the way the management interface parses it.
+fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Unix socket OpenVPN connects back to (`--management-client`). Credentials are
+/// sent over it, so it lives in a temporary directory only the current user can
+/// enter, removed together with the listener.
+pub struct ManagementListener {
+    dir: TempDir,
+    path: PathBuf,
+    listener: UnixListener,
+}
+
+impl ManagementListener {
+    pub fn bind() -> io::Result<ManagementListener> {
+        let dir = TempDir::with_prefix("openaws-vpn-client-")?;
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o700))?;
+        let path = dir.child("management.sock");
+        let listener = UnixListener::bind(&path)?;
+
+        Ok(ManagementListener {
+            dir,
+            path,
+            listener,
+        })
+    }
+
+    /// OpenVPN options that make it connect to this listener, wait for
+    /// `hold release` and ask for credentials over the socket.
+    pub fn args(&self) -> Vec<OsString> {
+        vec![
+            "--management".into(),
+            self.path.clone().into(),
+            "unix".into(),
+            "--management-client".into(),
+            "--management-hold".into(),
+            "--management-query-passwords".into(),
+        ]
+    }
+
+    pub async fn accept(self, timeout: Duration) -> io::Result<ManagementClient> {
+        let (stream, _) = tokio::time::timeout(timeout, self.listener.accept())
+            .await
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "OpenVPN did not connect to the management socket",
+                )
+            })??;
+
+        let (read, writer) = stream.into_split();
+        let (tx, events) = unbounded_channel();
+
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(read).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if tx.send(ManagementEvent::parse(&line)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(ManagementClient {
+            writer,
+            events,
+            _dir: self.dir,
+        })
+    }
+}
+
+/// Connection to a running OpenVPN process over its management interface.
+pub struct ManagementClient {
+    writer: OwnedWriteHalf,
+    events: UnboundedReceiver<ManagementEvent>,
+    _dir: TempDir,
+}
+
+impl ManagementClient {
+    /// Next event, or `None` once OpenVPN closed the connection.
+    pub async fn next_event(&mut self) -> Option<ManagementEvent> {
+        self.events.recv().await
+    }
+
+    pub async fn command(&mut self, command: &str) -> io::Result<()> {
+        self.writer.write_all(command.as_bytes()).await?;
+        self.writer.write_all(b"\n").await?;
+        self.writer.flush().await
+    }
+
+    /// Turns on real-time state and traffic notifications and lets OpenVPN
+    /// leave the initial hold.
+    pub async fn start(&mut self) -> io::Result<()> {
+        self.command("state on").await?;
+        self.command("bytecount 5").await?;
+        self.command("hold release").await
+    }
+
+    pub async fn send_credentials(
+        &mut self,
+        realm: &str,
+        username: &str,
+        password: &str,
+    ) -> io::Result<()> {
+        self.command(&format!("username {} {}", quote(realm), quote(username)))
+            .await?;
+        self.command(&format!("password {} {}", quote(realm), quote(password)))
+            .await
+    }
+
+    pub async fn terminate(&mut self) -> io::Result<()> {
+        self.command("signal SIGTERM").await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_state() {
+        assert_eq!(
+            ManagementEvent::parse(">STATE:1700000000,CONNECTED,SUCCESS,10.0.0.2,192.0.2.10,443,,"),
+            ManagementEvent::State(StateEvent {
+                time: 1700000000,
+                name: "CONNECTED".to_string(),
+                description: "SUCCESS".to_string(),
+                local_ip: "10.0.0.2".to_string(),
+                remote_ip: "192.0.2.10".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn parses_saml_challenge() {
+        let line = ">PASSWORD:Verification Failed: 'Auth' ['CRV1:R:instance-1/123/abc:b'':https://portal.sso.example.com/saml?x=1']";
+
+        assert_eq!(
+            ManagementEvent::parse(line),
+            ManagementEvent::Password(PasswordEvent::VerificationFailed {
+                realm: "Auth".to_string(),
+                challenge: Some(Challenge {
+                    flags: "R".to_string(),
+                    state_id: "instance-1/123/abc".to_string(),
+                    username: "b''".to_string(),
+                    text: "https://portal.sso.example.com/saml?x=1".to_string(),
+                }),
+            })
+        );
+    }
+
+    #[test]
+    fn parses_other_notifications() {
+        assert_eq!(
+            ManagementEvent::parse(">PASSWORD:Need 'Auth' username/password"),
+            ManagementEvent::Password(PasswordEvent::Need {
+                realm: "Auth".to_string()
+            })
+        );
+        assert_eq!(
+            ManagementEvent::parse(">BYTECOUNT:1024,2048"),
+            ManagementEvent::ByteCount {
+                bytes_in: 1024,
+                bytes_out: 2048
+            }
+        );
+        assert_eq!(
+            ManagementEvent::parse(">FATAL:Cannot open TUN/TAP dev"),
+            ManagementEvent::Fatal("Cannot open TUN/TAP dev".to_string())
+        );
+        assert_eq!(
+            ManagementEvent::parse("SUCCESS: hold release succeeded"),
+            ManagementEvent::Success("hold release succeeded".to_string())
+        );
+    }
+
+    #[test]
+    fn quotes_parameters() {
+        assert_eq!(quote(r#"a "b" \c"#), r#""a \"b\" \\c""#);
+    }
+}
@@ -1,54 +1,181 @@
-use crate::app::{State, VpnApp};
-use crate::cmd::run_ovpn;
-use crate::config::Pwd;
-use crate::task::OavcTask;
+use crate::app::VpnApp;
+use crate::cmd::{connect_ovpn, run_ovpn, Launch, OvpnExit, ProcessInfo};
+use crate::config::{Candidate, DnsBackend, PrivilegeHelper, Pwd, SplitDns};
+use crate::diagnose::Diagnosis;
+use crate::error::ConnectError;
+use crate::host_dns::{self, PushedDns, TunnelInfo};
+use crate::log::Log;
+use crate::management::{EventHandler, ManagementEvent};
+use crate::openvpn::discover;
+use crate::saml::Assertion;
+use crate::saml_server::Saml;
+use crate::session::{SessionClock, SessionEnd};
+use crate::split_dns::{self, Upstreams};
+use crate::state_manager::State;
+use crate::task::{OavcProcessTask, OavcTask};
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::SocketAddr;
 use std::ops::Deref;
-use std::rc::{Rc, Weak};
-use std::sync::Mutex;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// How often a connected tunnel is checked against its limits.
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How long a tunnel has to stay up before its earlier drops are forgotten.
+const STABLE_TUNNEL: Duration = Duration::from_secs(60);
 
 pub struct ConnectionManager {
     pub app: Mutex<Weak<VpnApp>>,
+    failover: Mutex<Failover>,
+    /// Bytes received and sent by the current tunnel, from `>BYTECOUNT`.
+    traffic: Mutex<(u64, u64)>,
+    /// Login the current tunnel was authenticated with.
+    session: Mutex<Option<Assertion>>,
+    /// SAML response of that login, kept to restart the tunnel while it is valid.
+    saml: Mutex<Option<Saml>>,
+    reconnect: Mutex<Reconnect>,
+    /// Limits of the tunnel while it is connected.
+    clock: Mutex<Option<SessionClock>>,
+    watcher: Mutex<Option<JoinHandle<()>>>,
+    /// Whether a new login was started in the background to replace the current one.
+    renewing: Mutex<bool>,
+    /// Explanation of the last OpenVPN failure of the current connection.
+    diagnosis: Mutex<Option<Diagnosis>>,
+    /// OpenVPN executable of the current connection.
+    openvpn: Mutex<Option<PathBuf>>,
+    /// Device and DNS options of the tunnel run, from the log of OpenVPN.
+    tunnel: Mutex<TunnelInfo>,
+    /// Split-DNS forwarder of the tunnel, when systemd-resolved does not route.
+    forwarder: Mutex<Option<JoinHandle<()>>>,
+    /// Last queued change of the host DNS, the next one waits for it.
+    dns_change: Mutex<Option<JoinHandle<()>>>,
+}
+
+#[derive(Default)]
+struct Reconnect {
+    /// Attempts since the last time the tunnel was up, shown as `Reconnecting(n)`.
+    attempt: u32,
+    /// Times the tunnel dropped or failed to come back since it was last
+    /// stable, bounded by the policy.
+    drops: u32,
+    /// When the current tunnel came up.
+    up_since: Option<Instant>,
+}
+
+/// What a tunnel that went down needs to come back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Recovery {
+    /// Restart OpenVPN with the SAML response of the current login.
+    ReuseSession,
+    /// Go through the browser login again.
+    Reauthenticate,
 }
 
-unsafe impl Send for ConnectionManager {}
-unsafe impl Sync for ConnectionManager {}
+impl Recovery {
+    /// The SAML response can only be replayed while the server has not
+    /// rejected it and the assertion has not expired.
+    pub fn after(exit: &OvpnExit, session: Option<&Assertion>, now: SystemTime) -> Recovery {
+        match session {
+            Some(session) if !exit.auth_failed() && session.validate(now).is_ok() => {
+                Recovery::ReuseSession
+            }
+            _ => Recovery::Reauthenticate,
+        }
+    }
+}
+
+impl fmt::Display for Recovery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Recovery::ReuseSession => f.write_str("reusing the current login"),
+            Recovery::Reauthenticate => f.write_str("with a new browser login"),
+        }
+    }
+}
+
+/// Endpoints left to try for the current connection and the ones that failed so far.
+#[derive(Default)]
+struct Failover {
+    total: usize,
+    pending: VecDeque<Candidate>,
+    current: Option<Candidate>,
+    attempts: Vec<EndpointAttempt>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    Authentication,
+    Tunnel,
+}
+
+#[derive(Clone, Debug)]
+pub struct EndpointAttempt {
+    pub candidate: Candidate,
+    pub stage: Stage,
+    pub outcome: ConnectError,
+}
+
+impl fmt::Display for EndpointAttempt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stage = match self.stage {
+            Stage::Authentication => "authentication",
+            Stage::Tunnel => "tunnel",
+        };
+        write!(f, "{} during {}: {}", self.candidate, stage, self.outcome)
+    }
+}
 
 impl ConnectionManager {
     pub fn new() -> Self {
         Self {
             app: Mutex::new(Weak::new()),
+            failover: Mutex::new(Failover::default()),
+            traffic: Mutex::new((0, 0)),
+            session: Mutex::new(None),
+            saml: Mutex::new(None),
+            reconnect: Mutex::new(Reconnect::default()),
+            clock: Mutex::new(None),
+            watcher: Mutex::new(None),
+            renewing: Mutex::new(false),
+            diagnosis: Mutex::new(None),
+            openvpn: Mutex::new(None),
+            tunnel: Mutex::new(TunnelInfo::default()),
+            forwarder: Mutex::new(None),
+            dns_change: Mutex::new(None),
         }
     }
 
-    pub fn set_app(&self, app: Rc<VpnApp>) {
+    pub fn set_app(&self, app: Arc<VpnApp>) {
         let mut l = self.app.lock().unwrap();
-        *l = Rc::downgrade(&app);
+        *l = Arc::downgrade(&app);
     }
 
+    #[allow(dead_code)]
     pub fn change_connect_state(&self) {
-        let state = {
-            let app = self.app.lock().unwrap();
-            let app = app.upgrade().unwrap();
-            let state = { *(app.state.lock().unwrap()).as_ref().unwrap().state.borrow() };
+        let state = self.state();
+        {
+            let app = self.app.lock().unwrap().upgrade().unwrap();
             app.log.append(format!("Handling... {:?}", &state));
-            state
-        };
+        }
 
         match state {
-            State::Disconnected => self.connect(),
+            State::Disconnected | State::Failed(_) => self.connect(),
             State::Connected => self.disconnect(),
-            State::Connecting => self.try_disconnect(),
+            _ => self.try_disconnect(),
         }
     }
 
     pub fn try_disconnect(&self) {
-        let state = {
-            let app = self.app.lock().unwrap();
-            let app = app.upgrade().unwrap();
-            let state = { *(app.state.lock().unwrap()).as_ref().unwrap().state.borrow() };
+        let state = self.state();
+        {
+            let app = self.app.lock().unwrap().upgrade().unwrap();
             app.log.append(format!("Handling... {:?}", &state));
-            state
-        };
+        }
 
         match state {
             State::Disconnected => (),
@@ -58,9 +185,35 @@ impl ConnectionManager {
 
     pub fn connect(&self) {
         println!("Connecting...");
-        self.set_connecting();
+        if !self.set_state(State::Connecting) {
+            let app = self.app.lock().unwrap().upgrade().unwrap();
+            app.log.append("Error: A connection is already active");
+            return;
+        }
+        *self.reconnect.lock().unwrap() = Reconnect::default();
+        self.diagnosis.lock().unwrap().take();
 
-        let (file, remote, addrs) = {
+        // Refuse an unusable OpenVPN before the browser login
+        let app = self.app.lock().unwrap().upgrade().unwrap();
+        match discover(app.config.openvpn_path().as_deref()) {
+            Ok(openvpn) => {
+                app.log.append(format!("Using OpenVPN {}", openvpn));
+                *self.openvpn.lock().unwrap() = Some(openvpn.path);
+            }
+            Err(e) => {
+                app.log.append(format!("Error: {}", e));
+                self.set_state(State::Failed(e.into()));
+                return;
+            }
+        }
+
+        self.start_authentication();
+    }
+
+    /// Fills the endpoint list from the resolved profile and starts the SAML
+    /// challenge against the first one.
+    fn start_authentication(&self) {
+        let (file, candidates) = {
             let app = self.app.lock().unwrap();
             let app = app.upgrade().unwrap();
 
@@ -69,98 +222,705 @@ impl ConnectionManager {
                     let x = app.config.config.lock().unwrap().deref().clone();
                     x
                 },
-                {
-                    let x = app.config.remote.lock().unwrap().deref().clone();
-                    x
-                },
-                {
-                    let x = app.config.addresses.lock().unwrap().deref().clone();
-                    x
-                },
+                app.config.candidates(),
+            )
+        };
+
+        if file.is_none() {
+            let app = self.app.lock().unwrap().upgrade().unwrap();
+            app.log.append("Error: No VPN configuration file selected");
+            self.set_state(State::Failed(ConnectError::NoProfile));
+            return;
+        }
+
+        if candidates.is_empty() {
+            // Handle the case where DNS resolution failed to find any addresses
+            let app = self.app.lock().unwrap().upgrade().unwrap();
+            app.log
+                .append("Error: DNS resolution failed - no addresses found for VPN endpoint");
+            let error = match app.config.resolve_error() {
+                Some(e) => ConnectError::Resolve(e),
+                None => ConnectError::NoAddresses,
+            };
+            self.set_state(State::Failed(error));
+            return;
+        }
+
+        {
+            let mut failover = self.failover.lock().unwrap();
+            *failover = Failover {
+                total: candidates.len(),
+                pending: candidates.into(),
+                current: None,
+                attempts: Vec::new(),
+            };
+        }
+
+        self.try_next_endpoint();
+    }
+
+    /// Starts the SAML challenge against the next endpoint that has not been tried yet.
+    fn try_next_endpoint(&self) {
+        let (candidate, index, total) = {
+            let mut failover = self.failover.lock().unwrap();
+            let candidate = failover.pending.pop_front();
+            failover.current = candidate.clone();
+            (
+                candidate,
+                failover.total - failover.pending.len(),
+                failover.total,
             )
         };
 
-        // Check if addresses are available first
-        if let Some(ref addrs) = addrs {
-            if addrs.is_empty() {
-                // Handle the case where DNS resolution failed to find any addresses
-                let app = self.app.lock().unwrap();
-                let app = app.upgrade().unwrap();
+        let app = self.app.lock().unwrap().upgrade().unwrap();
+
+        let candidate = match candidate {
+            Some(c) => c,
+            None => {
                 app.log
-                    .append("Error: DNS resolution failed - no addresses found for VPN endpoint");
-                self.set_disconnected();
+                    .append(format!("Error: All {} VPN endpoints failed", total));
+                let (attempts, last) = {
+                    let failover = self.failover.lock().unwrap();
+                    let attempts: Vec<String> =
+                        failover.attempts.iter().map(|a| a.to_string()).collect();
+                    (
+                        attempts,
+                        failover.attempts.last().map(|a| a.outcome.clone()),
+                    )
+                };
+                for (i, attempt) in attempts.iter().enumerate() {
+                    app.log.append(format!("  {}. {}", i + 1, attempt));
+                }
+                self.set_state(State::Failed(ConnectError::AllEndpointsFailed {
+                    endpoints: total,
+                    attempts,
+                    last: Box::new(last.unwrap_or(ConnectError::NoAddresses)),
+                }));
                 return;
             }
+        };
 
-            if let Some(ref remote) = remote {
-                if let Some(ref file) = file {
-                    let log = {
-                        let app = self.app.lock().unwrap();
-                        let app = app.upgrade().unwrap();
-                        app.log.clone()
-                    };
-
-                    let first_addr = addrs[0].to_string(); // Now safe because we checked if it's empty
-                    let config_file = file.clone();
-                    let port = remote.1;
-
-                    let pwd = {
-                        let app = self.app.lock().unwrap();
-                        let app = app.upgrade().unwrap();
-                        app.config.pwd.clone()
-                    };
-
-                    let join = {
-                        let app = self.app.lock().unwrap();
-                        let app = app.upgrade().unwrap();
-                        let log = log.clone();
-
-                        app.runtime.spawn(async move {
-                            let mut lock = pwd.lock().await;
-                            let auth = run_ovpn(log, config_file, first_addr, port).await;
-                            *lock = Some(Pwd { pwd: auth.pwd });
-
-                            println!("Please authenticate in your browser: {}", auth.url);
-                            open::that(auth.url).unwrap()
-                        })
-                    };
-
-                    let log = log.clone();
-                    let app = self.app.lock().unwrap();
-                    let app = app.upgrade().unwrap();
-                    app.openvpn.replace(Some(OavcTask {
-                        name: "OpenVPN Initial SAML Process".to_string(),
-                        handle: join,
-                        log,
-                    }));
+        let file = app.config.config.lock().unwrap().deref().clone();
+        let (launch, config_file) = match (self.launch(), file) {
+            (Some(launch), Some(f)) => (launch, f),
+            _ => {
+                app.log.append("Error: No VPN configuration file selected");
+                self.set_state(State::Failed(ConnectError::NoProfile));
+                return;
+            }
+        };
+
+        app.log.append(format!(
+            "Trying endpoint {}/{}: {}",
+            index, total, candidate
+        ));
+
+        let log = app.log.clone();
+        let pwd = app.config.pwd.clone();
+        let manager = app.connection_manager.clone();
+        let target = candidate.clone();
+        let acs_port = app.config.saml_listen().port;
+        let on_event = ConnectionManager::event_handler(manager.clone(), Stage::Authentication);
+
+        let join = app.runtime.spawn(async move {
+            let mut lock = pwd.lock().await;
+            let auth = match run_ovpn(log.clone(), launch, config_file, target, acs_port, on_event)
+                .await
+            {
+                Ok(auth) => auth,
+                Err(exit) => {
+                    drop(lock);
+                    let man = manager.lock().unwrap();
+                    if let Some(man) = man.as_ref() {
+                        man.process_failed(Stage::Authentication, &exit);
+                    }
                     return;
                 }
+            };
+            *lock = Some(Pwd { pwd: auth.pwd });
+
+            {
+                let man = manager.lock().unwrap();
+                if let Some(man) = man.as_ref() {
+                    man.set_state(State::AwaitingBrowser);
+                }
+            }
+
+            println!("Please authenticate in your browser: {}", auth.url);
+            if let Err(e) = open::that(auth.url) {
+                log.append(format!("Unable to open the browser: {}", e));
             }
+        });
+
+        *app.openvpn.lock().unwrap() = Some(OavcTask {
+            name: "OpenVPN Initial SAML Process".to_string(),
+            handle: join,
+            log: app.log.clone(),
+        });
+    }
+
+    /// Records why an OpenVPN process for the current endpoint failed and moves
+    /// on to the next endpoint.
+    fn process_failed(&self, stage: Stage, exit: &OvpnExit) {
+        self.record_diagnosis(exit);
+        let error = exit
+            .error
+            .clone()
+            .unwrap_or(ConnectError::Exited(exit.code));
+        self.endpoint_failed(stage, error);
+    }
+
+    fn record_diagnosis(&self, exit: &OvpnExit) {
+        if let Some(diagnosis) = &exit.diagnosis {
+            let app = self.app.lock().unwrap().upgrade().unwrap();
+            app.log.append(format!("Diagnosis: {}", diagnosis));
+            *self.diagnosis.lock().unwrap() = Some(diagnosis.clone());
         }
+    }
 
-        // If we get here, something is missing (file, remote, or addresses)
-        let app = self.app.lock().unwrap();
-        let app = app.upgrade().unwrap();
+    /// Explanation of the last OpenVPN failure since `connect`.
+    pub fn diagnosis(&self) -> Option<Diagnosis> {
+        self.diagnosis.lock().unwrap().clone()
+    }
 
-        if addrs.is_none() || addrs.as_ref().unwrap().is_empty() {
-            app.log
-                .append("Error: DNS resolution failed for VPN endpoint");
-        } else if remote.is_none() {
-            app.log.append("Error: Remote configuration missing");
-        } else if file.is_none() {
-            app.log.append("Error: No VPN configuration file selected");
-        } else {
-            app.log.append("Error: Unknown connection issue");
+    /// Records the failure of the current endpoint and moves on to the next one.
+    fn endpoint_failed(&self, stage: Stage, error: ConnectError) {
+        let attempt = {
+            let mut failover = self.failover.lock().unwrap();
+            let candidate = match failover.current.take() {
+                Some(c) => c,
+                None => return,
+            };
+            let attempt = EndpointAttempt {
+                candidate,
+                stage,
+                outcome: error,
+            };
+            failover.attempts.push(attempt.clone());
+            attempt
+        };
+
+        {
+            let app = self.app.lock().unwrap();
+            let app = app.upgrade().unwrap();
+            app.log.append(format!("Endpoint failed: {}", attempt));
+        }
+
+        self.set_reconnecting();
+        self.try_next_endpoint();
+    }
+
+    /// Forwards management events of an OpenVPN process to the connection manager.
+    pub fn event_handler(
+        manager: Arc<Mutex<Option<ConnectionManager>>>,
+        stage: Stage,
+    ) -> EventHandler {
+        Arc::new(move |event| {
+            let man = manager.lock().unwrap();
+            if let Some(man) = man.as_ref() {
+                man.handle_event(stage, event);
+            }
+        })
+    }
+
+    pub fn handle_event(&self, stage: Stage, event: &ManagementEvent) {
+        let app = self.app.lock().unwrap().upgrade().unwrap();
+
+        match event {
+            ManagementEvent::State(state) => {
+                app.log.append(format!(
+                    "OpenVPN state: {} {}",
+                    state.name, state.description
+                ));
+                if stage == Stage::Tunnel && state.name == "CONNECTED" {
+                    {
+                        let mut reconnect = self.reconnect.lock().unwrap();
+                        reconnect.attempt = 0;
+                        reconnect.up_since = Some(Instant::now());
+                    }
+                    self.set_state(State::Connected);
+                    self.watch_session();
+                    self.apply_dns();
+                }
+            }
+            ManagementEvent::ByteCount {
+                bytes_in,
+                bytes_out,
+            } => {
+                let mut traffic = self.traffic.lock().unwrap();
+                *traffic = (*bytes_in, *bytes_out);
+                if let Some(clock) = self.clock.lock().unwrap().as_mut() {
+                    clock.traffic(SystemTime::now(), *traffic);
+                }
+            }
+            ManagementEvent::Log { message, .. } => {
+                if stage == Stage::Tunnel {
+                    self.tunnel.lock().unwrap().observe(message);
+                }
+                app.log.append(message)
+            }
+            ManagementEvent::Fatal(message) => {
+                app.log.append(format!("OpenVPN fatal error: {}", message))
+            }
+            ManagementEvent::Error(message) => app
+                .log
+                .append(format!("OpenVPN management error: {}", message)),
+            _ => {}
+        }
+    }
+
+    /// How to start OpenVPN for the current connection.
+    fn launch(&self) -> Option<Launch> {
+        let openvpn = self.openvpn.lock().unwrap().clone()?;
+        let app = self.app.lock().unwrap().upgrade().unwrap();
+        Some(Launch {
+            openvpn,
+            privilege: app.config.privilege_helper(),
+            verbosity: app.config.verbosity(),
+            inactive_timeout: app.config.session_policy().inactive_timeout,
+            routes: app.config.route_policy(),
+        })
+    }
+
+    /// The endpoint the current connection attempt is using.
+    pub fn current_endpoint(&self) -> Option<Candidate> {
+        self.failover.lock().unwrap().current.clone()
+    }
+
+    pub fn session(&self) -> Option<Assertion> {
+        self.session.lock().unwrap().clone()
+    }
+
+    fn set_session(&self, assertion: Assertion) {
+        let mut session = self.session.lock().unwrap();
+        *session = Some(assertion);
+    }
+
+    /// When and why the connected tunnel is going to be closed.
+    pub fn session_end(&self) -> Option<SessionEnd> {
+        let app = self.app.lock().unwrap().upgrade().unwrap();
+        let policy = app.config.session_policy();
+        let clock = self.clock.lock().unwrap();
+        clock.as_ref().and_then(|c| c.end(&policy))
+    }
+
+    /// Takes a SAML login posted by the browser, either for the connection in
+    /// progress or to replace the login of the connected tunnel. A renewed
+    /// login restarts the tunnel, the old one is stopped before the new one is up.
+    pub fn accept_login(&self, assertion: Assertion, saml: Saml) {
+        let renewing = std::mem::replace(&mut *self.renewing.lock().unwrap(), false);
+        if renewing && self.state() == State::Connected {
+            let app = self.app.lock().unwrap().upgrade().unwrap();
+            app.log.append("Switching the tunnel to the renewed login");
+            self.set_session(assertion);
+
+            // Stopped on purpose, its exit must not count as a drop
+            let old = app.openvpn_connection.lock().unwrap().take();
+            if let Some(task) = old {
+                task.abort(false);
+            }
+            self.set_reconnecting();
+            self.start_tunnel(saml);
+            return;
+        }
+
+        if !self.set_state(State::Authenticating) {
+            return;
+        }
+        self.set_session(assertion);
+        self.start_tunnel(saml);
+    }
+
+    /// A posted login failed validation. A renewal leaves the tunnel alone.
+    pub fn reject_login(&self, reason: String) {
+        if std::mem::replace(&mut *self.renewing.lock().unwrap(), false) {
+            return;
+        }
+        self.set_state(State::Failed(ConnectError::SamlRejected(reason)));
+    }
+
+    /// Starts following the limits of the freshly connected tunnel.
+    fn watch_session(&self) {
+        let app = self.app.lock().unwrap().upgrade().unwrap();
+        let login_expiry = self.session().and_then(|s| s.session_expiry());
+        *self.clock.lock().unwrap() = Some(SessionClock::new(SystemTime::now(), login_expiry));
+
+        if let Some(end) = self.session_end() {
+            app.log.append(format!("The VPN session ends at {}", end));
+        }
+
+        let manager = app.connection_manager.clone();
+        let watcher = app.runtime.spawn(async move {
+            loop {
+                tokio::time::sleep(SESSION_CHECK_INTERVAL).await;
+                let man = manager.lock().unwrap();
+                match man.as_ref() {
+                    Some(man) if man.check_session() => {}
+                    _ => break,
+                }
+            }
+        });
+
+        if let Some(previous) = self.watcher.lock().unwrap().replace(watcher) {
+            previous.abort();
+        }
+    }
+
+    /// Warns when the tunnel is about to be closed and starts the renewal if
+    /// enabled. Returns `false` once the tunnel is no longer connected.
+    fn check_session(&self) -> bool {
+        if self.state() != State::Connected {
+            return false;
+        }
+
+        let app = self.app.lock().unwrap().upgrade().unwrap();
+        let policy = app.config.session_policy();
+        let now = SystemTime::now();
+        let end = match self.clock.lock().unwrap().as_mut() {
+            Some(clock) => clock.warning(now, &policy),
+            None => return false,
+        };
+
+        if let Some(end) = end {
+            let left = end.at.duration_since(now).unwrap_or_default();
+            app.log.append(format!(
+                "Warning: The VPN session ends in {} minutes, at {}",
+                (left.as_secs() + 59) / 60,
+                end
+            ));
+            if policy.renew && end.reason.renewable() {
+                self.start_renewal();
+            }
+        }
+
+        true
+    }
+
+    /// Runs the SAML challenge again while the tunnel stays up, the tunnel is
+    /// switched over once the browser posts the new login.
+    fn start_renewal(&self) {
+        {
+            let mut renewing = self.renewing.lock().unwrap();
+            if *renewing {
+                return;
+            }
+            *renewing = true;
+        }
+
+        let app = self.app.lock().unwrap().upgrade().unwrap();
+        let candidate = self.current_endpoint();
+        let config = app.config.config.lock().unwrap().deref().clone();
+        let (launch, candidate, config) = match (self.launch(), candidate, config) {
+            (Some(launch), Some(candidate), Some(config)) => (launch, candidate, config),
+            _ => {
+                *self.renewing.lock().unwrap() = false;
+                return;
+            }
+        };
+
+        app.log
+            .append("Renewing the VPN login, complete it in the browser to stay connected");
+
+        let log = app.log.clone();
+        let pwd = app.config.pwd.clone();
+        let manager = app.connection_manager.clone();
+        let acs_port = app.config.saml_listen().port;
+        let on_event = ConnectionManager::event_handler(manager.clone(), Stage::Authentication);
+
+        let join = app.runtime.spawn(async move {
+            let mut lock = pwd.lock().await;
+            match run_ovpn(log.clone(), launch, config, candidate, acs_port, on_event).await {
+                Ok(auth) => {
+                    *lock = Some(Pwd { pwd: auth.pwd });
+                    println!("Please authenticate in your browser: {}", auth.url);
+                    if let Err(e) = open::that(auth.url) {
+                        log.append(format!("Unable to open the browser: {}", e));
+                    }
+                }
+                Err(exit) => {
+                    drop(lock);
+                    let error = exit.error.unwrap_or(ConnectError::MissingSamlChallenge);
+                    log.append(format!("Error: Unable to renew the VPN login: {}", error));
+                    if let Some(diagnosis) = exit.diagnosis {
+                        log.append(format!("Diagnosis: {}", diagnosis));
+                    }
+                    let man = manager.lock().unwrap();
+                    if let Some(man) = man.as_ref() {
+                        *man.renewing.lock().unwrap() = false;
+                    }
+                }
+            }
+        });
+
+        *app.openvpn.lock().unwrap() = Some(OavcTask {
+            name: "OpenVPN Renewal SAML Process".to_string(),
+            handle: join,
+            log: app.log.clone(),
+        });
+    }
+
+    /// Starts the tunnel to the current endpoint with a SAML response.
+    pub fn start_tunnel(&self, saml: Saml) {
+        let app = self.app.lock().unwrap().upgrade().unwrap();
+
+        let candidate = self.current_endpoint();
+        let config = app.config.config.lock().unwrap().deref().clone();
+        let (launch, candidate, config) = match (self.launch(), candidate, config) {
+            (Some(launch), Some(candidate), Some(config)) => (launch, candidate, config),
+            _ => {
+                app.log.append("Error: No connection is in progress");
+                self.set_state(State::Failed(ConnectError::NoProfile));
+                return;
+            }
+        };
+
+        *self.saml.lock().unwrap() = Some(saml.clone());
+        *self.tunnel.lock().unwrap() = TunnelInfo::default();
+
+        let log = app.log.clone();
+        let info = Arc::new(ProcessInfo::new(launch.privilege));
+        let manager = app.connection_manager.clone();
+        let on_event = ConnectionManager::event_handler(manager.clone(), Stage::Tunnel);
+
+        let handle = {
+            let info = info.clone();
+            app.runtime.spawn(async move {
+                let exit = connect_ovpn(log, launch, config, candidate, saml, info, on_event).await;
+                let man = manager.lock().unwrap();
+                if let Some(man) = man.as_ref() {
+                    man.tunnel_exited(&exit);
+                }
+                exit
+            })
+        };
+
+        let task = OavcProcessTask::new(
+            "OpenVPN Connection".to_string(),
+            handle,
+            app.log.clone(),
+            info,
+        );
+        *app.openvpn_connection.lock().unwrap() = Some(task);
+    }
+
+    /// Points the host at the nameservers pushed to the tunnel that just came up.
+    fn apply_dns(&self) {
+        let app = self.app.lock().unwrap().upgrade().unwrap();
+        let backend = app.config.dns_backend();
+        let tunnel = self.tunnel.lock().unwrap().clone();
+        let device = match tunnel.device {
+            Some(device) if !tunnel.dns.is_empty() => device,
+            _ => {
+                app.log.append("The server pushed no DNS servers");
+                return;
+            }
+        };
+        let detected = match host_dns::detect(backend) {
+            Some(detected) => detected,
+            None => {
+                app.log.append(format!(
+                    "Leaving the DNS configuration alone (dns backend {})",
+                    backend
+                ));
+                return;
+            }
+        };
+
+        let split = self.split_dns(&app, detected, &tunnel.dns);
+        let log = app.log.clone();
+        let privilege = app.config.privilege_helper();
+        self.queue_dns_change(&app, move || {
+            match host_dns::apply(detected, privilege, &device, &tunnel.dns, &split) {
+                Ok(applied) => log.append(format!("Applied DNS: {}", applied)),
+                Err(e) => log.append(format!("Warning: Unable to apply the pushed DNS: {}", e)),
+            }
+        });
+    }
+
+    /// The split-DNS rules to apply. Without systemd-resolved they need the
+    /// forwarder, when it cannot be started all queries go to the VPN instead.
+    fn split_dns(&self, app: &VpnApp, detected: DnsBackend, dns: &PushedDns) -> SplitDns {
+        let split = app.config.split_dns();
+        if split.is_empty() || detected == DnsBackend::Resolved {
+            return split;
+        }
+
+        let upstreams = Upstreams {
+            vpn: dns
+                .servers
+                .iter()
+                .map(|s| SocketAddr::new(*s, 53))
+                .collect(),
+            local: split_dns::host_nameservers(split.forwarder),
+        };
+        match split_dns::start(&app.runtime, split.forwarder, split.clone(), upstreams) {
+            Ok(forwarder) => {
+                if let Some(previous) = self.forwarder.lock().unwrap().replace(forwarder) {
+                    previous.abort();
+                }
+                split
+            }
+            Err(e) => {
+                app.log.append(format!(
+                    "Warning: Unable to start the split-DNS forwarder on {}:53, \
+                     applying the pushed DNS to all queries: {}",
+                    split.forwarder, e
+                ));
+                SplitDns::default()
+            }
+        }
+    }
+
+    /// Undoes the DNS change of the last tunnel, if any. Queued behind an
+    /// apply that is still running, which would otherwise land afterwards.
+    fn restore_dns(&self) {
+        self.stop_forwarder();
+        let app = self.app.lock().unwrap().upgrade().unwrap();
+        let log = app.log.clone();
+        let privilege = app.config.privilege_helper();
+        self.queue_dns_change(&app, move || restore_dns(&log, privilege));
+    }
+
+    /// Runs `change` off the runtime once the previously queued change is done,
+    /// so that the host DNS ends up as the last change asked for.
+    fn queue_dns_change(&self, app: &VpnApp, change: impl FnOnce() + Send + 'static) {
+        let mut last = self.dns_change.lock().unwrap();
+        let previous = last.take();
+        *last = Some(app.runtime.spawn(async move {
+            if let Some(previous) = previous {
+                let _ = previous.await;
+            }
+            let _ = tokio::task::spawn_blocking(change).await;
+        }));
+    }
+
+    /// Resolves once the DNS changes queued so far are done. Awaited without
+    /// the manager locked, a change may wait for a password prompt.
+    #[allow(dead_code)]
+    pub fn dns_settled(&self) -> oneshot::Receiver<()> {
+        let app = self.app.lock().unwrap().upgrade().unwrap();
+        let (tx, rx) = oneshot::channel();
+        self.queue_dns_change(&app, move || {
+            let _ = tx.send(());
+        });
+        rx
+    }
+
+    fn stop_forwarder(&self) {
+        if let Some(forwarder) = self.forwarder.lock().unwrap().take() {
+            forwarder.abort();
+        }
+    }
+
+    /// Decides what to do once the OpenVPN connection process ended.
+    /// A reconnect that does not bring the tunnel back counts as another drop,
+    /// other tunnels that never came up move on to the next endpoint.
+    fn tunnel_exited(&self, exit: &OvpnExit) {
+        self.restore_dns();
+        let state = self.state();
+        if matches!(state, State::Disconnected | State::Failed(_)) {
+            return;
+        }
+
+        if !exit.connected && !matches!(state, State::Reconnecting(_)) {
+            self.process_failed(Stage::Tunnel, exit);
+            return;
+        }
+        self.record_diagnosis(exit);
+
+        let app = self.app.lock().unwrap().upgrade().unwrap();
+        let policy = app.config.reconnect_policy();
+        let drops = {
+            let mut reconnect = self.reconnect.lock().unwrap();
+            let stable = reconnect
+                .up_since
+                .take()
+                .is_some_and(|since| since.elapsed() >= STABLE_TUNNEL);
+            if stable {
+                reconnect.drops = 0;
+            }
+            reconnect.drops += 1;
+            reconnect.drops
+        };
+
+        if drops > policy.max_attempts {
+            let error = ConnectError::ConnectionLost {
+                attempts: policy.max_attempts,
+            };
+            app.log.append(format!(
+                "Error: {} (OpenVPN exited with code {})",
+                error, exit.code
+            ));
+            self.saml.lock().unwrap().take();
+            self.set_state(State::Failed(error));
+            return;
+        }
+
+        let recovery = Recovery::after(exit, self.session().as_ref(), SystemTime::now());
+        let delay = policy.delay(drops);
+        let what = match exit.connected {
+            true => "Connection lost",
+            false => "Reconnect failed",
+        };
+        app.log.append(format!(
+            "{} (OpenVPN exited with code {}), reconnecting in {:.1}s {} (attempt {}/{})",
+            what,
+            exit.code,
+            delay.as_secs_f64(),
+            recovery,
+            drops,
+            policy.max_attempts
+        ));
+        self.set_reconnecting();
+
+        let manager = app.connection_manager.clone();
+        let join = app.runtime.spawn(async move {
+            tokio::time::sleep(delay).await;
+            let man = manager.lock().unwrap();
+            if let Some(man) = man.as_ref() {
+                man.reconnect(recovery);
+            }
+        });
+
+        *app.openvpn.lock().unwrap() = Some(OavcTask {
+            name: "Reconnect".to_string(),
+            handle: join,
+            log: app.log.clone(),
+        });
+    }
+
+    fn reconnect(&self, recovery: Recovery) {
+        if !matches!(self.state(), State::Reconnecting(_)) {
+            return;
+        }
+
+        let saml = self.saml.lock().unwrap().clone();
+        match (recovery, saml) {
+            (Recovery::ReuseSession, Some(saml)) => self.start_tunnel(saml),
+            _ => {
+                self.saml.lock().unwrap().take();
+                self.session.lock().unwrap().take();
+                self.start_authentication();
+            }
         }
+    }
 
-        self.set_disconnected();
+    /// Moves to the next `Reconnecting(n)`.
+    fn set_reconnecting(&self) {
+        let attempt = {
+            let mut reconnect = self.reconnect.lock().unwrap();
+            reconnect.attempt += 1;
+            reconnect.attempt
+        };
+        self.set_state(State::Reconnecting(attempt));
     }
+
     pub fn force_disconnect(&self) {
         println!("Forcing disconnect...");
 
         let app = self.app.lock().unwrap();
         let app = app.upgrade().unwrap();
-        let mut openvpn = app.openvpn.borrow_mut();
+        let mut openvpn = app.openvpn.lock().unwrap();
 
         if let Some(ref srv) = openvpn.take() {
             srv.abort(false);
@@ -181,13 +941,20 @@ impl ConnectionManager {
             app.log.append("Disconnecting...");
         }
 
-        self.set_disconnected();
+        self.set_state(State::Disconnected);
+        self.session.lock().unwrap().take();
+        self.saml.lock().unwrap().take();
+        self.clock.lock().unwrap().take();
+        *self.renewing.lock().unwrap() = false;
+        if let Some(watcher) = self.watcher.lock().unwrap().take() {
+            watcher.abort();
+        }
 
         {
             let app = self.app.lock().unwrap();
             let app = app.upgrade().unwrap();
 
-            let mut openvpn = app.openvpn.borrow_mut();
+            let mut openvpn = app.openvpn.lock().unwrap();
 
             if let Some(ref srv) = openvpn.take() {
                 srv.abort(true);
@@ -201,30 +968,327 @@ impl ConnectionManager {
                 app.log.append("OpenVPN disconnected!");
             }
 
+            let mut traffic = self.traffic.lock().unwrap();
+            if *traffic != (0, 0) {
+                app.log.append(format!(
+                    "Transferred {} bytes in, {} bytes out",
+                    traffic.0, traffic.1
+                ));
+                *traffic = (0, 0);
+            }
+
+            // Behind an apply that is still running, `dns_settled` tells when it is done
+            self.restore_dns();
+
             app.log.append("Disconnected!");
         }
     }
 
-    fn set_connecting(&self) {
-        let app = self.app.lock().unwrap();
-        let app = app.upgrade().unwrap();
-        app.state.lock().unwrap().as_ref().unwrap().set_connecting();
+    pub fn state(&self) -> State {
+        let app = self.app.lock().unwrap().upgrade().unwrap();
+        let state = app.state.lock().unwrap().as_ref().unwrap().state();
+        state
     }
 
-    fn set_disconnected(&self) {
-        let app = self.app.lock().unwrap();
-        let app = app.upgrade().unwrap();
-        app.state
+    /// Moves the state machine, returns `false` when the transition is not allowed.
+    pub fn set_state(&self, state: State) -> bool {
+        let app = self.app.lock().unwrap().upgrade().unwrap();
+        let accepted = app
+            .state
             .lock()
             .unwrap()
             .as_ref()
             .unwrap()
-            .set_disconnected();
+            .transition(state)
+            .is_ok();
+        accepted
     }
+}
 
-    fn _set_connected(&self) {
-        let app = self.app.lock().unwrap();
-        let app = app.upgrade().unwrap();
-        app.state.lock().unwrap().as_ref().unwrap().set_connected();
+/// Undoes the recorded DNS change, also the one left behind by a crash.
+pub fn restore_dns(log: &Log, privilege: PrivilegeHelper) {
+    match host_dns::restore(privilege) {
+        Ok(Some(applied)) => log.append(format!("Restored DNS: {}", applied)),
+        Ok(None) => {}
+        Err(e) => log.append(format!("Warning: Unable to restore the DNS: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Endpoint, PrivilegeHelper, ReconnectPolicy};
+    use crate::ovpn::Proto;
+
+    /// An app whose OpenVPN cannot be started, so every attempt fails right away.
+    fn app() -> Arc<VpnApp> {
+        let app = Arc::new(VpnApp::new());
+        app.set_connection_manager(ConnectionManager::new());
+        with_manager(&app, |man| {
+            man.set_app(app.clone());
+            *man.openvpn.lock().unwrap() = Some(PathBuf::from("/nonexistent/openvpn"));
+        });
+        *app.config.config.lock().unwrap() = Some(PathBuf::from("/nonexistent/aws-oavc.ovpn"));
+        app.config.set_privilege_helper(PrivilegeHelper::None);
+        app
+    }
+
+    fn with_manager<T>(app: &VpnApp, f: impl FnOnce(&ConnectionManager) -> T) -> T {
+        let man = app.connection_manager.lock().unwrap();
+        f(man.as_ref().unwrap())
+    }
+
+    fn candidate(host: &str, addr: &str) -> Candidate {
+        Candidate {
+            endpoint: Endpoint {
+                host: host.to_string(),
+                port: 443,
+                proto: Proto::Udp,
+            },
+            addr: addr.parse().unwrap(),
+        }
+    }
+
+    /// Waits until the background tasks settle on a `Failed` state.
+    fn failure(app: &VpnApp) -> ConnectError {
+        for _ in 0..200 {
+            if let State::Failed(e) = app.state.lock().unwrap().as_ref().unwrap().state() {
+                return e;
+            }
+            std::thread::sleep(Duration::from_millis(25));
+        }
+        panic!("the connection never failed");
+    }
+
+    /// A login that is valid for five more minutes.
+    fn assertion(now: SystemTime) -> Assertion {
+        Assertion {
+            issuer: None,
+            name_id: Some("jane@example.com".to_string()),
+            audiences: Vec::new(),
+            not_before: None,
+            not_on_or_after: Some(now + Duration::from_secs(300)),
+            session_not_on_or_after: None,
+            status: "urn:oasis:names:tc:SAML:2.0:status:Success".to_string(),
+            status_message: None,
+        }
+    }
+
+    /// A manager with a connected tunnel to `endpoint` and a reusable login.
+    fn connected(app: &VpnApp, endpoint: &Candidate, initial_delay: Duration) {
+        app.config.set_reconnect_policy(ReconnectPolicy {
+            max_attempts: 3,
+            initial_delay,
+            max_delay: initial_delay * 4,
+        });
+        with_manager(app, |man| {
+            man.failover.lock().unwrap().current = Some(endpoint.clone());
+            man.set_session(assertion(SystemTime::now()));
+            *man.saml.lock().unwrap() = Some(Saml {
+                data: "assertion".to_string(),
+                pwd: "pwd".to_string(),
+            });
+            for state in [
+                State::Connecting,
+                State::AwaitingBrowser,
+                State::Authenticating,
+                State::Connected,
+            ] {
+                assert!(man.set_state(state));
+            }
+        });
+    }
+
+    fn exit(auth_failed: bool) -> OvpnExit {
+        OvpnExit {
+            code: 1,
+            connected: true,
+            error: auth_failed.then_some(ConnectError::AuthRejected),
+            diagnosis: None,
+        }
+    }
+
+    #[test]
+    fn reuses_the_session_only_while_it_is_valid() {
+        let now = SystemTime::now();
+        let session = assertion(now);
+
+        assert_eq!(
+            Recovery::after(&exit(false), Some(&session), now),
+            Recovery::ReuseSession
+        );
+        assert_eq!(
+            Recovery::after(&exit(true), Some(&session), now),
+            Recovery::Reauthenticate
+        );
+        assert_eq!(
+            Recovery::after(
+                &exit(false),
+                Some(&session),
+                now + Duration::from_secs(3600)
+            ),
+            Recovery::Reauthenticate
+        );
+        assert_eq!(
+            Recovery::after(&exit(false), None, now),
+            Recovery::Reauthenticate
+        );
+    }
+
+    #[test]
+    fn fails_over_to_every_endpoint_and_lists_the_attempts() {
+        let app = app();
+        *app.config.addresses.lock().unwrap() = Some(vec![
+            candidate("a.example.com", "192.0.2.1"),
+            candidate("a.example.com", "192.0.2.2"),
+            candidate("b.example.com", "198.51.100.1"),
+        ]);
+        with_manager(&app, |man| {
+            assert!(man.set_state(State::Connecting));
+            man.start_authentication();
+        });
+
+        let error = failure(&app);
+        let (endpoints, attempts, last) = match &error {
+            ConnectError::AllEndpointsFailed {
+                endpoints,
+                attempts,
+                last,
+            } => (*endpoints, attempts.clone(), last.clone()),
+            e => panic!("unexpected error {:?}", e),
+        };
+        assert_eq!(endpoints, 3);
+        assert_eq!(attempts.len(), 3);
+        for (attempt, addr) in attempts
+            .iter()
+            .zip(["192.0.2.1", "192.0.2.2", "198.51.100.1"])
+        {
+            assert!(attempt.contains(addr), "{}", attempt);
+            assert!(attempt.contains("during authentication"), "{}", attempt);
+        }
+        assert!(matches!(*last, ConnectError::Spawn(_)));
+        assert_eq!(error.code(), 12);
+        assert!(error
+            .to_string()
+            .contains("; 3. b.example.com (198.51.100.1)"));
+
+        // Each failover was announced as a reconnect
+        let history = app.state.lock().unwrap().as_ref().unwrap().history();
+        let reconnects = history
+            .iter()
+            .filter(|t| matches!(t.to, State::Reconnecting(_)))
+            .count();
+        assert_eq!(reconnects, 3);
+    }
+
+    #[test]
+    fn failed_reconnects_back_off_on_the_same_endpoint() {
+        let app = app();
+        let endpoint = candidate("a.example.com", "192.0.2.1");
+        connected(&app, &endpoint, Duration::from_millis(10));
+
+        // Every restart of the tunnel fails to spawn OpenVPN
+        with_manager(&app, |man| man.tunnel_exited(&exit(false)));
+
+        assert_eq!(failure(&app), ConnectError::ConnectionLost { attempts: 3 });
+        with_manager(&app, |man| {
+            assert_eq!(man.reconnect.lock().unwrap().drops, 4);
+            assert_eq!(man.current_endpoint(), Some(endpoint));
+        });
+        let history = app.state.lock().unwrap().as_ref().unwrap().history();
+        let reconnects: Vec<_> = history
+            .iter()
+            .filter_map(|t| match t.to {
+                State::Reconnecting(n) => Some(n),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(reconnects, [1, 2, 3]);
+    }
+
+    #[test]
+    fn drops_are_forgotten_once_the_tunnel_was_stable() {
+        let app = app();
+        let endpoint = candidate("a.example.com", "192.0.2.1");
+        connected(&app, &endpoint, Duration::from_secs(60));
+
+        with_manager(&app, |man| {
+            {
+                let mut reconnect = man.reconnect.lock().unwrap();
+                reconnect.drops = 2;
+                reconnect.up_since = Some(Instant::now());
+            }
+            man.tunnel_exited(&exit(false));
+            assert_eq!(man.reconnect.lock().unwrap().drops, 3);
+            assert_eq!(man.state(), State::Reconnecting(1));
+
+            // Up again for longer than STABLE_TUNNEL
+            assert!(man.set_state(State::Connected));
+            man.reconnect.lock().unwrap().up_since = Instant::now().checked_sub(STABLE_TUNNEL);
+            man.tunnel_exited(&exit(false));
+            assert_eq!(man.reconnect.lock().unwrap().drops, 1);
+        });
+    }
+
+    #[test]
+    fn dns_changes_run_in_the_order_they_were_queued() {
+        let app = app();
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        with_manager(&app, |man| {
+            let apply = tx.clone();
+            man.queue_dns_change(&app, move || {
+                std::thread::sleep(Duration::from_millis(200));
+                apply.send("apply").unwrap();
+            });
+            man.queue_dns_change(&app, move || tx.send("restore").unwrap());
+        });
+
+        let timeout = Duration::from_secs(5);
+        assert_eq!(rx.recv_timeout(timeout), Ok("apply"));
+        assert_eq!(rx.recv_timeout(timeout), Ok("restore"));
+    }
+
+    #[test]
+    fn dns_settles_after_the_queued_changes() {
+        let app = app();
+        let done = Arc::new(Mutex::new(false));
+
+        let settled = with_manager(&app, |man| {
+            let done = done.clone();
+            man.queue_dns_change(&app, move || {
+                std::thread::sleep(Duration::from_millis(200));
+                *done.lock().unwrap() = true;
+            });
+            man.dns_settled()
+        });
+
+        assert_eq!(app.runtime.block_on(settled), Ok(()));
+        assert!(*done.lock().unwrap());
+    }
+
+    #[test]
+    fn split_dns_falls_back_to_the_full_dns_without_a_forwarder() {
+        let app = app();
+        let dns = PushedDns {
+            servers: vec!["10.0.0.2".parse().unwrap()],
+            domains: vec!["internal.example.com".to_string()],
+        };
+        let mut split = SplitDns::new(vec!["internal.example.com".to_string()]);
+        app.config.set_split_dns(split.clone());
+
+        with_manager(&app, |man| {
+            // systemd-resolved routes the domains itself
+            assert_eq!(man.split_dns(&app, DnsBackend::Resolved, &dns), split);
+
+            // Not an address of this host, binding fails as it would on port 53 without root
+            split.forwarder = "192.0.2.1".parse().unwrap();
+            app.config.set_split_dns(split);
+            assert_eq!(
+                man.split_dns(&app, DnsBackend::File, &dns),
+                SplitDns::default()
+            );
+            assert!(man.forwarder.lock().unwrap().is_none());
+        });
     }
 }
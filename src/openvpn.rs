@@ -0,0 +1,321 @@
+use std::env;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use temp_dir::TempDir;
+
+/// Environment variable pointing at the OpenVPN executable to use.
+pub const OPENVPN_ENV: &str = "OPENAWS_OPENVPN";
+
+/// Location of the patched OpenVPN built by `scripts/patch-openvpn.sh`.
+const BUNDLED_PATH: &str = "share/openvpn/bin";
+
+/// Length of the config line used to probe for the AWS patch. Stock OpenVPN
+/// refuses lines longer than 256 bytes, the patch raises the limit so that
+/// the SAML response fits.
+const PATCH_PROBE_LINE: usize = 1024;
+
+/// Searched after PATH, OpenVPN usually lives in an sbin directory that is
+/// only on the PATH of root.
+const SYSTEM_DIRS: &[&str] = &[
+    "/usr/local/sbin",
+    "/usr/sbin",
+    "/sbin",
+    "/opt/homebrew/sbin",
+];
+
+#[cfg(windows)]
+const BINARY_NAME: &str = "openvpn.exe";
+#[cfg(not(windows))]
+const BINARY_NAME: &str = "openvpn";
+
+/// Where an OpenVPN executable was found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    Setting,
+    Environment,
+    Bundled,
+    Path,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Setting => f.write_str("settings"),
+            Source::Environment => write!(f, "${}", OPENVPN_ENV),
+            Source::Bundled => f.write_str("bundled"),
+            Source::Path => f.write_str("PATH"),
+        }
+    }
+}
+
+/// An OpenVPN executable that carries the AWS SAML patch.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpenVpn {
+    pub path: PathBuf,
+    pub source: Source,
+    /// `OpenVPN <version>` as reported by `--version`.
+    pub version: String,
+}
+
+impl fmt::Display for OpenVpn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}, {})",
+            self.path.display(),
+            self.source,
+            self.version
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum BinaryError {
+    /// None of the searched locations has an executable.
+    NotFound(Vec<PathBuf>),
+    /// The executable could not be run.
+    Probe(PathBuf, io::Error),
+    /// The executable does not answer `--version` like OpenVPN does.
+    NotOpenVpn(PathBuf),
+    /// Stock OpenVPN, which cannot pass the SAML response to the server.
+    Unpatched { path: PathBuf, version: String },
+}
+
+impl fmt::Display for BinaryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BinaryError::NotFound(searched) => {
+                let searched: Vec<_> = searched.iter().map(|p| p.display().to_string()).collect();
+                write!(
+                    f,
+                    "no OpenVPN executable found, searched {}",
+                    searched.join(", ")
+                )
+            }
+            BinaryError::Probe(path, e) => write!(f, "unable to run {}: {}", path.display(), e),
+            BinaryError::NotOpenVpn(path) => write!(f, "{} is not OpenVPN", path.display()),
+            BinaryError::Unpatched { path, version } => write!(
+                f,
+                "{} ({}) lacks the AWS SAML patch, build one with scripts/patch-openvpn.sh \
+                 or point {} at a patched binary",
+                path.display(),
+                version,
+                OPENVPN_ENV
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BinaryError {}
+
+/// Finds the OpenVPN executable to use and checks that it is patched. The
+/// first location that has an executable wins, it is not skipped when it
+/// turns out to be unpatched.
+pub fn discover(setting: Option<&Path>) -> Result<OpenVpn, BinaryError> {
+    let candidates = candidates(
+        setting,
+        env::var_os(OPENVPN_ENV).map(PathBuf::from),
+        &bundled_dirs(),
+        env::var_os("PATH"),
+    );
+
+    let (source, path) = match candidates.iter().find(|(_, p)| p.is_file()) {
+        Some(found) => found.clone(),
+        None => {
+            return Err(BinaryError::NotFound(
+                candidates.into_iter().map(|(_, p)| p).collect(),
+            ))
+        }
+    };
+
+    probe(source, path)
+}
+
+/// Locations to look at, in order.
+fn candidates(
+    setting: Option<&Path>,
+    environment: Option<PathBuf>,
+    bundled: &[PathBuf],
+    path: Option<std::ffi::OsString>,
+) -> Vec<(Source, PathBuf)> {
+    let mut candidates = Vec::new();
+    if let Some(setting) = setting {
+        candidates.push((Source::Setting, setting.to_path_buf()));
+    }
+    if let Some(environment) = environment.filter(|p| !p.as_os_str().is_empty()) {
+        candidates.push((Source::Environment, environment));
+    }
+    for dir in bundled {
+        candidates.push((Source::Bundled, dir.join(BUNDLED_PATH).join(BINARY_NAME)));
+    }
+    let mut dirs: Vec<PathBuf> = path
+        .map(|p| env::split_paths(&p).collect())
+        .unwrap_or_default();
+    for dir in SYSTEM_DIRS.iter().map(PathBuf::from) {
+        if !dirs.contains(&dir) {
+            dirs.push(dir);
+        }
+    }
+    for dir in dirs {
+        candidates.push((Source::Path, dir.join(BINARY_NAME)));
+    }
+    candidates
+}
+
+/// Directories that may hold `share/openvpn/bin`: the install prefix next to
+/// the executable, a source checkout above `target/<profile>`, and the working
+/// directory.
+fn bundled_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Ok(exe) = env::current_exe() {
+        dirs.extend(exe.ancestors().skip(1).take(3).map(Path::to_path_buf));
+    }
+    if let Ok(cwd) = env::current_dir() {
+        if !dirs.contains(&cwd) {
+            dirs.push(cwd);
+        }
+    }
+    dirs
+}
+
+/// Runs `openvpn --config <probe> --version` with a config made of one long
+/// comment. Stock OpenVPN rejects the line before printing its version.
+fn probe(source: Source, path: PathBuf) -> Result<OpenVpn, BinaryError> {
+    let output = TempDir::with_prefix("openaws-vpn-client-").and_then(|dir| {
+        let config = dir.child("probe.conf");
+        std::fs::write(&config, format!("# {}\n", "x".repeat(PATCH_PROBE_LINE)))?;
+        Command::new(&path)
+            .arg("--config")
+            .arg(&config)
+            .arg("--version")
+            .output()
+    });
+    let output = match output {
+        Ok(o) => o,
+        Err(e) => return Err(BinaryError::Probe(path, e)),
+    };
+    let text = String::from_utf8_lossy(&output.stdout).to_string()
+        + &String::from_utf8_lossy(&output.stderr);
+
+    match parse_probe(&text) {
+        Probe::Patched(version) => Ok(OpenVpn {
+            path,
+            source,
+            version,
+        }),
+        Probe::Unpatched => {
+            let version = Command::new(&path)
+                .arg("--version")
+                .output()
+                .ok()
+                .and_then(|o| version_line(&String::from_utf8_lossy(&o.stdout)))
+                .unwrap_or_else(|| "unknown version".to_string());
+            Err(BinaryError::Unpatched { path, version })
+        }
+        Probe::Unknown => Err(BinaryError::NotOpenVpn(path)),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Probe {
+    Patched(String),
+    Unpatched,
+    Unknown,
+}
+
+fn parse_probe(output: &str) -> Probe {
+    if output.contains("Maximum option line length") {
+        return Probe::Unpatched;
+    }
+    match version_line(output) {
+        Some(version) => Probe::Patched(version),
+        None => Probe::Unknown,
+    }
+}
+
+/// `OpenVPN 2.5.1 x86_64-pc-linux-gnu [SSL (OpenSSL)] ...` -> `OpenVPN 2.5.1`
+fn version_line(output: &str) -> Option<String> {
+    let line = output.lines().find(|l| l.starts_with("OpenVPN "))?;
+    let version = line.split_whitespace().nth(1)?;
+    Some(format!("OpenVPN {}", version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn searches_in_order() {
+        let found = candidates(
+            Some(Path::new("/opt/aws/openvpn")),
+            Some(PathBuf::from("/env/openvpn")),
+            &[PathBuf::from("/usr/lib/openaws")],
+            Some(env::join_paths(["/usr/local/bin", "/usr/bin"]).unwrap()),
+        );
+
+        let sources: Vec<_> = found.iter().map(|(s, _)| *s).take(4).collect();
+        assert_eq!(
+            sources,
+            [
+                Source::Setting,
+                Source::Environment,
+                Source::Bundled,
+                Source::Path
+            ]
+        );
+        assert_eq!(
+            found[2].1,
+            Path::new("/usr/lib/openaws/share/openvpn/bin").join(BINARY_NAME)
+        );
+        assert_eq!(found[3].1, Path::new("/usr/local/bin").join(BINARY_NAME));
+        // sbin is searched after PATH
+        assert_eq!(found[5].1, Path::new("/usr/local/sbin").join(BINARY_NAME));
+
+        let empty = candidates(None, Some(PathBuf::new()), &[], None);
+        assert!(empty.iter().all(|(s, _)| *s == Source::Path));
+    }
+
+    #[test]
+    fn detects_the_aws_patch() {
+        assert_eq!(
+            parse_probe(
+                "OpenVPN 2.5.1 x86_64-pc-linux-gnu [SSL (OpenSSL)] [LZO] [LZ4] [EPOLL] built on Mar  4 2024\n\
+                 library versions: OpenSSL 3.0.2 15 Mar 2022, LZO 2.10\n"
+            ),
+            Probe::Patched("OpenVPN 2.5.1".to_string())
+        );
+        assert_eq!(
+            parse_probe(
+                "Options error: In /tmp/openaws-vpn-client-x/probe.conf:1: Maximum option \
+                 line length (256) exceeded, line starts with # xxxxxxxx\n\
+                 Use --help for more information.\n"
+            ),
+            Probe::Unpatched
+        );
+        assert_eq!(parse_probe("sh: 1: not found\n"), Probe::Unknown);
+    }
+
+    /// Runs the probe against real builds of OpenVPN with and without the
+    /// patch of build-mobile/openvpn-aws.patch, given in the environment.
+    #[test]
+    #[ignore = "needs OPENAWS_TEST_PATCHED_OPENVPN and OPENAWS_TEST_STOCK_OPENVPN"]
+    fn probes_real_builds() {
+        let build = |var: &str| PathBuf::from(env::var_os(var).expect(var));
+        let patched = build("OPENAWS_TEST_PATCHED_OPENVPN");
+        let stock = build("OPENAWS_TEST_STOCK_OPENVPN");
+
+        let found = probe(Source::Environment, patched.clone()).unwrap();
+        assert_eq!(found.path, patched);
+        assert!(found.version.starts_with("OpenVPN 2."), "{}", found.version);
+
+        match probe(Source::Path, stock.clone()) {
+            Err(BinaryError::Unpatched { path, version }) => {
+                assert_eq!(path, stock);
+                assert!(version.starts_with("OpenVPN 2."), "{}", version);
+            }
+            other => panic!("stock OpenVPN accepted: {:?}", other),
+        }
+    }
+}
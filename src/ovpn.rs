@@ -0,0 +1,557 @@
+use std::fmt;
+
+/// Position of an entry inside an `.ovpn` file, both 1-based.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub span: Span,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.span, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// A single option line, e.g. `remote cvpn-endpoint-xxx.amazonaws.com 443`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Directive {
+    pub name: String,
+    pub args: Vec<String>,
+    pub span: Span,
+    raw: String,
+}
+
+impl Directive {
+    pub fn arg(&self, index: usize) -> Option<&str> {
+        self.args.get(index).map(|a| a.as_str())
+    }
+}
+
+/// An inline file such as `<ca>...</ca>`. `body` holds everything between the tags.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Block {
+    pub tag: String,
+    pub body: String,
+    pub span: Span,
+    raw: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Entry {
+    Directive(Directive),
+    Block(Block),
+    Comment {
+        text: String,
+        span: Span,
+        raw: String,
+    },
+    Blank {
+        raw: String,
+    },
+}
+
+impl Entry {
+    /// The exact source text of this entry, line terminators included.
+    pub fn raw(&self) -> &str {
+        match self {
+            Entry::Directive(d) => &d.raw,
+            Entry::Block(b) => &b.raw,
+            Entry::Comment { raw, .. } => raw,
+            Entry::Blank { raw } => raw,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Proto {
+    Udp,
+    Tcp,
+}
+
+impl Proto {
+    pub fn parse(value: &str) -> Option<Proto> {
+        match value {
+            "udp" | "udp4" | "udp6" => Some(Proto::Udp),
+            "tcp" | "tcp4" | "tcp6" | "tcp-client" | "tcp4-client" | "tcp6-client" => {
+                Some(Proto::Tcp)
+            }
+            _ => None,
+        }
+    }
+
+    /// Value passed to OpenVPN's `--proto` for a client connection to an
+    /// IPv4 or IPv6 address.
+    pub fn as_arg(&self, ipv6: bool) -> &'static str {
+        match (self, ipv6) {
+            (Proto::Udp, false) => "udp",
+            (Proto::Udp, true) => "udp6",
+            (Proto::Tcp, false) => "tcp-client",
+            (Proto::Tcp, true) => "tcp6-client",
+        }
+    }
+}
+
+impl fmt::Display for Proto {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Proto::Udp => f.write_str("udp"),
+            Proto::Tcp => f.write_str("tcp"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Remote {
+    pub host: String,
+    pub port: Option<u16>,
+    pub proto: Option<Proto>,
+    pub span: Span,
+}
+
+/// Parsed OpenVPN profile. Every entry keeps its original text, so writing
+/// the profile back out reproduces the input byte for byte.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OvpnProfile {
+    pub entries: Vec<Entry>,
+}
+
+impl OvpnProfile {
+    pub fn parse(content: &str) -> Result<OvpnProfile, ParseError> {
+        let mut entries = Vec::new();
+        let mut lines = RawLines::new(content);
+
+        while let Some((number, raw)) = lines.next() {
+            let text = raw.trim_end_matches(['\r', '\n']);
+            let trimmed = text.trim_start();
+            let column = text.len() - trimmed.len() + 1;
+            let span = Span {
+                line: number,
+                column,
+            };
+
+            if trimmed.is_empty() {
+                entries.push(Entry::Blank {
+                    raw: raw.to_string(),
+                });
+            } else if trimmed.starts_with('#') || trimmed.starts_with(';') {
+                entries.push(Entry::Comment {
+                    text: trimmed[1..].trim().to_string(),
+                    span,
+                    raw: raw.to_string(),
+                });
+            } else if let Some(rest) = trimmed.strip_prefix("</") {
+                return Err(ParseError {
+                    span,
+                    message: format!("closing tag '</{}' without an opening tag", rest),
+                });
+            } else if trimmed.starts_with('<') {
+                let tag = parse_open_tag(trimmed).ok_or_else(|| ParseError {
+                    span,
+                    message: format!("malformed inline block tag '{}'", trimmed.trim_end()),
+                })?;
+
+                let close = format!("</{}>", tag);
+                let mut block_raw = raw.to_string();
+                let mut body = String::new();
+                let mut closed = false;
+
+                for (inner_number, inner) in lines.by_ref() {
+                    block_raw.push_str(inner);
+                    let inner_text = inner.trim_end_matches(['\r', '\n']).trim();
+                    if inner_text == close {
+                        closed = true;
+                        break;
+                    }
+                    if inner_text.starts_with("</") {
+                        return Err(ParseError {
+                            span: Span {
+                                line: inner_number,
+                                column: 1,
+                            },
+                            message: format!("expected '{}' but found '{}'", close, inner_text),
+                        });
+                    }
+                    body.push_str(inner);
+                }
+
+                if !closed {
+                    return Err(ParseError {
+                        span,
+                        message: format!("inline block <{}> is never closed", tag),
+                    });
+                }
+
+                entries.push(Entry::Block(Block {
+                    tag,
+                    body,
+                    span,
+                    raw: block_raw,
+                }));
+            } else {
+                let tokens = tokenize(trimmed, span)?;
+                let mut tokens = tokens.into_iter();
+                let name = tokens.next().unwrap_or_default();
+                entries.push(Entry::Directive(Directive {
+                    name,
+                    args: tokens.collect(),
+                    span,
+                    raw: raw.to_string(),
+                }));
+            }
+        }
+
+        Ok(OvpnProfile { entries })
+    }
+
+    pub fn directives(&self) -> impl Iterator<Item = &Directive> {
+        self.entries.iter().filter_map(|e| match e {
+            Entry::Directive(d) => Some(d),
+            _ => None,
+        })
+    }
+
+    pub fn directives_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Directive> {
+        self.directives().filter(move |d| d.name == name)
+    }
+
+    pub fn directive<'a>(&'a self, name: &'a str) -> Option<&'a Directive> {
+        self.directives_named(name).next()
+    }
+
+    /// All `remote` entries in file order. Port and protocol are only set when
+    /// the `remote` line itself carries them.
+    pub fn remotes(&self) -> Vec<Remote> {
+        self.directives_named("remote")
+            .filter_map(|d| {
+                let host = d.arg(0)?.to_string();
+                Some(Remote {
+                    host,
+                    port: d.arg(1).and_then(|p| p.parse::<u16>().ok()),
+                    proto: d.arg(2).and_then(Proto::parse),
+                    span: d.span,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns a copy of the profile without the directives matched by `drop`.
+    pub fn without<F>(&self, drop: F) -> OvpnProfile
+    where
+        F: Fn(&Directive) -> bool,
+    {
+        OvpnProfile {
+            entries: self
+                .entries
+                .iter()
+                .filter(|e| match e {
+                    Entry::Directive(d) => !drop(d),
+                    _ => true,
+                })
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Display for OvpnProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for entry in &self.entries {
+            f.write_str(entry.raw())?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits text into lines while keeping their terminators.
+struct RawLines<'a> {
+    rest: &'a str,
+    number: usize,
+}
+
+impl<'a> RawLines<'a> {
+    fn new(content: &'a str) -> Self {
+        Self {
+            rest: content,
+            number: 0,
+        }
+    }
+}
+
+impl<'a> Iterator for RawLines<'a> {
+    type Item = (usize, &'a str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rest.is_empty() {
+            return None;
+        }
+
+        let end = self
+            .rest
+            .find('\n')
+            .map(|i| i + 1)
+            .unwrap_or(self.rest.len());
+        let (line, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        self.number += 1;
+        Some((self.number, line))
+    }
+}
+
+fn parse_open_tag(text: &str) -> Option<String> {
+    let inner = text.trim_end().strip_prefix('<')?.strip_suffix('>')?;
+    if inner.is_empty()
+        || !inner
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return None;
+    }
+    Some(inner.to_string())
+}
+
+/// Splits a directive line the way OpenVPN does: whitespace separated,
+/// with single quotes taken literally and backslash escapes inside double quotes.
+/// A `#` or `;` starting a token begins a trailing comment.
+fn tokenize(text: &str, span: Span) -> Result<Vec<String>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = text.char_indices().peekable();
+
+    while let Some(&(_, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        if c == '#' || c == ';' {
+            break;
+        }
+
+        let mut token = String::new();
+        while let Some(&(index, c)) = chars.peek() {
+            if c.is_whitespace() {
+                break;
+            }
+            chars.next();
+
+            match c {
+                '"' | '\'' => {
+                    let quote = c;
+                    let mut closed = false;
+                    while let Some((_, c)) = chars.next() {
+                        if c == quote {
+                            closed = true;
+                            break;
+                        }
+                        if c == '\\' && quote == '"' {
+                            if let Some((_, escaped)) = chars.next() {
+                                token.push(escaped);
+                            }
+                            continue;
+                        }
+                        token.push(c);
+                    }
+                    if !closed {
+                        return Err(ParseError {
+                            span: Span {
+                                line: span.line,
+                                column: span.column + index,
+                            },
+                            message: format!("unterminated {} quote", quote),
+                        });
+                    }
+                }
+                '\\' => {
+                    if let Some((_, escaped)) = chars.next() {
+                        token.push(escaped);
+                    }
+                }
+                _ => token.push(c),
+            }
+        }
+
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> String {
+        let path = format!("{}/fixtures/profiles/{}", env!("CARGO_MANIFEST_DIR"), name);
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    fn error(content: &str) -> ParseError {
+        OvpnProfile::parse(content).unwrap_err()
+    }
+
+    #[test]
+    fn round_trips_an_aws_profile() {
+        let content = fixture("aws.ovpn");
+        let profile = OvpnProfile::parse(&content).unwrap();
+        assert_eq!(profile.to_string(), content);
+
+        let block = profile
+            .entries
+            .iter()
+            .find_map(|e| match e {
+                Entry::Block(b) => Some(b),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(block.tag, "ca");
+        assert_eq!(
+            block.span,
+            Span {
+                line: 11,
+                column: 1
+            }
+        );
+        assert!(block.body.starts_with("-----BEGIN CERTIFICATE-----\n"));
+        assert!(block.body.ends_with("-----END CERTIFICATE-----\n"));
+
+        let comments = profile
+            .entries
+            .iter()
+            .filter_map(|e| match e {
+                Entry::Comment { text, span, .. } => Some((text.as_str(), span.line)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            comments,
+            [
+                (
+                    "Added by the administrator: keep the tunnel up while idle",
+                    30
+                ),
+                ("ping 10", 31)
+            ]
+        );
+
+        let verb = profile.directive("verb").unwrap();
+        assert_eq!(verb.args, ["3"]);
+        assert_eq!(
+            verb.span,
+            Span {
+                line: 10,
+                column: 1
+            }
+        );
+    }
+
+    #[test]
+    fn keeps_line_endings_and_indentation() {
+        let content = "client\r\n  dev tun\r\n\r\n<ca>\r\nabc\r\n</ca>\r\nverb 3";
+        let profile = OvpnProfile::parse(content).unwrap();
+        assert_eq!(profile.to_string(), content);
+        assert_eq!(
+            profile.directive("dev").unwrap().span,
+            Span { line: 2, column: 3 }
+        );
+        assert_eq!(profile.directive("verb").unwrap().args, ["3"]);
+    }
+
+    #[test]
+    fn tokenizes_quotes_and_trailing_comments() {
+        let profile =
+            OvpnProfile::parse("setenv NAME \"a \\\"b\\\"\" 'c\\d' # note\nverb 3 ;x\n").unwrap();
+        assert_eq!(
+            profile.directive("setenv").unwrap().args,
+            ["NAME", "a \"b\"", "c\\d"]
+        );
+        assert_eq!(profile.directive("verb").unwrap().args, ["3"]);
+    }
+
+    #[test]
+    fn drops_directives_and_keeps_the_rest_verbatim() {
+        let content = fixture("aws.ovpn");
+        let profile = OvpnProfile::parse(&content).unwrap();
+        let stripped = profile.without(|d| d.name.starts_with("auth-"));
+
+        assert_eq!(stripped.directive("auth-user-pass"), None);
+        assert_eq!(stripped.directive("auth-federate"), None);
+        assert!(stripped.directive("remote").is_some());
+        let expected = content
+            .lines()
+            .filter(|l| !l.starts_with("auth-"))
+            .fold(String::new(), |acc, l| acc + l + "\n");
+        assert_eq!(stripped.to_string(), expected);
+        // The original is left alone
+        assert_eq!(profile.to_string(), content);
+    }
+
+    #[test]
+    fn lists_remotes_in_file_order() {
+        let profile = OvpnProfile::parse(
+            "remote a.example.com 443 udp\n\
+             remote b.example.com\n\
+             remote\n\
+             remote c.example.com 1194 tcp-client\n\
+             remote d.example.com abc quic\n",
+        )
+        .unwrap();
+        let remotes = profile
+            .remotes()
+            .into_iter()
+            .map(|r| (r.host, r.port, r.proto, r.span.line))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            remotes,
+            [
+                ("a.example.com".to_string(), Some(443), Some(Proto::Udp), 1),
+                ("b.example.com".to_string(), None, None, 2),
+                ("c.example.com".to_string(), Some(1194), Some(Proto::Tcp), 4),
+                ("d.example.com".to_string(), None, None, 5),
+            ]
+        );
+        assert!(OvpnProfile::parse("client\n").unwrap().remotes().is_empty());
+    }
+
+    #[test]
+    fn reports_where_parsing_failed() {
+        let unclosed = error("client\n<ca>\nabc\n");
+        assert_eq!(unclosed.span, Span { line: 2, column: 1 });
+        assert_eq!(
+            unclosed.to_string(),
+            "2:1: inline block <ca> is never closed"
+        );
+
+        let mismatched = error("<ca>\nabc\n</cert>\n");
+        assert_eq!(mismatched.span, Span { line: 3, column: 1 });
+        assert_eq!(mismatched.message, "expected '</ca>' but found '</cert>'");
+
+        let stray = error("client\n  </key>\n");
+        assert_eq!(stray.span, Span { line: 2, column: 3 });
+
+        let malformed = error("<ca x>\n</ca>\n");
+        assert_eq!(malformed.span, Span { line: 1, column: 1 });
+
+        let quote = error("verb 3\n  setenv NAME \"abc\n");
+        assert_eq!(
+            quote.span,
+            Span {
+                line: 2,
+                column: 15
+            }
+        );
+        assert_eq!(quote.message, "unterminated \" quote");
+    }
+}
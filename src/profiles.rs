@@ -0,0 +1,317 @@
+use crate::ovpn::{OvpnProfile, ParseError};
+use crate::saml::format_time;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Extension of the stored profiles, their metadata sits next to them as `<name>.toml`.
+const PROFILE_EXTENSION: &str = "ovpn";
+const META_EXTENSION: &str = "toml";
+
+#[derive(Debug)]
+pub enum ProfileError {
+    Io(io::Error),
+    Parse(ParseError),
+    Meta(String),
+    NoRemote,
+    InvalidName(String),
+    NotFound(String),
+    Exists(String),
+}
+
+impl fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProfileError::Io(e) => write!(f, "profile store: {}", e),
+            ProfileError::Parse(e) => write!(f, "invalid profile at {}", e),
+            ProfileError::Meta(e) => write!(f, "invalid profile metadata: {}", e),
+            ProfileError::NoRemote => write!(f, "profile has no 'remote' directive"),
+            ProfileError::InvalidName(name) => write!(
+                f,
+                "invalid profile name '{}', use letters, digits, '.', '_' and '-'",
+                name
+            ),
+            ProfileError::NotFound(name) => write!(f, "no profile named '{}'", name),
+            ProfileError::Exists(name) => write!(f, "a profile named '{}' already exists", name),
+        }
+    }
+}
+
+impl std::error::Error for ProfileError {}
+
+impl From<io::Error> for ProfileError {
+    fn from(e: io::Error) -> Self {
+        ProfileError::Io(e)
+    }
+}
+
+impl From<ParseError> for ProfileError {
+    fn from(e: ParseError) -> Self {
+        ProfileError::Parse(e)
+    }
+}
+
+/// Outcome of the last `connect` with a profile.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Connected,
+    Failed,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Connected => f.write_str("connected"),
+            Outcome::Failed => f.write_str("failed"),
+        }
+    }
+}
+
+/// What is known about a stored profile. Times are seconds since the epoch.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Metadata {
+    /// File the profile was imported from.
+    pub source: PathBuf,
+    /// SHA-256 of the stored profile.
+    pub checksum: String,
+    pub imported: u64,
+    pub last_used: Option<u64>,
+    pub last_result: Option<Outcome>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Profile {
+    pub name: String,
+    pub path: PathBuf,
+    pub meta: Metadata,
+}
+
+impl Profile {
+    pub fn remotes(&self) -> Result<Vec<String>, ProfileError> {
+        let content = std::fs::read_to_string(&self.path)?;
+        Ok(OvpnProfile::parse(&content)?
+            .remotes()
+            .into_iter()
+            .map(|r| r.host)
+            .collect())
+    }
+
+    /// Whether the file it was imported from still has the same content.
+    pub fn source_changed(&self) -> Option<bool> {
+        let content = std::fs::read(&self.meta.source).ok()?;
+        Some(checksum(&content) != self.meta.checksum)
+    }
+}
+
+/// Named profiles kept in `<data_local_dir>/openaws-vpn-client/profiles`.
+pub struct ProfileStore {
+    dir: PathBuf,
+}
+
+impl ProfileStore {
+    pub fn open() -> ProfileStore {
+        let dir = dirs::data_local_dir()
+            .unwrap_or_else(std::env::temp_dir)
+            .join("openaws-vpn-client")
+            .join("profiles");
+        ProfileStore::at(dir)
+    }
+
+    fn at(dir: PathBuf) -> ProfileStore {
+        ProfileStore { dir }
+    }
+
+    /// Copies `file` into the store. The profile must parse and have a remote.
+    pub fn import(&self, file: &Path, name: &str, replace: bool) -> Result<Profile, ProfileError> {
+        check_name(name)?;
+        if !replace && self.profile_path(name).exists() {
+            return Err(ProfileError::Exists(name.to_string()));
+        }
+
+        let content = std::fs::read(file)?;
+        let profile = OvpnProfile::parse(&String::from_utf8_lossy(&content))?;
+        if profile.remotes().is_empty() {
+            return Err(ProfileError::NoRemote);
+        }
+
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(self.profile_path(name), &content)?;
+        let meta = Metadata {
+            source: std::fs::canonicalize(file).unwrap_or_else(|_| file.to_path_buf()),
+            checksum: checksum(&content),
+            imported: now(),
+            last_used: None,
+            last_result: None,
+        };
+        self.write_meta(name, &meta)?;
+        self.get(name)
+    }
+
+    pub fn get(&self, name: &str) -> Result<Profile, ProfileError> {
+        check_name(name)?;
+        let path = self.profile_path(name);
+        if !path.exists() {
+            return Err(ProfileError::NotFound(name.to_string()));
+        }
+        let text = std::fs::read_to_string(self.meta_path(name))?;
+        let meta = toml::from_str(&text).map_err(|e| ProfileError::Meta(e.to_string()))?;
+        Ok(Profile {
+            name: name.to_string(),
+            path,
+            meta,
+        })
+    }
+
+    /// Stored profiles sorted by name. Unreadable entries are skipped.
+    pub fn list(&self) -> Result<Vec<Profile>, ProfileError> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut profiles: Vec<Profile> = entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.extension().map_or(false, |e| e == PROFILE_EXTENSION))
+            .filter_map(|p| self.get(p.file_stem()?.to_str()?).ok())
+            .collect();
+        profiles.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(profiles)
+    }
+
+    pub fn remove(&self, name: &str) -> Result<(), ProfileError> {
+        self.get(name)?;
+        std::fs::remove_file(self.profile_path(name))?;
+        let _ = std::fs::remove_file(self.meta_path(name));
+        Ok(())
+    }
+
+    pub fn rename(&self, from: &str, to: &str) -> Result<Profile, ProfileError> {
+        self.get(from)?;
+        check_name(to)?;
+        if self.profile_path(to).exists() {
+            return Err(ProfileError::Exists(to.to_string()));
+        }
+        std::fs::rename(self.profile_path(from), self.profile_path(to))?;
+        std::fs::rename(self.meta_path(from), self.meta_path(to))?;
+        self.get(to)
+    }
+
+    /// Records a `connect` with the profile.
+    pub fn record(&self, name: &str, outcome: Outcome) -> Result<(), ProfileError> {
+        let mut profile = self.get(name)?;
+        profile.meta.last_used = Some(now());
+        profile.meta.last_result = Some(outcome);
+        self.write_meta(name, &profile.meta)
+    }
+
+    fn write_meta(&self, name: &str, meta: &Metadata) -> Result<(), ProfileError> {
+        let text = toml::to_string(meta).map_err(|e| ProfileError::Meta(e.to_string()))?;
+        std::fs::write(self.meta_path(name), text)?;
+        Ok(())
+    }
+
+    fn profile_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", name, PROFILE_EXTENSION))
+    }
+
+    fn meta_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", name, META_EXTENSION))
+    }
+}
+
+/// Names end up in file names, so path separators and leading dots are refused.
+fn check_name(name: &str) -> Result<(), ProfileError> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    if valid {
+        Ok(())
+    } else {
+        Err(ProfileError::InvalidName(name.to_string()))
+    }
+}
+
+fn checksum(content: &[u8]) -> String {
+    hex::encode(Sha256::digest(content))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn format_secs(secs: u64) -> String {
+    format_time(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    const PROFILE: &str = "client\nremote cvpn-endpoint-0123.prod.clientvpn.eu-west-1.amazonaws.com 443\nauth-federate\n";
+
+    #[test]
+    fn manages_profiles() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.child("downloaded.ovpn");
+        std::fs::write(&source, PROFILE).unwrap();
+        let store = ProfileStore::at(dir.child("profiles"));
+        assert!(store.list().unwrap().is_empty());
+
+        let prod = store.import(&source, "prod", false).unwrap();
+        assert_eq!(prod.meta.checksum, checksum(PROFILE.as_bytes()));
+        assert_eq!(prod.source_changed(), Some(false));
+        assert!(matches!(
+            store.import(&source, "prod", false),
+            Err(ProfileError::Exists(_))
+        ));
+        store.import(&source, "staging", false).unwrap();
+
+        store.record("prod", Outcome::Failed).unwrap();
+        let prod = store.get("prod").unwrap();
+        assert_eq!(prod.meta.last_result, Some(Outcome::Failed));
+        assert!(prod.meta.last_used.is_some());
+
+        store.rename("prod", "customer").unwrap();
+        store.remove("staging").unwrap();
+        let names: Vec<_> = store.list().unwrap().into_iter().map(|p| p.name).collect();
+        assert_eq!(names, ["customer"]);
+        assert_eq!(
+            store.get("customer").unwrap().meta.last_result,
+            Some(Outcome::Failed)
+        );
+
+        std::fs::write(&source, "remote other.example.com\n").unwrap();
+        assert_eq!(store.get("customer").unwrap().source_changed(), Some(true));
+    }
+
+    #[test]
+    fn refuses_bad_names_and_profiles() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.child("empty.ovpn");
+        std::fs::write(&source, "client\n").unwrap();
+        let store = ProfileStore::at(dir.child("profiles"));
+
+        for name in ["", "../prod", ".hidden", "a b"] {
+            assert!(matches!(
+                store.import(&source, name, false),
+                Err(ProfileError::InvalidName(_))
+            ));
+        }
+        assert!(matches!(
+            store.import(&source, "empty", false),
+            Err(ProfileError::NoRemote)
+        ));
+        assert!(matches!(store.get("prod"), Err(ProfileError::NotFound(_))));
+    }
+}
@@ -0,0 +1,411 @@
+use base64::Engine;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Tolerated difference between our clock and the identity provider's.
+const CLOCK_SKEW: Duration = Duration::from_secs(120);
+
+const STATUS_SUCCESS: &str = "urn:oasis:names:tc:SAML:2.0:status:Success";
+
+/// What the identity provider asserted about the login, read from the
+/// `SAMLResponse` posted by the browser. The signature is checked by the VPN
+/// server, this is only used to catch bad logins early and report the session.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Assertion {
+    pub issuer: Option<String>,
+    pub name_id: Option<String>,
+    pub audiences: Vec<String>,
+    pub not_before: Option<SystemTime>,
+    pub not_on_or_after: Option<SystemTime>,
+    /// `SessionNotOnOrAfter` of the authentication statement.
+    pub session_not_on_or_after: Option<SystemTime>,
+    pub status: String,
+    pub status_message: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum SamlError {
+    Base64(base64::DecodeError),
+    Utf8,
+    Xml(roxmltree::Error),
+    NotAResponse,
+    Status {
+        status: String,
+        message: Option<String>,
+    },
+    NotYetValid(SystemTime),
+    Expired(SystemTime),
+}
+
+impl fmt::Display for SamlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SamlError::Base64(e) => write!(f, "invalid base64: {}", e),
+            SamlError::Utf8 => write!(f, "response is not valid UTF-8"),
+            SamlError::Xml(e) => write!(f, "invalid XML: {}", e),
+            SamlError::NotAResponse => write!(f, "not a SAML response"),
+            SamlError::Status { status, message } => {
+                let status = status.rsplit(':').next().unwrap_or(status);
+                match message {
+                    Some(message) => write!(f, "login failed with {}: {}", status, message),
+                    None => write!(f, "login failed with {}", status),
+                }
+            }
+            SamlError::NotYetValid(at) => {
+                write!(f, "assertion is not valid before {}", format_time(*at))
+            }
+            SamlError::Expired(at) => write!(f, "assertion expired at {}", format_time(*at)),
+        }
+    }
+}
+
+impl std::error::Error for SamlError {}
+
+impl From<base64::DecodeError> for SamlError {
+    fn from(e: base64::DecodeError) -> Self {
+        SamlError::Base64(e)
+    }
+}
+
+impl From<roxmltree::Error> for SamlError {
+    fn from(e: roxmltree::Error) -> Self {
+        SamlError::Xml(e)
+    }
+}
+
+impl Assertion {
+    /// Decodes the base64 `SAMLResponse` form field.
+    pub fn parse(encoded: &str) -> Result<Assertion, SamlError> {
+        let compact: String = encoded.chars().filter(|c| !c.is_whitespace()).collect();
+        let xml = base64::engine::general_purpose::STANDARD.decode(compact)?;
+        let xml = String::from_utf8(xml).map_err(|_| SamlError::Utf8)?;
+        Assertion::from_xml(&xml)
+    }
+
+    pub fn from_xml(xml: &str) -> Result<Assertion, SamlError> {
+        let doc = roxmltree::Document::parse(xml)?;
+        let response = doc.root_element();
+        if response.tag_name().name() != "Response" {
+            return Err(SamlError::NotAResponse);
+        }
+
+        // Elements are matched by local name, identity providers differ in prefixes
+        let find = |name: &str| response.descendants().find(|n| n.tag_name().name() == name);
+        let text = |name: &str| {
+            find(name)
+                .and_then(|n| n.text())
+                .map(|t| t.trim().to_string())
+        };
+        let time = |element: &str, attribute: &str| {
+            find(element)
+                .and_then(|n| n.attribute(attribute))
+                .and_then(parse_time)
+        };
+
+        let status = find("Status");
+        let status_code = status
+            .and_then(|s| s.children().find(|n| n.tag_name().name() == "StatusCode"))
+            .and_then(|n| n.attribute("Value"))
+            .unwrap_or("")
+            .to_string();
+        let status_message = status
+            .and_then(|s| {
+                s.children()
+                    .find(|n| n.tag_name().name() == "StatusMessage")
+            })
+            .and_then(|n| n.text())
+            .map(|t| t.trim().to_string());
+
+        // The assertion issuer is the one that matters, the response may carry its own
+        let issuer = find("Assertion")
+            .and_then(|a| a.children().find(|n| n.tag_name().name() == "Issuer"))
+            .and_then(|n| n.text())
+            .map(|t| t.trim().to_string())
+            .or_else(|| text("Issuer"));
+
+        Ok(Assertion {
+            issuer,
+            name_id: text("NameID"),
+            audiences: response
+                .descendants()
+                .filter(|n| n.tag_name().name() == "Audience")
+                .filter_map(|n| n.text())
+                .map(|t| t.trim().to_string())
+                .collect(),
+            not_before: time("Conditions", "NotBefore"),
+            not_on_or_after: time("Conditions", "NotOnOrAfter"),
+            session_not_on_or_after: time("AuthnStatement", "SessionNotOnOrAfter"),
+            status: status_code,
+            status_message,
+        })
+    }
+
+    /// Rejects failed logins and assertions outside their validity window.
+    pub fn validate(&self, now: SystemTime) -> Result<(), SamlError> {
+        if self.status != STATUS_SUCCESS {
+            return Err(SamlError::Status {
+                status: self.status.clone(),
+                message: self.status_message.clone(),
+            });
+        }
+
+        if let Some(not_before) = self.not_before {
+            if now + CLOCK_SKEW < not_before {
+                return Err(SamlError::NotYetValid(not_before));
+            }
+        }
+        if let Some(not_on_or_after) = self.not_on_or_after {
+            if now >= not_on_or_after + CLOCK_SKEW {
+                return Err(SamlError::Expired(not_on_or_after));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// When the identity provider wants the user to log in again.
+    pub fn session_expiry(&self) -> Option<SystemTime> {
+        self.session_not_on_or_after
+    }
+
+    pub fn identity(&self) -> String {
+        match (&self.name_id, &self.issuer) {
+            (Some(name), Some(issuer)) => format!("{} ({})", name, issuer),
+            (Some(name), None) => name.clone(),
+            (None, Some(issuer)) => format!("unknown user ({})", issuer),
+            (None, None) => "unknown user".to_string(),
+        }
+    }
+}
+
+/// Parses an `xs:dateTime`, e.g. `2024-05-01T12:00:00.123Z` or with a `+02:00` offset.
+fn parse_time(value: &str) -> Option<SystemTime> {
+    let value = value.trim();
+    let (date, time) = value.split_once('T')?;
+
+    let mut date = date.splitn(3, '-');
+    let year: i64 = date.next()?.parse().ok()?;
+    let month: u32 = date.next()?.parse().ok()?;
+    let day: u32 = date.next()?.parse().ok()?;
+
+    let (time, offset) = if let Some(time) = time.strip_suffix('Z') {
+        (time, 0)
+    } else if let Some(i) = time.rfind(['+', '-']) {
+        let (h, m) = time[i + 1..].split_once(':')?;
+        let offset = h.parse::<i64>().ok()? * 3600 + m.parse::<i64>().ok()? * 60;
+        let sign = if time.as_bytes()[i] == b'-' { -1 } else { 1 };
+        (&time[..i], sign * offset)
+    } else {
+        (time, 0)
+    };
+
+    let mut time = time.splitn(3, ':');
+    let hour: i64 = digits(time.next()?)?;
+    let minute: i64 = digits(time.next()?)?;
+    let (second, fraction) = match time.next()? {
+        second if second.contains('.') => second.split_once('.')?,
+        second => (second, "0"),
+    };
+    let second: i64 = digits(second)?;
+    // Digits past nanoseconds are dropped
+    let fraction = &fraction[..fraction.len().min(9)];
+    let nanos = digits::<u32>(fraction)? * 10u32.pow(9 - fraction.len() as u32);
+
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+
+    let secs =
+        days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second - offset;
+    let secs = u64::try_from(secs).ok()?;
+    Some(UNIX_EPOCH + Duration::new(secs, nanos))
+}
+
+/// A number written with ASCII digits only, without sign or exponent.
+fn digits<T: std::str::FromStr>(value: &str) -> Option<T> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Formats a time as UTC, `2024-05-01 12:00:00 UTC`.
+pub fn format_time(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let secs = secs.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESPONSE: &str = r#"<samlp:Response xmlns:samlp="urn:oasis:names:tc:SAML:2.0:protocol" xmlns:saml="urn:oasis:names:tc:SAML:2.0:assertion" ID="_r1" Version="2.0" IssueInstant="2024-05-01T12:00:00Z">
+  <saml:Issuer>https://idp.example.com/response</saml:Issuer>
+  <samlp:Status><samlp:StatusCode Value="urn:oasis:names:tc:SAML:2.0:status:Success"/></samlp:Status>
+  <saml:Assertion ID="_a1" Version="2.0" IssueInstant="2024-05-01T12:00:00Z">
+    <saml:Issuer>https://idp.example.com/</saml:Issuer>
+    <saml:Subject>
+      <saml:NameID Format="urn:oasis:names:tc:SAML:1.1:nameid-format:emailAddress">jane@example.com</saml:NameID>
+    </saml:Subject>
+    <saml:Conditions NotBefore="2024-05-01T11:59:00Z" NotOnOrAfter="2024-05-01T12:05:00.250Z">
+      <saml:AudienceRestriction><saml:Audience>urn:amazon:webservices:clientvpn</saml:Audience></saml:AudienceRestriction>
+    </saml:Conditions>
+    <saml:AuthnStatement AuthnInstant="2024-05-01T12:00:00Z" SessionNotOnOrAfter="2024-05-01T20:00:00+02:00"/>
+  </saml:Assertion>
+</samlp:Response>"#;
+
+    fn at(value: &str) -> SystemTime {
+        parse_time(value).unwrap()
+    }
+
+    #[test]
+    fn reads_the_assertion() {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(RESPONSE);
+        let assertion = Assertion::parse(&encoded).unwrap();
+
+        assert_eq!(
+            assertion.issuer.as_deref(),
+            Some("https://idp.example.com/")
+        );
+        assert_eq!(assertion.name_id.as_deref(), Some("jane@example.com"));
+        assert_eq!(
+            assertion.audiences,
+            vec!["urn:amazon:webservices:clientvpn"]
+        );
+        assert_eq!(assertion.not_before, Some(at("2024-05-01T11:59:00Z")));
+        assert_eq!(assertion.session_expiry(), Some(at("2024-05-01T18:00:00Z")));
+        assert_eq!(assertion.status, STATUS_SUCCESS);
+        assert!(assertion.validate(at("2024-05-01T12:01:00Z")).is_ok());
+    }
+
+    #[test]
+    fn rejects_expired_assertions() {
+        let assertion = Assertion::from_xml(RESPONSE).unwrap();
+
+        assert!(matches!(
+            assertion.validate(at("2024-05-01T12:10:00Z")),
+            Err(SamlError::Expired(_))
+        ));
+        assert!(matches!(
+            assertion.validate(at("2024-05-01T11:00:00Z")),
+            Err(SamlError::NotYetValid(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_error_status() {
+        let xml = r#"<samlp:Response xmlns:samlp="urn:oasis:names:tc:SAML:2.0:protocol">
+  <samlp:Status>
+    <samlp:StatusCode Value="urn:oasis:names:tc:SAML:2.0:status:Responder"/>
+    <samlp:StatusMessage>User is not assigned to this application</samlp:StatusMessage>
+  </samlp:Status>
+</samlp:Response>"#;
+        let err = Assertion::from_xml(xml)
+            .unwrap()
+            .validate(SystemTime::now())
+            .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "login failed with Responder: User is not assigned to this application"
+        );
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(matches!(
+            Assertion::parse("not base64!"),
+            Err(SamlError::Base64(_))
+        ));
+        assert!(matches!(
+            Assertion::from_xml("<html/>"),
+            Err(SamlError::NotAResponse)
+        ));
+    }
+
+    #[test]
+    fn parses_fractional_and_leap_seconds() {
+        assert_eq!(
+            at("2024-05-01T12:00:00.250Z"),
+            at("2024-05-01T12:00:00Z") + Duration::from_millis(250)
+        );
+        assert_eq!(
+            at("2024-05-01T12:00:00.1234567891Z"),
+            at("2024-05-01T12:00:00Z") + Duration::from_nanos(123_456_789)
+        );
+        assert_eq!(at("2024-06-30T23:59:60Z"), at("2024-07-01T00:00:00Z"));
+    }
+
+    #[test]
+    fn rejects_malformed_seconds() {
+        for value in [
+            "2024-05-01T12:00:-1Z",
+            "2024-05-01T12:00:NaNZ",
+            "2024-05-01T12:00:infZ",
+            "2024-05-01T12:00:1e3Z",
+            "2024-05-01T12:00:61Z",
+            "2024-05-01T12:00:+5Z",
+            "2024-05-01T12:00:.5Z",
+            "2024-05-01T12:00:05.Z",
+            "2024-05-01T12:00:05.-1Z",
+            "2024-05-01T-1:00:00Z",
+            "2024-05-01T12:-1:00Z",
+        ] {
+            assert_eq!(parse_time(value), None, "{}", value);
+        }
+
+        let xml = RESPONSE.replace("2024-05-01T11:59:00Z", "2024-05-01T12:00:-1Z");
+        assert!(Assertion::from_xml(&xml).is_ok_and(|a| a.not_before.is_none()));
+    }
+
+    #[test]
+    fn formats_times() {
+        assert_eq!(
+            format_time(at("2024-02-29T23:59:59Z")),
+            "2024-02-29 23:59:59 UTC"
+        );
+        assert_eq!(format_time(UNIX_EPOCH), "1970-01-01 00:00:00 UTC");
+    }
+}
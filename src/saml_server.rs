@@ -1,18 +1,22 @@
 use crate::app::VpnApp;
-use crate::cmd::{connect_ovpn, ProcessInfo};
 use crate::config::Pwd;
-use crate::state_manager::{self, StateManager};
-use crate::task::{OavcProcessTask, OavcTask};
+use crate::saml::{format_time, Assertion};
+use crate::task::OavcTask;
 use std::collections::HashMap;
+use std::fmt;
 use std::ops::Deref;
-use std::rc::Rc;
-use std::sync::mpsc::SyncSender;
+use std::sync::mpsc::{SyncSender, TrySendError};
 use std::sync::Arc;
+use std::time::SystemTime;
 use tokio::sync::Mutex;
 use warp::http::StatusCode;
+use warp::reject::MethodNotAllowed;
 use warp::reply::WithStatus;
 use warp::{Filter, Rejection};
 
+/// Largest form body accepted, SAML responses are usually a few dozen kilobytes.
+const MAX_BODY_SIZE: u64 = 1024 * 1024;
+
 pub struct SamlServer {}
 
 impl SamlServer {
@@ -20,11 +24,10 @@ impl SamlServer {
         SamlServer {}
     }
 
-    pub fn start_server(&self, app: Rc<VpnApp>) {
-        app.log.append("Starting SAML server at 0.0.0.0:35001...");
+    pub fn start_server(&self, app: Arc<VpnApp>) {
+        let listen = app.config.saml_listen();
         let (tx, rx) = std::sync::mpsc::sync_channel::<Saml>(1);
 
-        println!("Starting server");
         let sender = warp::any().map(move || tx.clone());
 
         let pwd = app.config.pwd.clone();
@@ -32,31 +35,53 @@ impl SamlServer {
         let runtime = app.runtime.clone();
 
         let saml = warp::post()
+            .and(warp::body::content_length_limit(MAX_BODY_SIZE))
             .and(warp::body::form())
             .and(sender)
             .and(pwd)
             .and_then(
-                move |data: HashMap<String, String>,
-                      sender: SyncSender<Saml>,
-                      pwd: Arc<Mutex<Option<Pwd>>>| {
-                    async move {
-                        let pwd = pwd.lock().await;
-                        let saml = Saml {
-                            data: data["SAMLResponse"].clone(),
-                            pwd: pwd.deref().as_ref().unwrap().pwd.clone(),
-                        };
-                        sender.send(saml).unwrap();
-                        println!("Got SAML data!");
-
-                        Result::<WithStatus<_>, Rejection>::Ok(warp::reply::with_status(
-                            "Got SAMLResponse field, it is now safe to close this window",
-                            StatusCode::OK,
-                        ))
-                    }
+                |data: HashMap<String, String>,
+                 sender: SyncSender<Saml>,
+                 pwd: Arc<Mutex<Option<Pwd>>>| async move {
+                    Result::<WithStatus<_>, Rejection>::Ok(receive(data, sender, pwd).await)
                 },
-            );
+            )
+            .recover(|rejection: Rejection| async move {
+                let status = if rejection.is_not_found() {
+                    StatusCode::NOT_FOUND
+                } else if rejection.find::<MethodNotAllowed>().is_some() {
+                    StatusCode::METHOD_NOT_ALLOWED
+                } else {
+                    StatusCode::BAD_REQUEST
+                };
+                Result::<WithStatus<_>, Rejection>::Ok(warp::reply::with_status(
+                    "Invalid SAML response request",
+                    status,
+                ))
+            });
+
+        let mut servers = Vec::new();
+        for addr in listen.socket_addrs() {
+            let _guard = runtime.enter();
+            match warp::serve(saml.clone()).try_bind_ephemeral(addr) {
+                Ok((addr, server)) => {
+                    app.log
+                        .append(format!("Starting SAML server at {}...", addr));
+                    servers.push(server);
+                }
+                Err(e) => app
+                    .log
+                    .append(format!("Unable to start SAML server at {}: {}", addr, e)),
+            }
+        }
+        if servers.is_empty() {
+            app.log
+                .append("Error: The SAML server is not listening, logins cannot complete");
+        }
 
-        let handle = runtime.spawn(warp::serve(saml).run(([0, 0, 0, 0], 35001)));
+        let handle = runtime.spawn(async move {
+            futures_util::future::join_all(servers).await;
+        });
 
         let log = app.log.clone();
         let join = OavcTask {
@@ -65,73 +90,171 @@ impl SamlServer {
             log,
         };
 
-        app.server.replace(Some(join));
+        *app.server.lock().unwrap() = Some(join);
         let log = app.log.clone();
-        let addr = app.config.addresses.clone();
-        let port = app.config.remote.clone();
-        let config = app.config.config.clone();
-        let st = app.openvpn_connection.clone();
-        let stager = app.state.clone();
         let manager = app.connection_manager.clone();
 
         std::thread::spawn(move || loop {
             let data = rx.recv().unwrap();
             {
-                log.append(format!("SAML Data: {:?}...", &data.data[..6]).as_str());
+                log.append(format!(
+                    "Received SAML response ({} bytes)",
+                    data.data.len()
+                ));
             }
 
-            let addr = {
-                let addr = addr.clone();
-                let addr = addr.lock().unwrap();
-                addr.as_ref().unwrap()[0].to_string()
-            };
-            let config = {
-                let config = config.clone();
-                let config = config.lock().unwrap();
-                config.as_ref().unwrap().clone()
+            // The SAML session belongs to the server that issued the challenge
+            let candidate = {
+                let man = manager.lock().unwrap();
+                man.as_ref().and_then(|m| m.current_endpoint())
             };
-            let port = {
-                let port = port.clone();
-                let port = port.lock().unwrap();
-                port.as_ref().unwrap().clone().1
-            };
-
-            let info = Arc::new(ProcessInfo::new());
+            if candidate.is_none() {
+                log.append("Received SAML data, but no connection is in progress");
+                continue;
+            }
 
-            let handle = {
-                let info = info.clone();
-                let log = log.clone();
-                let manager = manager.clone();
-                runtime.clone().spawn(async move {
-                    let con = connect_ovpn(log.clone(), config, addr, port, data, info).await;
+            let assertion = match Assertion::parse(&data.data)
+                .and_then(|a| a.validate(SystemTime::now()).map(|_| a))
+            {
+                Ok(assertion) => assertion,
+                Err(e) => {
+                    log.append(format!("Rejected SAML response: {}", e));
                     let man = manager.lock().unwrap();
-                    man.as_ref().unwrap().try_disconnect();
-                    con
-                })
+                    if let Some(man) = man.as_ref() {
+                        man.reject_login(format!("SAML response rejected: {}", e));
+                    }
+                    continue;
+                }
             };
-
-            let task =
-                OavcProcessTask::new("OpenVPN Connection".to_string(), handle, log.clone(), info);
-            {
-                let mut st = st.lock().unwrap();
-                *st = Some(task);
+            log.append(format!(
+                "Authenticated as {} for {}",
+                assertion.identity(),
+                assertion.audiences.join(", ")
+            ));
+            if let Some(expiry) = assertion.session_expiry() {
+                log.append(format!("Login session expires at {}", format_time(expiry)));
             }
 
-            // Fix for the temporary value issue
-            let stager_clone = stager.clone();
-            let stager_guard = stager_clone.lock().unwrap();
-            if let Some(state_manager) = stager_guard.as_ref() {
-                state_manager.set_connected();
+            let man = manager.lock().unwrap();
+            if let Some(man) = man.as_ref() {
+                man.accept_login(assertion, data);
             }
         });
     }
 }
 
-#[derive(Debug, Clone)]
+/// Checks a posted login and hands it to the connection thread.
+async fn receive(
+    data: HashMap<String, String>,
+    sender: SyncSender<Saml>,
+    pwd: Arc<Mutex<Option<Pwd>>>,
+) -> WithStatus<&'static str> {
+    let response = match data.get("SAMLResponse") {
+        Some(r) if !r.is_empty() => r.clone(),
+        _ => {
+            return warp::reply::with_status("Missing SAMLResponse field", StatusCode::BAD_REQUEST)
+        }
+    };
+
+    let pwd = match pwd.lock().await.deref() {
+        Some(pwd) => pwd.pwd.clone(),
+        None => {
+            return warp::reply::with_status("No VPN login is in progress", StatusCode::BAD_REQUEST)
+        }
+    };
+
+    match sender.try_send(Saml {
+        data: response,
+        pwd,
+    }) {
+        Ok(()) => {
+            println!("Got SAML data!");
+            warp::reply::with_status(
+                "Got SAMLResponse field, it is now safe to close this window",
+                StatusCode::OK,
+            )
+        }
+        Err(TrySendError::Full(_)) => warp::reply::with_status(
+            "A SAML response is already being processed",
+            StatusCode::CONFLICT,
+        ),
+        Err(TrySendError::Disconnected(_)) => warp::reply::with_status(
+            "The VPN client is shutting down",
+            StatusCode::SERVICE_UNAVAILABLE,
+        ),
+    }
+}
+
+#[derive(Clone)]
 pub struct Saml {
     pub data: String,
     pub pwd: String,
 }
 
+// The assertion is a bearer credential, keep it out of logs
+impl fmt::Debug for Saml {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Saml")
+            .field("data", &format_args!("<{} bytes>", self.data.len()))
+            .field("pwd", &self.pwd)
+            .finish()
+    }
+}
+
 unsafe impl Send for Saml {}
 unsafe impl Sync for Saml {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::Reply;
+
+    async fn status(
+        data: &[(&str, &str)],
+        sender: SyncSender<Saml>,
+        pwd: Option<&str>,
+    ) -> StatusCode {
+        let data = data
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let pwd = Arc::new(Mutex::new(pwd.map(|p| Pwd { pwd: p.to_string() })));
+        receive(data, sender, pwd).await.into_response().status()
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_requests() {
+        let (tx, rx) = std::sync::mpsc::sync_channel(1);
+
+        assert_eq!(
+            status(&[("RelayState", "x")], tx.clone(), Some("pwd")).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(&[("SAMLResponse", "")], tx.clone(), Some("pwd")).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(&[("SAMLResponse", "abc")], tx, None).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn accepts_one_response_at_a_time() {
+        let (tx, rx) = std::sync::mpsc::sync_channel(1);
+
+        assert_eq!(
+            status(&[("SAMLResponse", "abc")], tx.clone(), Some("pwd")).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(&[("SAMLResponse", "def")], tx, Some("pwd")).await,
+            StatusCode::CONFLICT
+        );
+
+        let saml = rx.try_recv().unwrap();
+        assert_eq!((saml.data.as_str(), saml.pwd.as_str()), ("abc", "pwd"));
+    }
+}
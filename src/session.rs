@@ -0,0 +1,188 @@
+use crate::config::SessionPolicy;
+use crate::saml::format_time;
+use std::fmt;
+use std::time::SystemTime;
+
+/// Why a connected tunnel is going to be closed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EndReason {
+    /// `SessionNotOnOrAfter` of the SAML login.
+    LoginExpiry,
+    /// The configured maximum session length of the Client VPN endpoint.
+    MaxSession,
+    /// OpenVPN's `--inactive` timer.
+    Inactivity,
+}
+
+impl EndReason {
+    /// Whether a new login keeps the connection going. An idle tunnel is
+    /// better left to close.
+    pub fn renewable(&self) -> bool {
+        !matches!(self, EndReason::Inactivity)
+    }
+}
+
+impl fmt::Display for EndReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EndReason::LoginExpiry => f.write_str("the SAML login expires"),
+            EndReason::MaxSession => f.write_str("the maximum session length is reached"),
+            EndReason::Inactivity => f.write_str("of inactivity"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SessionEnd {
+    pub at: SystemTime,
+    pub reason: EndReason,
+}
+
+impl fmt::Display for SessionEnd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} because {}", format_time(self.at), self.reason)
+    }
+}
+
+/// Tracks the limits of a connected tunnel.
+#[derive(Clone, Debug)]
+pub struct SessionClock {
+    connected_at: SystemTime,
+    last_activity: SystemTime,
+    login_expiry: Option<SystemTime>,
+    traffic: (u64, u64),
+    /// End already warned about, warned again only when it moves.
+    warned: Option<SystemTime>,
+}
+
+impl SessionClock {
+    pub fn new(now: SystemTime, login_expiry: Option<SystemTime>) -> SessionClock {
+        SessionClock {
+            connected_at: now,
+            last_activity: now,
+            login_expiry,
+            traffic: (0, 0),
+            warned: None,
+        }
+    }
+
+    /// Records the byte counters of the tunnel, any change counts as activity.
+    pub fn traffic(&mut self, now: SystemTime, traffic: (u64, u64)) {
+        if traffic != self.traffic {
+            self.traffic = traffic;
+            self.last_activity = now;
+        }
+    }
+
+    /// The earliest limit the tunnel runs into, if it has any.
+    pub fn end(&self, policy: &SessionPolicy) -> Option<SessionEnd> {
+        let mut ends = Vec::new();
+        if let Some(timeout) = policy.inactive_timeout {
+            ends.push(SessionEnd {
+                at: self.last_activity + timeout,
+                reason: EndReason::Inactivity,
+            });
+        }
+        if let Some(at) = self.login_expiry {
+            ends.push(SessionEnd {
+                at,
+                reason: EndReason::LoginExpiry,
+            });
+        }
+        if let Some(max) = policy.max_session {
+            ends.push(SessionEnd {
+                at: self.connected_at + max,
+                reason: EndReason::MaxSession,
+            });
+        }
+
+        ends.into_iter().min_by_key(|e| e.at)
+    }
+
+    /// The end to warn about, once it is closer than `policy.warn_before`.
+    pub fn warning(&mut self, now: SystemTime, policy: &SessionPolicy) -> Option<SessionEnd> {
+        let end = self.end(policy)?;
+        if now + policy.warn_before < end.at || self.warned == Some(end.at) {
+            return None;
+        }
+
+        self.warned = Some(end.at);
+        Some(end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn policy(max_session: Option<u64>) -> SessionPolicy {
+        SessionPolicy {
+            max_session: max_session.map(Duration::from_secs),
+            warn_before: Duration::from_secs(300),
+            renew: false,
+            inactive_timeout: Some(Duration::from_secs(3600)),
+        }
+    }
+
+    #[test]
+    fn picks_the_earliest_limit() {
+        let now = SystemTime::now();
+        let hour = Duration::from_secs(3600);
+        let mut clock = SessionClock::new(now, Some(now + 8 * hour));
+
+        assert_eq!(
+            clock.end(&policy(None)).map(|e| e.reason),
+            Some(EndReason::Inactivity)
+        );
+
+        clock.traffic(now + 30 * hour / 4, (10, 10));
+        assert_eq!(
+            clock.end(&policy(None)),
+            Some(SessionEnd {
+                at: now + 8 * hour,
+                reason: EndReason::LoginExpiry
+            })
+        );
+        assert_eq!(
+            clock.end(&policy(Some(7 * 3600))).map(|e| e.reason),
+            Some(EndReason::MaxSession)
+        );
+
+        // Without an inactivity timeout only the other limits are left
+        let never_idle = SessionPolicy {
+            inactive_timeout: None,
+            ..policy(None)
+        };
+        assert_eq!(SessionClock::new(now, None).end(&never_idle), None);
+        assert_eq!(
+            SessionClock::new(now, Some(now + 8 * hour))
+                .end(&never_idle)
+                .map(|e| e.reason),
+            Some(EndReason::LoginExpiry)
+        );
+    }
+
+    #[test]
+    fn warns_once_per_end() {
+        let now = SystemTime::now();
+        let mut clock = SessionClock::new(now, None);
+        let policy = policy(None);
+
+        assert_eq!(clock.warning(now, &policy), None);
+
+        let later = now + Duration::from_secs(3400);
+        assert_eq!(
+            clock.warning(later, &policy).map(|e| e.reason),
+            Some(EndReason::Inactivity)
+        );
+        assert_eq!(clock.warning(later, &policy), None);
+
+        // Traffic pushes the end back, the next idle hour warns again
+        clock.traffic(later, (1, 1));
+        assert_eq!(clock.warning(later, &policy), None);
+        assert!(clock
+            .warning(later + Duration::from_secs(3400), &policy)
+            .is_some());
+    }
+}
@@ -0,0 +1,676 @@
+use crate::app::VpnApp;
+use crate::config::{
+    parse_doh_url, parse_nameserver, parse_split_domain, AddressFamily, Cidr, DnsBackend,
+    DohConfig, PrivilegeHelper, RandomHostname, ReconnectPolicy, ResolverConfig, RoutePolicy,
+    SamlListen, SplitDns, DEFAULT_FORWARDER, DEFAULT_VERBOSITY, MAX_VERBOSITY,
+};
+use crate::local_config::LocalConfig;
+use crate::log::{Log, DEFAULT_RETENTION};
+use crate::state_manager::State;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+/// Settings chosen by the user, next to `state.toml`.
+const SETTINGS_FILE: &str = "settings.toml";
+
+/// Last used profile of versions before the settings file, migrated to
+/// `default_profile`.
+const LEGACY_PROFILE_FILE: &str = "last_ovpn";
+
+/// Schema version written to new files. Bump it and add a step to `migrate`
+/// when a setting is renamed or changes meaning.
+const CURRENT_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum SettingsError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    /// Written by a newer version of the client.
+    UnsupportedVersion(u32),
+    Invalid {
+        key: &'static str,
+        reason: String,
+    },
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingsError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            SettingsError::Parse(path, e) => write!(f, "{}: {}", path.display(), e),
+            SettingsError::UnsupportedVersion(v) => write!(
+                f,
+                "settings version {} is newer than the supported version {}",
+                v, CURRENT_VERSION
+            ),
+            SettingsError::Invalid { key, reason } => write!(f, "invalid `{}`: {}", key, reason),
+        }
+    }
+}
+
+impl std::error::Error for SettingsError {}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub version: u32,
+    /// Stored profile name or file used by `connect` without one.
+    pub default_profile: Option<PathBuf>,
+    pub openvpn: Option<PathBuf>,
+    pub privilege_helper: PrivilegeHelper,
+    pub verbosity: u8,
+    pub address_family: AddressFamily,
+    pub random_hostname: RandomHostname,
+    /// How the DNS servers pushed by the VPN are applied to the host.
+    pub dns_backend: DnsBackend,
+    /// Loopback address of the split-DNS forwarder, used without systemd-resolved.
+    pub dns_forwarder: IpAddr,
+    pub saml: SamlSettings,
+    pub reconnect: ReconnectSettings,
+    pub resolver: ResolverSettings,
+    pub logs: LogSettings,
+    pub hooks: Hooks,
+    /// Per profile, by stored profile name or file name without `.ovpn`.
+    pub profiles: BTreeMap<String, ProfileSettings>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SamlSettings {
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReconnectSettings {
+    pub max_attempts: u32,
+    pub initial_delay_secs: u64,
+    pub max_delay_secs: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResolverSettings {
+    /// `address` or `address:port`, the system nameservers when empty.
+    pub nameservers: Vec<String>,
+    pub timeout_secs: u64,
+    pub retries: u32,
+    pub tcp_fallback: bool,
+    pub system_fallback: bool,
+    /// DNS-over-HTTPS service asked instead of the nameservers.
+    pub doh_url: Option<String>,
+    /// Address of the DoH service, its name is looked up otherwise.
+    pub doh_bootstrap: Option<IpAddr>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSettings {
+    /// Lines of the daemon log kept for `logs`.
+    pub max_lines: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProfileSettings {
+    /// Domains resolved through the VPN, all of them when empty.
+    pub split_dns: Vec<String>,
+    /// Only these networks go through the tunnel, the pushed routes are ignored.
+    pub route_include: Vec<String>,
+    /// IPv4 networks kept outside the tunnel.
+    pub route_exclude: Vec<String>,
+    /// Ignore the pushed routes and DNS options, only `routes` are added.
+    pub route_nopull: bool,
+    /// Networks routed through the tunnel in addition.
+    pub routes: Vec<String>,
+}
+
+/// Shell commands run with `sh -c` when the tunnel comes up and goes down.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Hooks {
+    pub up: Option<String>,
+    pub down: Option<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            version: CURRENT_VERSION,
+            default_profile: None,
+            openvpn: None,
+            privilege_helper: PrivilegeHelper::default(),
+            verbosity: DEFAULT_VERBOSITY,
+            address_family: AddressFamily::default(),
+            random_hostname: RandomHostname::default(),
+            dns_backend: DnsBackend::default(),
+            dns_forwarder: DEFAULT_FORWARDER,
+            saml: SamlSettings::default(),
+            reconnect: ReconnectSettings::default(),
+            resolver: ResolverSettings::default(),
+            logs: LogSettings::default(),
+            hooks: Hooks::default(),
+            profiles: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for SamlSettings {
+    fn default() -> Self {
+        let listen = SamlListen::default();
+        SamlSettings {
+            addresses: listen.addresses,
+            port: listen.port,
+        }
+    }
+}
+
+impl Default for ReconnectSettings {
+    fn default() -> Self {
+        let policy = ReconnectPolicy::default();
+        ReconnectSettings {
+            max_attempts: policy.max_attempts,
+            initial_delay_secs: policy.initial_delay.as_secs(),
+            max_delay_secs: policy.max_delay.as_secs(),
+        }
+    }
+}
+
+impl Default for ResolverSettings {
+    fn default() -> Self {
+        let config = ResolverConfig::default();
+        ResolverSettings {
+            nameservers: Vec::new(),
+            timeout_secs: config.timeout.as_secs(),
+            retries: config.retries,
+            tcp_fallback: config.tcp_fallback,
+            system_fallback: config.system_fallback,
+            doh_url: None,
+            doh_bootstrap: None,
+        }
+    }
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        LogSettings {
+            max_lines: DEFAULT_RETENTION,
+        }
+    }
+}
+
+impl Settings {
+    /// Reads the settings at `path`, or at the default location when none is
+    /// given. Only the default location may be missing, it then starts from
+    /// the legacy files if there are any.
+    pub fn load(path: Option<&Path>) -> Result<Settings, SettingsError> {
+        match path {
+            Some(path) => Settings::read(path),
+            None => match LocalConfig::dir() {
+                Some(dir) => Settings::load_dir(&dir),
+                None => Ok(Settings::default()),
+            },
+        }
+    }
+
+    fn load_dir(dir: &Path) -> Result<Settings, SettingsError> {
+        let path = dir.join(SETTINGS_FILE);
+        if path.exists() {
+            return Settings::read(&path);
+        }
+
+        let legacy = dir.join(LEGACY_PROFILE_FILE);
+        let profile = match std::fs::read_to_string(&legacy) {
+            Ok(profile) => profile.trim().to_string(),
+            Err(_) => return Ok(Settings::default()),
+        };
+        let settings = Settings {
+            default_profile: Some(PathBuf::from(profile)).filter(|p| !p.as_os_str().is_empty()),
+            ..Settings::default()
+        };
+        settings.write(&path)?;
+        let _ = std::fs::remove_file(legacy);
+        Ok(settings)
+    }
+
+    fn read(path: &Path) -> Result<Settings, SettingsError> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| SettingsError::Io(path.to_path_buf(), e))?;
+        Settings::parse(&text).map_err(|e| match e {
+            SettingsError::Parse(_, e) => SettingsError::Parse(path.to_path_buf(), e),
+            e => e,
+        })
+    }
+
+    fn parse(text: &str) -> Result<Settings, SettingsError> {
+        let parse_error = |e| SettingsError::Parse(PathBuf::from(SETTINGS_FILE), e);
+        let mut value: toml::Value = toml::from_str(text).map_err(parse_error)?;
+        let version = match value.get("version") {
+            Some(toml::Value::Integer(v)) if *v > 0 => *v as u32,
+            _ => {
+                return Err(SettingsError::Invalid {
+                    key: "version",
+                    reason: "expected a positive integer".to_string(),
+                })
+            }
+        };
+        if version > CURRENT_VERSION {
+            return Err(SettingsError::UnsupportedVersion(version));
+        }
+        migrate(&mut value, version);
+
+        let settings: Settings = value.try_into().map_err(parse_error)?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    fn write(&self, path: &Path) -> Result<(), SettingsError> {
+        let io_error = |e| SettingsError::Io(path.to_path_buf(), e);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(io_error)?;
+        }
+        let text = toml::to_string(self)
+            .map_err(|e| io_error(io::Error::new(io::ErrorKind::InvalidData, e)))?;
+        std::fs::write(path, text).map_err(io_error)
+    }
+
+    fn validate(&self) -> Result<(), SettingsError> {
+        let invalid = |key, reason: &str| {
+            Err(SettingsError::Invalid {
+                key,
+                reason: reason.to_string(),
+            })
+        };
+
+        if self.verbosity > MAX_VERBOSITY {
+            return invalid("verbosity", "OpenVPN verbosity goes from 0 to 11");
+        }
+        if self.saml.addresses.is_empty() {
+            return invalid("saml.addresses", "at least one address is needed");
+        }
+        if self.saml.port == 0 {
+            return invalid("saml.port", "the port must not be 0");
+        }
+        if self.reconnect.initial_delay_secs == 0 {
+            return invalid("reconnect.initial_delay_secs", "must be at least 1");
+        }
+        if self.reconnect.max_delay_secs < self.reconnect.initial_delay_secs {
+            return invalid(
+                "reconnect.max_delay_secs",
+                "must not be less than initial_delay_secs",
+            );
+        }
+        if let Some(server) = self
+            .resolver
+            .nameservers
+            .iter()
+            .find(|s| parse_nameserver(s).is_none())
+        {
+            return invalid(
+                "resolver.nameservers",
+                &format!("'{}' is not an address", server),
+            );
+        }
+        if let Some(url) = &self.resolver.doh_url {
+            if parse_doh_url(url).is_none() {
+                return invalid(
+                    "resolver.doh_url",
+                    &format!("'{}' is not an https:// URL", url),
+                );
+            }
+        } else if self.resolver.doh_bootstrap.is_some() {
+            return invalid("resolver.doh_bootstrap", "doh_url is not set");
+        }
+        if self.resolver.timeout_secs == 0 {
+            return invalid("resolver.timeout_secs", "must be at least 1");
+        }
+        if !self.dns_forwarder.is_loopback() {
+            return invalid("dns_forwarder", "must be a loopback address");
+        }
+        for (name, profile) in &self.profiles {
+            if let Some(domain) = profile
+                .split_dns
+                .iter()
+                .find(|d| parse_split_domain(d).is_none())
+            {
+                return invalid(
+                    "profiles.split_dns",
+                    &format!("'{}' of profile '{}' is not a domain", domain, name),
+                );
+            }
+            for (key, nets) in [
+                ("profiles.route_include", &profile.route_include),
+                ("profiles.route_exclude", &profile.route_exclude),
+                ("profiles.routes", &profile.routes),
+            ] {
+                if let Some(net) = nets.iter().find(|n| Cidr::parse(n).is_none()) {
+                    return invalid(
+                        key,
+                        &format!("'{}' of profile '{}' is not a network", net, name),
+                    );
+                }
+            }
+            if let Err(e) = self.route_policy(name).validate() {
+                return invalid(
+                    "profiles.route_exclude",
+                    &format!("{} in profile '{}'", e, name),
+                );
+            }
+        }
+        if self.logs.max_lines == 0 {
+            return invalid("logs.max_lines", "must be at least 1");
+        }
+        for (key, hook) in [
+            ("hooks.up", &self.hooks.up),
+            ("hooks.down", &self.hooks.down),
+        ] {
+            if hook.as_ref().map_or(false, |h| h.trim().is_empty()) {
+                return invalid(key, "the command is empty");
+            }
+        }
+        Ok(())
+    }
+
+    pub fn saml_listen(&self) -> SamlListen {
+        SamlListen {
+            addresses: self.saml.addresses.clone(),
+            port: self.saml.port,
+        }
+    }
+
+    pub fn resolver_config(&self) -> ResolverConfig {
+        ResolverConfig {
+            nameservers: self
+                .resolver
+                .nameservers
+                .iter()
+                .filter_map(|s| parse_nameserver(s))
+                .collect(),
+            timeout: Duration::from_secs(self.resolver.timeout_secs),
+            retries: self.resolver.retries,
+            tcp_fallback: self.resolver.tcp_fallback,
+            system_fallback: self.resolver.system_fallback,
+            doh: self
+                .resolver
+                .doh_url
+                .as_deref()
+                .and_then(parse_doh_url)
+                .map(|url| DohConfig {
+                    url,
+                    bootstrap: self.resolver.doh_bootstrap,
+                }),
+        }
+    }
+
+    /// Split-DNS rules of the profile `name`, none for unknown profiles.
+    pub fn split_dns(&self, name: &str) -> SplitDns {
+        let domains = self
+            .profiles
+            .get(name)
+            .map(|p| {
+                p.split_dns
+                    .iter()
+                    .filter_map(|d| parse_split_domain(d))
+                    .collect()
+            })
+            .unwrap_or_default();
+        SplitDns {
+            domains,
+            forwarder: self.dns_forwarder,
+        }
+    }
+
+    /// Route policy of the profile `name`, the pushed routes for unknown profiles.
+    pub fn route_policy(&self, name: &str) -> RoutePolicy {
+        let nets = |nets: &[String]| nets.iter().filter_map(|n| Cidr::parse(n)).collect();
+        match self.profiles.get(name) {
+            Some(profile) => RoutePolicy {
+                include: nets(&profile.route_include),
+                exclude: nets(&profile.route_exclude),
+                no_pull: profile.route_nopull,
+                routes: nets(&profile.routes),
+            },
+            None => RoutePolicy::default(),
+        }
+    }
+
+    pub fn reconnect_policy(&self) -> ReconnectPolicy {
+        ReconnectPolicy {
+            max_attempts: self.reconnect.max_attempts,
+            initial_delay: Duration::from_secs(self.reconnect.initial_delay_secs),
+            max_delay: Duration::from_secs(self.reconnect.max_delay_secs),
+        }
+    }
+}
+
+/// Rewrites a file of an older schema `version` into the current one.
+fn migrate(value: &mut toml::Value, version: u32) {
+    // Version 1 is the first schema, later steps go here:
+    // if version < 2 { ... }
+    let _ = version;
+    if let Some(table) = value.as_table_mut() {
+        table.insert(
+            "version".to_string(),
+            toml::Value::Integer(CURRENT_VERSION as i64),
+        );
+    }
+}
+
+impl Hooks {
+    /// Runs the hooks on every transition into and out of `Connected`.
+    pub fn watch(self, app: &VpnApp) {
+        if self.up.is_none() && self.down.is_none() {
+            return;
+        }
+        let mut transitions = match app.state.lock().unwrap().as_ref() {
+            Some(state_manager) => state_manager.subscribe(),
+            None => return,
+        };
+        let log = app.log.clone();
+
+        app.runtime.spawn(async move {
+            loop {
+                let transition = match transitions.recv().await {
+                    Ok(transition) => transition,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return,
+                };
+                let hook = match (&transition.from, &transition.to) {
+                    (State::Connected, State::Connected) => None,
+                    (_, State::Connected) => self.up.as_ref().map(|h| ("up", h)),
+                    (State::Connected, _) => self.down.as_ref().map(|h| ("down", h)),
+                    _ => None,
+                };
+                if let Some((name, command)) = hook {
+                    run_hook(&log, name, command, &transition.to).await;
+                }
+            }
+        });
+    }
+}
+
+async fn run_hook(log: &Arc<Log>, name: &str, command: &str, state: &State) {
+    log.append(format!("Running {} hook", name));
+    let status = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("OPENAWS_HOOK", name)
+        .env("OPENAWS_STATE", state.to_string())
+        .status()
+        .await;
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => log.append(format!("The {} hook failed: {}", name, status)),
+        Err(e) => log.append(format!("Unable to run the {} hook: {}", name, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    #[test]
+    fn reads_a_full_file() {
+        let settings = Settings::parse(
+            r#"
+            version = 1
+            default_profile = "/home/user/work.ovpn"
+            privilege_helper = "pkexec"
+            verbosity = 4
+            address_family = "happy-eyeballs"
+            random_hostname = "never"
+            dns_backend = "resolvconf"
+
+            [saml]
+            addresses = ["127.0.0.1"]
+            port = 35002
+
+            [reconnect]
+            max_attempts = 0
+
+            [resolver]
+            nameservers = ["192.0.2.53", "[2001:db8::53]:5353"]
+            tcp_fallback = false
+            doh_url = "https://dns.example.com/dns-query"
+            doh_bootstrap = "192.0.2.53"
+
+            [logs]
+            max_lines = 500
+
+            [hooks]
+            up = "notify-send up"
+
+            [profiles.prod]
+            split_dns = ["*.internal.example.com", "corp.example.com"]
+            route_include = ["10.0.0.0/16"]
+            route_exclude = ["10.0.5.0/24"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            settings.default_profile,
+            Some(PathBuf::from("/home/user/work.ovpn"))
+        );
+        assert_eq!(settings.privilege_helper, PrivilegeHelper::Pkexec);
+        assert_eq!(settings.address_family, AddressFamily::HappyEyeballs);
+        assert_eq!(settings.random_hostname, RandomHostname::Never);
+        assert_eq!(settings.dns_backend, DnsBackend::Resolvconf);
+        assert_eq!(settings.saml_listen().port, 35002);
+        assert_eq!(settings.reconnect_policy().max_attempts, 0);
+        assert_eq!(
+            settings.reconnect_policy().max_delay,
+            ReconnectPolicy::default().max_delay
+        );
+        let resolver = settings.resolver_config();
+        assert_eq!(resolver.nameservers[0], "192.0.2.53:53".parse().unwrap());
+        assert_eq!(resolver.nameservers[1].port(), 5353);
+        assert!(!resolver.tcp_fallback && resolver.system_fallback);
+        let doh = resolver.doh.unwrap();
+        assert_eq!(doh.url.host(), Some("dns.example.com"));
+        assert_eq!(doh.bootstrap, Some("192.0.2.53".parse().unwrap()));
+        assert_eq!(settings.logs.max_lines, 500);
+        assert_eq!(settings.hooks.down, None);
+        assert_eq!(
+            settings.split_dns("prod").domains,
+            ["internal.example.com", "corp.example.com"]
+        );
+        assert!(settings.split_dns("staging").is_empty());
+        let routes = settings.route_policy("prod");
+        assert_eq!(routes.include, [Cidr::parse("10.0.0.0/16").unwrap()]);
+        assert_eq!(routes.exclude[0].to_string(), "10.0.5.0/24");
+        assert!(!routes.accepts_pushed());
+        assert!(settings.route_policy("staging").accepts_pushed());
+
+        let text = toml::to_string(&settings).unwrap();
+        assert_eq!(Settings::parse(&text).unwrap(), settings);
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        assert!(matches!(
+            Settings::parse("verbosity = 3"),
+            Err(SettingsError::Invalid { key: "version", .. })
+        ));
+        assert!(matches!(
+            Settings::parse("version = 2"),
+            Err(SettingsError::UnsupportedVersion(2))
+        ));
+        assert!(matches!(
+            Settings::parse("version = 1\nverbosity = 12"),
+            Err(SettingsError::Invalid {
+                key: "verbosity",
+                ..
+            })
+        ));
+        assert!(matches!(
+            Settings::parse("version = 1\n[resolver]\nnameservers = [\"dns.example.com\"]"),
+            Err(SettingsError::Invalid {
+                key: "resolver.nameservers",
+                ..
+            })
+        ));
+        assert!(matches!(
+            Settings::parse("version = 1\n[resolver]\ndoh_url = \"http://dns.example.com/\""),
+            Err(SettingsError::Invalid {
+                key: "resolver.doh_url",
+                ..
+            })
+        ));
+        assert!(matches!(
+            Settings::parse("version = 1\n[profiles.prod]\nsplit_dns = [\"corp..example.com\"]"),
+            Err(SettingsError::Invalid {
+                key: "profiles.split_dns",
+                ..
+            })
+        ));
+        assert!(matches!(
+            Settings::parse("version = 1\n[profiles.prod]\nroute_exclude = [\"2001:db8::/32\"]"),
+            Err(SettingsError::Invalid {
+                key: "profiles.route_exclude",
+                ..
+            })
+        ));
+        assert!(matches!(
+            Settings::parse("version = 1\nverbostiy = 3"),
+            Err(SettingsError::Parse(..))
+        ));
+        assert!(matches!(
+            Settings::parse("version = 1\n[hooks]\nup = \" \""),
+            Err(SettingsError::Invalid {
+                key: "hooks.up",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn migrates_the_last_profile() {
+        let dir = TempDir::new().unwrap();
+        std::fs::write(dir.child(LEGACY_PROFILE_FILE), "/tmp/prod.ovpn\n").unwrap();
+
+        let settings = Settings::load_dir(dir.path()).unwrap();
+        assert_eq!(
+            settings.default_profile,
+            Some(PathBuf::from("/tmp/prod.ovpn"))
+        );
+        assert!(!dir.child(LEGACY_PROFILE_FILE).exists());
+        assert_eq!(Settings::load_dir(dir.path()).unwrap(), settings);
+
+        let empty = TempDir::new().unwrap();
+        assert_eq!(
+            Settings::load_dir(empty.path()).unwrap(),
+            Settings::default()
+        );
+        assert!(!empty.child(SETTINGS_FILE).exists());
+    }
+}
@@ -0,0 +1,235 @@
+use crate::config::SplitDns;
+use domain::base::iana::Rcode;
+use domain::base::{Message, MessageBuilder};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::runtime::Runtime;
+use tokio::task::JoinHandle;
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// How long a nameserver may take to answer before the next one is asked.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Largest DNS message accepted over UDP, the common EDNS buffer size.
+const MAX_MESSAGE: usize = 4096;
+
+/// Nameservers the forwarder sends queries to.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Upstreams {
+    /// Pushed by the VPN, asked for the split domains.
+    pub vpn: Vec<SocketAddr>,
+    /// Those of the host before the tunnel, asked for everything else.
+    pub local: Vec<SocketAddr>,
+}
+
+impl Upstreams {
+    fn route(&self, rules: &SplitDns, query: &[u8]) -> Option<&[SocketAddr]> {
+        let query = Message::from_octets(query).ok()?;
+        let name = query.first_question()?.qname().to_string();
+        match rules.matches(&name) {
+            true => Some(&self.vpn),
+            false => Some(&self.local),
+        }
+    }
+}
+
+/// Nameservers in `/etc/resolv.conf`, without the forwarder itself.
+pub fn host_nameservers(forwarder: IpAddr) -> Vec<SocketAddr> {
+    let content = std::fs::read_to_string(RESOLV_CONF).unwrap_or_default();
+    parse_nameservers(&content, forwarder)
+}
+
+fn parse_nameservers(content: &str, forwarder: IpAddr) -> Vec<SocketAddr> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("nameserver"), Some(addr)) => addr.parse::<IpAddr>().ok(),
+                _ => None,
+            }
+        })
+        .filter(|addr| *addr != forwarder)
+        .map(|addr| SocketAddr::new(addr, 53))
+        .collect()
+}
+
+/// Starts the forwarder on port 53 of `address`, which takes root or
+/// `CAP_NET_BIND_SERVICE`. It runs until the handle is aborted.
+pub fn start(
+    runtime: &Runtime,
+    address: IpAddr,
+    rules: SplitDns,
+    upstreams: Upstreams,
+) -> io::Result<JoinHandle<()>> {
+    let socket = std::net::UdpSocket::bind(SocketAddr::new(address, 53))?;
+    socket.set_nonblocking(true)?;
+    let _runtime = runtime.enter();
+    let socket = UdpSocket::from_std(socket)?;
+    Ok(runtime.spawn(serve(socket, rules, upstreams)))
+}
+
+async fn serve(socket: UdpSocket, rules: SplitDns, upstreams: Upstreams) {
+    let socket = Arc::new(socket);
+    let mut buf = vec![0u8; MAX_MESSAGE];
+    loop {
+        let (len, client) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(_) => continue,
+        };
+        let query = buf[..len].to_vec();
+        // Not a query, nothing to answer
+        let servers = match upstreams.route(&rules, &query) {
+            Some(servers) => servers.to_vec(),
+            None => continue,
+        };
+
+        let socket = socket.clone();
+        tokio::spawn(async move {
+            let answer = match forward(&query, &servers).await {
+                Some(answer) => answer,
+                None => match server_failure(&query) {
+                    Some(answer) => answer,
+                    None => return,
+                },
+            };
+            let _ = socket.send_to(&answer, client).await;
+        });
+    }
+}
+
+/// The answer of the first of `servers` that replies in time.
+async fn forward(query: &[u8], servers: &[SocketAddr]) -> Option<Vec<u8>> {
+    for server in servers {
+        let unspecified = match server {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let socket = match UdpSocket::bind(SocketAddr::new(unspecified, 0)).await {
+            Ok(socket) => socket,
+            Err(_) => continue,
+        };
+        if socket.send_to(query, server).await.is_err() {
+            continue;
+        }
+
+        let mut buf = vec![0u8; MAX_MESSAGE];
+        let answer = tokio::time::timeout(UPSTREAM_TIMEOUT, async {
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.ok()?;
+                // Same server and query id, anything else is spoofed or stale
+                if from == *server && len >= 2 && buf[..2] == query[..2] {
+                    return Some(buf[..len].to_vec());
+                }
+            }
+        })
+        .await;
+        if let Ok(Some(answer)) = answer {
+            return Some(answer);
+        }
+    }
+    None
+}
+
+fn server_failure(query: &[u8]) -> Option<Vec<u8>> {
+    let query = Message::from_octets(query).ok()?;
+    let answer = MessageBuilder::new_vec()
+        .start_answer(&query, Rcode::ServFail)
+        .ok()?;
+    Some(answer.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use domain::base::{Dname, Rtype};
+    use domain::rdata::A;
+    use std::str::FromStr;
+
+    /// Answers every A query with `addr`.
+    async fn nameserver(addr: Ipv4Addr) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let local = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let query = Message::from_octets(buf[..len].to_vec()).unwrap();
+                let name = query.first_question().unwrap().qname().to_string();
+                let name = Dname::<Vec<u8>>::from_str(&name).unwrap();
+                let mut answer = MessageBuilder::new_vec()
+                    .start_answer(&query, Rcode::NoError)
+                    .unwrap();
+                answer.push((name, 60, A::new(addr))).unwrap();
+                let _ = socket.send_to(&answer.finish(), peer).await;
+            }
+        });
+        local
+    }
+
+    async fn ask(forwarder: SocketAddr, name: &str) -> Message<Vec<u8>> {
+        let mut query = MessageBuilder::new_vec().question();
+        query.header_mut().set_id(4242);
+        query.header_mut().set_rd(true);
+        query
+            .push((Dname::<Vec<u8>>::from_str(name).unwrap(), Rtype::A))
+            .unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.send_to(&query.finish(), forwarder).await.unwrap();
+        let mut buf = [0u8; 512];
+        let (len, _) = socket.recv_from(&mut buf).await.unwrap();
+        Message::from_octets(buf[..len].to_vec()).unwrap()
+    }
+
+    fn address(answer: &Message<Vec<u8>>) -> Option<Ipv4Addr> {
+        let record = answer.answer().ok()?.limit_to::<A>().next()?.ok()?;
+        Some(record.data().addr())
+    }
+
+    #[test]
+    fn reads_the_host_nameservers() {
+        let content = "# generated\nnameserver 192.0.2.53\nnameserver 127.0.0.153\n\
+                       nameserver 2001:db8::53\nsearch example.com\n";
+        assert_eq!(
+            parse_nameservers(content, "127.0.0.153".parse().unwrap()),
+            [
+                "192.0.2.53:53".parse::<SocketAddr>().unwrap(),
+                "[2001:db8::53]:53".parse().unwrap()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_only_the_split_domains_to_the_vpn() {
+        let vpn = nameserver(Ipv4Addr::new(10, 0, 0, 10)).await;
+        let local = nameserver(Ipv4Addr::new(192, 0, 2, 10)).await;
+        let rules = SplitDns::new(vec!["internal.example.com".to_string()]);
+        let upstreams = Upstreams {
+            vpn: vec![vpn],
+            local: vec![local],
+        };
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let forwarder = socket.local_addr().unwrap();
+        let serving = tokio::spawn(serve(socket, rules, upstreams));
+
+        let internal = ask(forwarder, "git.internal.example.com").await;
+        assert_eq!(internal.header().id(), 4242);
+        assert_eq!(address(&internal), Some(Ipv4Addr::new(10, 0, 0, 10)));
+        let public = ask(forwarder, "www.example.com").await;
+        assert_eq!(address(&public), Some(Ipv4Addr::new(192, 0, 2, 10)));
+        serving.abort();
+
+        // Nobody to ask, the client still gets an answer
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let forwarder = socket.local_addr().unwrap();
+        let serving = tokio::spawn(serve(socket, SplitDns::default(), Upstreams::default()));
+        let failed = ask(forwarder, "www.example.com").await;
+        assert_eq!(failed.header().rcode(), Rcode::ServFail);
+        serving.abort();
+    }
+}
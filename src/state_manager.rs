@@ -1,41 +1,232 @@
-use crate::app::State;
 use crate::consts::*;
+use crate::error::ConnectError;
 use crate::log::Log;
-use std::cell::RefCell;
-use std::sync::Arc;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::sync::broadcast;
+
+/// Number of transitions kept in the transition log.
+const HISTORY_SIZE: usize = 64;
+
+/// Transitions buffered for each subscriber before it starts lagging.
+const CHANNEL_SIZE: usize = 32;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum State {
+    Disconnected,
+    /// Starting OpenVPN against an endpoint to obtain the SAML challenge.
+    Connecting,
+    /// The SAML login page was opened, waiting for the browser to post back.
+    AwaitingBrowser,
+    /// The SAML response was received, OpenVPN is establishing the tunnel.
+    Authenticating,
+    Connected,
+    /// Trying again after the previous attempt failed, counting attempts from 1.
+    Reconnecting(u32),
+    Failed(ConnectError),
+}
+
+impl State {
+    fn can_become(&self, next: &State) -> bool {
+        use State::*;
+
+        match (self, next) {
+            // Disconnecting is always possible
+            (_, Disconnected) => true,
+            (Disconnected | Failed(_), Connecting) => true,
+            (Connecting | Reconnecting(_), AwaitingBrowser) => true,
+            (AwaitingBrowser | Reconnecting(_), Authenticating) => true,
+            (Authenticating | Reconnecting(_), Connected) => true,
+            (Connecting | AwaitingBrowser | Authenticating | Connected, Reconnecting(_)) => true,
+            (Reconnecting(a), Reconnecting(b)) => b > a,
+            (
+                Connecting | AwaitingBrowser | Authenticating | Connected | Reconnecting(_),
+                Failed(_),
+            ) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            State::Disconnected => f.write_str(DISCONNECTED),
+            State::Connecting => f.write_str(CONNECTING),
+            State::AwaitingBrowser => f.write_str(AWAITING_BROWSER),
+            State::Authenticating => f.write_str(AUTHENTICATING),
+            State::Connected => f.write_str(CONNECTED),
+            State::Reconnecting(attempt) => write!(f, "{} ({})", RECONNECTING, attempt),
+            State::Failed(reason) => write!(f, "{}: {}", FAILED, reason),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Transition {
+    pub from: State,
+    pub to: State,
+    pub at: SystemTime,
+}
+
+impl fmt::Display for Transition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let at = self
+            .at
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        write!(f, "{} {} -> {}", at, self.from, self.to)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct IllegalTransition {
+    pub from: State,
+    pub to: State,
+}
+
+impl fmt::Display for IllegalTransition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "illegal state transition {} -> {}", self.from, self.to)
+    }
+}
+
+impl std::error::Error for IllegalTransition {}
 
-#[derive(Clone)]
 pub struct StateManager {
     pub log: Arc<Log>,
-    pub state: RefCell<State>,
+    state: Mutex<State>,
+    history: Mutex<VecDeque<Transition>>,
+    events: broadcast::Sender<Transition>,
 }
 
-unsafe impl Send for StateManager {}
-unsafe impl Sync for StateManager {}
-
 impl StateManager {
     pub fn new(log: Arc<Log>) -> StateManager {
-        let manager = StateManager {
+        StateManager {
             log,
-            state: RefCell::new(State::Disconnected),
+            state: Mutex::new(State::Disconnected),
+            history: Mutex::new(VecDeque::new()),
+            events: broadcast::channel(CHANNEL_SIZE).0,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Most recent transitions, oldest first.
+    pub fn history(&self) -> Vec<Transition> {
+        self.history.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Receives every transition made after subscribing. A subscriber that
+    /// falls behind gets `RecvError::Lagged` and should read `state()` again.
+    pub fn subscribe(&self) -> broadcast::Receiver<Transition> {
+        self.events.subscribe()
+    }
+
+    /// Moves to `next` if the current state allows it. Staying in the same
+    /// state is accepted and not recorded.
+    pub fn transition(&self, next: State) -> Result<(), IllegalTransition> {
+        let mut state = self.state.lock().unwrap();
+        if *state == next {
+            return Ok(());
+        }
+
+        if !state.can_become(&next) {
+            let err = IllegalTransition {
+                from: state.clone(),
+                to: next,
+            };
+            self.log.append(format!("Ignoring {}", err));
+            return Err(err);
+        }
+
+        let from = std::mem::replace(&mut *state, next.clone());
+        self.log.append(next.to_string());
+
+        let mut history = self.history.lock().unwrap();
+        if history.len() == HISTORY_SIZE {
+            history.pop_front();
+        }
+        let transition = Transition {
+            from,
+            to: next,
+            at: SystemTime::now(),
         };
-        return manager;
+        history.push_back(transition.clone());
+
+        // Nobody listening is not an error
+        let _ = self.events.send(transition);
+
+        Ok(())
     }
 }
 
-impl StateManager {
-    pub fn set_connecting(&self) {
-        self.state.replace(State::Connecting);
-        self.log.append(CONNECTING);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_the_connection_flow() {
+        let states = StateManager::new(Arc::new(Log::new()));
+
+        for next in [
+            State::Connecting,
+            State::AwaitingBrowser,
+            State::Authenticating,
+            State::Reconnecting(1),
+            State::Authenticating,
+            State::Connected,
+            State::Disconnected,
+        ] {
+            states.transition(next).unwrap();
+        }
+
+        assert_eq!(states.state(), State::Disconnected);
+        assert_eq!(states.history().len(), 7);
     }
 
-    pub fn set_disconnected(&self) {
-        self.state.replace(State::Disconnected);
-        self.log.append(DISCONNECTED);
+    #[test]
+    fn publishes_transitions() {
+        let states = StateManager::new(Arc::new(Log::new()));
+        let mut events = states.subscribe();
+
+        states.transition(State::Connecting).unwrap();
+        assert!(states.transition(State::Connected).is_err());
+        states.transition(State::Disconnected).unwrap();
+
+        let first = events.try_recv().unwrap();
+        assert_eq!(
+            (first.from, first.to),
+            (State::Disconnected, State::Connecting)
+        );
+        let second = events.try_recv().unwrap();
+        assert_eq!(
+            (second.from, second.to),
+            (State::Connecting, State::Disconnected)
+        );
+        assert!(events.try_recv().is_err());
     }
 
-    pub fn set_connected(&self) {
-        self.state.replace(State::Connected);
-        self.log.append(CONNECTED);
+    #[test]
+    fn rejects_illegal_transitions() {
+        let states = StateManager::new(Arc::new(Log::new()));
+
+        assert!(states.transition(State::Connected).is_err());
+        assert!(states
+            .transition(State::Failed(ConnectError::Timeout))
+            .is_err());
+
+        states.transition(State::Connecting).unwrap();
+        states.transition(State::Reconnecting(2)).unwrap();
+        assert!(states.transition(State::Reconnecting(1)).is_err());
+        assert!(states.transition(State::Connecting).is_err());
+
+        assert_eq!(states.state(), State::Reconnecting(2));
+        assert_eq!(states.history().len(), 2);
     }
 }
@@ -46,7 +46,7 @@ impl<T> OavcProcessTask<T> {
             let pid = self.info.pid.lock().unwrap();
 
             if let Some(ref pid) = *pid {
-                kill_openvpn(*pid)
+                kill_openvpn(*pid, self.info.privilege)
             }
 
             if log {